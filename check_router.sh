@@ -0,0 +1,97 @@
+#!/usr/bin/env bash
+
+# Checks on a running local replica (dfx start --clean --background) that the
+# router acts for its callers: the shard records the end user in its history,
+# keeps watchlists and idempotency keys per end user, and refuses routed calls
+# from anyone but the router. With three ids per shard, the four items added
+# here fill the first shard and start a second. Listing and search must return
+# items from both, and a keyed add retried after that must not add a second item.
+
+set -euo pipefail
+
+ROUTER=icp_rust_boilerplate_router
+BACKEND_DID=src/icp_rust_boilerplate_backend/icp_rust_boilerplate_backend.did
+
+function fail() {
+  echo "FAIL: $1" >&2
+  exit 1
+}
+
+function as_user() {
+  local identity=$1
+  shift
+  dfx --identity "$identity" canister call "$@"
+}
+
+# Item ids in a candid reply on stdin, one per line.
+function item_ids() {
+  grep -oE '\bid = [0-9_]+' | sed 's/id = //; s/_//g'
+}
+
+# Succeeds when the candid reply on stdin holds items from both sides of
+# $FIRST_END, the end of the first shard's id block.
+function spans_shards() {
+  item_ids | awk -v end="$FIRST_END" '$1 < end { first = 1 } $1 >= end { second = 1 } END { exit !(first && second) }'
+}
+
+for identity in router-check-alice router-check-bob; do
+  dfx identity new --storage-mode=plaintext "$identity" >/dev/null 2>&1 || true
+done
+ALICE=$(dfx --identity router-check-alice identity get-principal)
+
+dfx deploy "$ROUTER" --argument '(opt record { ids_per_shard = 3 : nat64; max_stable_memory_bytes = null; cycles_per_shard = 1_000_000_000_000 : nat; admin_controllers = null })'
+dfx build icp_rust_boilerplate_backend
+WASM_ARG=$(mktemp)
+trap 'rm -f "$WASM_ARG"' EXIT
+echo "(blob \"$(xxd -p target/wasm32-unknown-unknown/release/icp_rust_boilerplate_backend.wasm | tr -d '\n' | sed 's/../\\&/g')\")" > "$WASM_ARG"
+dfx canister call "$ROUTER" set_shard_wasm --argument-file "$WASM_ARG"
+
+ITEM='record { name = "Drill"; description = "Cordless"; location = "Shelf A"; is_available = true }'
+
+echo "Adding an item as alice, twice with the same idempotency key"
+first=$(as_user router-check-alice "$ROUTER" add_item "($ITEM, opt \"router-check\")")
+second=$(as_user router-check-alice "$ROUTER" add_item "($ITEM, opt \"router-check\")")
+[ "$first" = "$second" ] || fail "a retried add_item added a second item"
+ID=$(echo "$first" | grep -oE 'id = [0-9_]+' | head -n 1 | sed 's/id = //; s/_//g')
+
+echo "Adding an item as bob with alice's key"
+bob=$(as_user router-check-bob "$ROUTER" add_item "($ITEM, opt \"router-check\")")
+[ "$bob" != "$first" ] || fail "bob got alice's stored reply back"
+
+echo "Adding two more items, which fill the first shard and start a second"
+for name in Saw Hammer; do
+  as_user router-check-alice "$ROUTER" add_item "(record { name = \"$name\"; description = \"Cordless\"; location = \"Shelf B\"; is_available = true }, null)" \
+    | grep -q "Ok" || fail "adding $name failed"
+done
+SHARDS=$(dfx canister call "$ROUTER" get_shards)
+[ "$(echo "$SHARDS" | grep -cE 'principal "[^"]+"')" -eq 2 ] || fail "the router does not have two shards"
+SHARD=$(echo "$SHARDS" | grep -oE 'principal "[^"]+"' | head -n 1 | cut -d '"' -f 2)
+FIRST_END=$(echo "$SHARDS" | grep -oE 'end_id = [0-9_]+' | head -n 1 | sed 's/end_id = //; s/_//g')
+
+echo "Retrying alice's first add now that a newer shard exists"
+retry=$(as_user router-check-alice "$ROUTER" add_item "($ITEM, opt \"router-check\")")
+[ "$retry" = "$first" ] || fail "a retry after the second shard was created added a second item"
+
+echo "Checking that listing and search cover both shards"
+as_user router-check-alice "$ROUTER" list_items '(null, 10 : nat64)' | spans_shards \
+  || fail "list_items did not return items from both shards"
+as_user router-check-alice "$ROUTER" search_items '("Cordless")' | spans_shards \
+  || fail "search_items did not return items from both shards"
+
+echo "Checking that the shard's history names alice"
+dfx canister call "$SHARD" get_audit_log '(0 : nat64, 10 : nat64)' --candid "$BACKEND_DID" | grep -q "$ALICE" \
+  || fail "the shard recorded the router instead of alice"
+
+echo "Watching the item as alice through the router"
+as_user router-check-alice "$ROUTER" watch_item "($ID : nat64, null)" | grep -q "Ok" || fail "watch_item failed"
+as_user router-check-alice "$SHARD" get_watchlist --candid "$BACKEND_DID" | grep -q "$ID" \
+  || fail "the item is not on alice's watchlist"
+if as_user router-check-bob "$SHARD" get_watchlist --candid "$BACKEND_DID" | grep -q "$ID"; then
+  fail "the item is on bob's watchlist"
+fi
+
+echo "Calling the shard's routed_update directly as bob"
+as_user router-check-bob "$SHARD" routed_update "(principal \"$ALICE\", \"watch_item\", blob \"\")" --candid "$BACKEND_DID" \
+  | grep -q "Unauthorized" || fail "the shard took a routed call from bob"
+
+echo "OK"
@@ -0,0 +1,260 @@
+#[macro_use]
+extern crate serde;
+
+// Companion archive for the storage canister. It holds audit entries and
+// retired items that the storage canister offloaded, and serves them back
+// through paginated queries. Only the storage canister may append. Reads are
+// limited to the storage canister, which redacts restricted fields for its
+// callers, and to the archive's controllers.
+
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::caller;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{Cell, DefaultMemoryImpl, Log, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Mirrors SmartStorageItem in the storage canister.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SmartStorageItem {
+    id: u64,
+    name: String,
+    description: String,
+    location: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+    is_available: bool,
+    version: u64,
+}
+
+impl Storable for SmartStorageItem {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Mirrors the audit types of the storage canister.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum AuditOperation {
+    Create,
+    Update,
+    MarkAvailable,
+    MarkUnavailable,
+    Delete,
+    Restore,
+    Purge,
+    Retire,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FieldChange {
+    field: String,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AuditEntry {
+    seq: u64,
+    item_id: u64,
+    operation: AuditOperation,
+    caller: Principal,
+    timestamp: u64,
+    changes: Vec<FieldChange>,
+    prev_hash: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+const STORAGE_CANISTER_MEMORY: u8 = 0;
+const AUDIT_LOG_INDEX_MEMORY: u8 = 1;
+const AUDIT_LOG_DATA_MEMORY: u8 = 2;
+const AUDIT_LOG_BY_ITEM_MEMORY: u8 = 3;
+const RETIRED_ITEMS_INDEX_MEMORY: u8 = 4;
+const RETIRED_ITEMS_DATA_MEMORY: u8 = 5;
+const RETIRED_ITEM_INDEX_MEMORY: u8 = 6;
+
+fn stable_memory(id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    // Raw principal of the storage canister allowed to append.
+    static STORAGE_CANISTER: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(stable_memory(STORAGE_CANISTER_MEMORY), Vec::new())
+            .expect("Cannot create the storage canister setting")
+    );
+
+    // Entries keep the storage canister's sequence numbers; an entry's position
+    // in the log is its seq.
+    static AUDIT_LOG: RefCell<Log<AuditEntry, Memory, Memory>> = RefCell::new(
+        Log::init(
+            stable_memory(AUDIT_LOG_INDEX_MEMORY),
+            stable_memory(AUDIT_LOG_DATA_MEMORY),
+        )
+        .expect("Cannot create the audit log")
+    );
+
+    static AUDIT_LOG_BY_ITEM: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(AUDIT_LOG_BY_ITEM_MEMORY)
+        ));
+
+    static RETIRED_ITEMS: RefCell<Log<SmartStorageItem, Memory, Memory>> = RefCell::new(
+        Log::init(
+            stable_memory(RETIRED_ITEMS_INDEX_MEMORY),
+            stable_memory(RETIRED_ITEMS_DATA_MEMORY),
+        )
+        .expect("Cannot create the retired item log")
+    );
+
+    // Item id -> index into RETIRED_ITEMS.
+    static RETIRED_ITEM_INDEX: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(RETIRED_ITEM_INDEX_MEMORY)
+        ));
+}
+
+#[ic_cdk::init]
+fn init(storage_canister: Principal) {
+    STORAGE_CANISTER
+        .with(|cell| cell.borrow_mut().set(storage_canister.as_slice().to_vec()))
+        .expect("cannot persist the storage canister");
+}
+
+fn ensure_storage_canister() -> Result<(), String> {
+    let storage = STORAGE_CANISTER.with(|cell| cell.borrow().get().clone());
+    if caller().as_slice() != storage.as_slice() {
+        return Err("only the storage canister may append to the archive".to_string());
+    }
+    Ok(())
+}
+
+fn ensure_reader() -> Result<(), String> {
+    let storage = STORAGE_CANISTER.with(|cell| cell.borrow().get().clone());
+    if caller().as_slice() != storage.as_slice() && !ic_cdk::api::is_controller(&caller()) {
+        return Err("only the storage canister and controllers may read the archive".to_string());
+    }
+    Ok(())
+}
+
+// Appends entries in sequence order and returns the new length. Entries that
+// are already archived are skipped, so a batch can be resent after a failure.
+#[ic_cdk::update]
+fn append_audit_entries(entries: Vec<AuditEntry>) -> Result<u64, String> {
+    ensure_storage_canister()?;
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        for entry in entries {
+            let len = log.len();
+            if entry.seq < len {
+                continue;
+            }
+            if entry.seq != len {
+                return Err(format!("expected seq={} but got seq={}", len, entry.seq));
+            }
+            log.append(&entry).expect("cannot append to the audit log");
+            AUDIT_LOG_BY_ITEM.with(|index| index.borrow_mut().insert((entry.item_id, entry.seq), ()));
+        }
+        Ok(log.len())
+    })
+}
+
+// Stores retired items and returns how many the archive holds. Items already
+// archived are skipped.
+#[ic_cdk::update]
+fn append_retired_items(items: Vec<SmartStorageItem>) -> Result<u64, String> {
+    ensure_storage_canister()?;
+    for item in items {
+        if RETIRED_ITEM_INDEX.with(|index| index.borrow().contains_key(&item.id)) {
+            continue;
+        }
+        let position = RETIRED_ITEMS
+            .with(|items| items.borrow().append(&item))
+            .expect("cannot append a retired item");
+        RETIRED_ITEM_INDEX.with(|index| index.borrow_mut().insert(item.id, position));
+    }
+    Ok(RETIRED_ITEM_INDEX.with(|index| index.borrow().len()))
+}
+
+#[ic_cdk::query]
+fn get_audit_log_length() -> u64 {
+    AUDIT_LOG.with(|log| log.borrow().len())
+}
+
+#[ic_cdk::query]
+fn get_audit_log(start: u64, limit: usize) -> Result<Vec<AuditEntry>, String> {
+    ensure_reader()?;
+    Ok(AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        (start..log.len())
+            .take(limit)
+            .filter_map(|seq| log.get(seq))
+            .collect()
+    }))
+}
+
+// Archived audit entries for one item, newest first.
+#[ic_cdk::query]
+fn get_item_history(item_id: u64, limit: usize, offset: usize) -> Result<Vec<AuditEntry>, String> {
+    ensure_reader()?;
+    let seqs: Vec<u64> = AUDIT_LOG_BY_ITEM.with(|index| {
+        index
+            .borrow()
+            .range((item_id, 0)..=(item_id, u64::MAX))
+            .map(|((_, seq), _)| seq)
+            .collect()
+    });
+    Ok(AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        seqs.into_iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|seq| log.get(seq))
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_retired_item(id: u64) -> Result<Option<SmartStorageItem>, String> {
+    ensure_reader()?;
+    let Some(position) = RETIRED_ITEM_INDEX.with(|index| index.borrow().get(&id)) else {
+        return Ok(None);
+    };
+    Ok(RETIRED_ITEMS.with(|items| items.borrow().get(position)))
+}
+
+#[ic_cdk::query]
+fn list_retired_items(limit: usize, offset: usize) -> Result<Vec<SmartStorageItem>, String> {
+    ensure_reader()?;
+    Ok(RETIRED_ITEM_INDEX.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|(_, position)| RETIRED_ITEMS.with(|items| items.borrow().get(position)))
+            .collect()
+    }))
+}
+
+ic_cdk::export_candid!();
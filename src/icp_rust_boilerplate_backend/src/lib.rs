@@ -1,16 +1,70 @@
 #[macro_use]
 extern crate serde;
 
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{Decode, Encode, Principal};
+use system::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
-use std::borrow::Borrow;
+use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, Log, Memory as _, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell, ops::Bound};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
+// The system API calls the canister logic makes. Unit tests swap in a clock,
+// caller, controllers and instruction counter of their own, since the real
+// calls only work inside a canister.
+#[cfg(not(test))]
+mod system {
+    pub use ic_cdk::api::call::arg_data_raw;
+    pub use ic_cdk::api::stable::stable64_size;
+    pub use ic_cdk::api::{caller, instruction_counter, is_controller, set_certified_data, time};
+}
+
+#[cfg(test)]
+mod system {
+    use candid::Principal;
+    use std::cell::{Cell, RefCell};
+
+    thread_local! {
+        pub static TIME: Cell<u64> = const { Cell::new(1) };
+        pub static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+        pub static CONTROLLERS: RefCell<Vec<Principal>> = const { RefCell::new(Vec::new()) };
+        pub static ARG_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        // Every read of the counter advances it by INSTRUCTION_STEP.
+        pub static INSTRUCTIONS: Cell<u64> = const { Cell::new(0) };
+        pub static INSTRUCTION_STEP: Cell<u64> = const { Cell::new(0) };
+    }
+
+    pub fn time() -> u64 {
+        TIME.with(Cell::get)
+    }
+
+    pub fn caller() -> Principal {
+        CALLER.with(Cell::get)
+    }
+
+    pub fn is_controller(principal: &Principal) -> bool {
+        CONTROLLERS.with(|controllers| controllers.borrow().contains(principal))
+    }
+
+    pub fn arg_data_raw() -> Vec<u8> {
+        ARG_DATA.with(|arg| arg.borrow().clone())
+    }
+
+    pub fn instruction_counter() -> u64 {
+        let used = INSTRUCTIONS.with(Cell::get);
+        INSTRUCTIONS.with(|instructions| instructions.set(used + INSTRUCTION_STEP.with(Cell::get)));
+        used
+    }
+
+    pub fn set_certified_data(_data: &[u8]) {}
+
+    pub fn stable64_size() -> u64 {
+        0
+    }
+}
+
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct SmartStorageItem {
     id: u64,
@@ -20,354 +74,7019 @@ struct SmartStorageItem {
     created_at: u64,
     updated_at: Option<u64>,
     is_available: bool,
+    // Bumped on every write; mutating endpoints reject a stale expected version.
+    version: u64,
 }
 
-impl Storable for SmartStorageItem {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Schema version written into the envelope of every item record. Bump it and
+// extend `VersionedItem` / `upgrade_item_record` whenever SmartStorageItem changes.
+const ITEM_SCHEMA_VERSION: u8 = 2;
+
+// Records written before envelopes existed are bare Candid and start with the
+// "DIDL" magic, so any leading byte other than b'D' is unambiguous.
+const LEGACY_CANDID_MAGIC: &[u8] = b"DIDL";
+
+// Layout of records written before the version envelope was introduced.
+#[derive(candid::CandidType, Deserialize)]
+struct SmartStorageItemV0 {
+    id: u64,
+    name: String,
+    description: String,
+    location: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+    is_available: bool,
+}
+
+// Version 1 only introduced the envelope; its fields match version 0.
+type SmartStorageItemV1 = SmartStorageItemV0;
+
+enum VersionedItem {
+    V0(SmartStorageItemV0),
+    V1(SmartStorageItemV1),
+    V2(SmartStorageItem),
+}
+
+// Raw, version-tagged item record. Decoding happens in `decode_item` so that a
+// corrupt or unknown record surfaces as an error instead of trapping inside the map.
+// In the item map this is either an inline envelope (older records) or a blob
+// reference pointing at the chunks holding the envelope in ITEM_BLOBS.
+#[derive(Clone)]
+struct StoredItem(Vec<u8>);
+
+// First byte of a blob reference, followed by the envelope length as a big-endian u32.
+const ITEM_BLOB_REF_TAG: u8 = 0xFF;
+
+// Item envelopes are split into chunks of this size, so records are no longer
+// limited by the map's bounded value size.
+const ITEM_BLOB_CHUNK_SIZE: usize = 4096;
+
+#[derive(Clone, Default)]
+struct ItemChunk(Vec<u8>);
+
+impl Storable for ItemChunk {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        ItemChunk(bytes.into_owned())
     }
 }
 
-impl BoundedStorable for SmartStorageItem {
-    const MAX_SIZE: u32 = 1024;
+impl BoundedStorable for ItemChunk {
+    const MAX_SIZE: u32 = ITEM_BLOB_CHUNK_SIZE as u32;
     const IS_FIXED_SIZE: bool = false;
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
-
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
-
-    static STORAGE_ITEM_STORAGE: RefCell<StableBTreeMap<u64, SmartStorageItem, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-        ));
+// Per-field byte limits enforced on every item write.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ItemFieldLimits {
+    max_name_length: u32,
+    max_description_length: u32,
+    max_location_length: u32,
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct SmartStorageItemPayload {
-    name: String,
-    description: String,
-    location: String,
-    is_available: bool,
+impl Default for ItemFieldLimits {
+    fn default() -> Self {
+        ItemFieldLimits {
+            max_name_length: 256,
+            max_description_length: 16 * 1024,
+            max_location_length: 256,
+        }
+    }
 }
 
-#[ic_cdk::query]
-fn get_smart_storage_item(id: u64) -> Result<SmartStorageItem, Error> {
-    match _get_smart_storage_item(&id) {
-        Some(item) => Ok(item),
-        None => Err(Error::NotFound {
-            msg: format!("an item with id={} not found", id),
-        }),
+impl Storable for ItemFieldLimits {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
 }
 
-#[ic_cdk::query]
-fn get_all_smart_storage_items() -> Vec<SmartStorageItem> {
-    STORAGE_ITEM_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .map(|(_, item)| item.clone())
-            .collect()
-    })
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum AuditOperation {
+    Create,
+    Update,
+    MarkAvailable,
+    MarkUnavailable,
+    Delete,
+    Restore,
+    Purge,
+    Retire,
 }
 
-#[ic_cdk::query]
-fn get_available_smart_storage_items() -> Vec<SmartStorageItem> {
-    STORAGE_ITEM_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .filter(|(_, item)| item.is_available)
-            .map(|(_, item)| item.clone())
-            .collect()
-    })
+impl AuditOperation {
+    fn label(&self) -> &'static str {
+        match self {
+            AuditOperation::Create => "Creation",
+            AuditOperation::Update => "Update",
+            AuditOperation::MarkAvailable => "MarkedAvailable",
+            AuditOperation::MarkUnavailable => "MarkedUnavailable",
+            AuditOperation::Delete => "Deletion",
+            AuditOperation::Restore => "Restoration",
+            AuditOperation::Purge => "Purge",
+            AuditOperation::Retire => "Retirement",
+        }
+    }
 }
 
-#[ic_cdk::query]
-fn search_smart_storage_items(query: String) -> Vec<SmartStorageItem> {
-    STORAGE_ITEM_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .filter(|(_, item)| item.name.contains(&query) || item.description.contains(&query))
-            .map(|(_, item)| item.clone())
-            .collect()
-    })
+// A single field's value before and after an operation; `None` when the item
+// did not exist on that side (creation or deletion).
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FieldChange {
+    field: String,
+    before: Option<String>,
+    after: Option<String>,
 }
 
-#[ic_cdk::update]
-fn add_smart_storage_item(item: SmartStorageItemPayload) -> Option<SmartStorageItem> {
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("cannot increment id counter");
-    let storage_item = SmartStorageItem {
-        id,
-        name: item.name,
-        description: item.description,
-        location: item.location,
-        created_at: time(),
-        updated_at: None,
-        is_available: item.is_available,
-    };
-    do_insert_smart_storage_item(&storage_item);
-    Some(storage_item)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AuditEntry {
+    seq: u64,
+    item_id: u64,
+    operation: AuditOperation,
+    caller: Principal,
+    timestamp: u64,
+    changes: Vec<FieldChange>,
+    // Hash of the previous entry (GENESIS_AUDIT_HASH for the first one) and of
+    // this entry, forming a tamper-evident chain.
+    prev_hash: Vec<u8>,
+    hash: Vec<u8>,
 }
 
-#[ic_cdk::update]
-fn update_smart_storage_item(id: u64, payload: SmartStorageItemPayload) -> Result<SmartStorageItem, Error> {
-    match STORAGE_ITEM_STORAGE.with(|service| service.borrow_mut().get(&id)) {
-        Some(mut item) => {
-            item.name = payload.name;
-            item.description = payload.description;
-            item.location = payload.location;
-            item.updated_at = Some(time());
-            item.is_available = payload.is_available;
-            
-            // No need to call do_insert_smart_storage_item as the item is modified in place
+const GENESIS_AUDIT_HASH: [u8; 32] = [0; 32];
 
-            Ok(item.clone())
-        }
-        None => Err(Error::NotFound {
-            msg: format!(
-                "couldn't update an item with id={}. item not found",
-                id
-            ),
-        }),
-    }
-}
+// Full item state used as a replay starting point. Holds an item envelope, or
+// nothing when the item did not exist at that point.
+struct ItemSnapshot(Vec<u8>);
 
-#[ic_cdk::query]
-fn is_item_available(id: u64) -> Result<bool, Error> {
-    match _get_smart_storage_item(&id) {
-        Some(item) => Ok(item.is_available),
-        None => Err(Error::NotFound {
-            msg: format!("an item with id={} not found", id),
-        }),
+impl Storable for ItemSnapshot {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
     }
-}
 
-#[ic_cdk::update]
-fn mark_item_as_available(id: u64) -> Result<SmartStorageItem, Error> {
-    match STORAGE_ITEM_STORAGE.with(|service| service.borrow_mut().get(&id)) {
-        Some(mut item) => {
-            item.is_available = true;
-            do_insert_smart_storage_item(&item);
-            Ok(item.clone())
-        }
-        None => Err(Error::NotFound {
-            msg: format!("an item with id={} not found", id),
-        }),
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        ItemSnapshot(bytes.into_owned())
     }
 }
 
-#[ic_cdk::update]
-fn mark_item_as_unavailable(id: u64) -> Result<SmartStorageItem, Error> {
-    if let Some(mut item) = STORAGE_ITEM_STORAGE.with(|service| service.borrow_mut().get(&id)) {
-        item.is_available = false;
-        do_insert_smart_storage_item(&item);
-        Ok(item.clone())
-    } else {
-        Err(Error::NotFound {
-            msg: format!("an item with id={} not found", id),
-        })
-    }
+// An item gets a fresh snapshot after this many audit entries, bounding how many
+// entries an "as of" query has to replay.
+const ITEM_SNAPSHOT_INTERVAL: usize = 32;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AuditChainHead {
+    length: u64,
+    head_hash: Vec<u8>,
+    // IC certificate over the certified data, which holds `head_hash`. Only
+    // available when called as a query.
+    certificate: Option<Vec<u8>>,
 }
 
-fn do_insert_smart_storage_item(item: &SmartStorageItem) {
-    STORAGE_ITEM_STORAGE.with(|service| service.borrow_mut().insert(item.id, item.clone()));
+// How far the audit log has been moved to the archive canister; see AUDIT_LOG.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AuditArchiveState {
+    base: u64,
+    // 0 while AUDIT_LOG lives in the primary pair of regions, 1 for the alternate pair.
+    active_log: u8,
+    // Hash and timestamp of entry `base - 1`, the last one archived.
+    last_archived_hash: Vec<u8>,
+    last_archived_timestamp: u64,
 }
 
-#[ic_cdk::update]
-fn delete_smart_storage_item(id: u64) -> Result<SmartStorageItem, Error> {
-    match STORAGE_ITEM_STORAGE.with(|service| service.borrow_mut().remove(&id)) {
-        Some(item) => Ok(item),
-        None => Err(Error::NotFound {
-            msg: format!(
-                "couldn't delete an item with id={}. item not found.",
-                id
-            ),
-        }),
+impl Storable for AuditArchiveState {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
+fn audit_log_regions(active_log: u8) -> (u8, u8) {
+    match active_log {
+        0 => (AUDIT_LOG_INDEX_MEMORY, AUDIT_LOG_DATA_MEMORY),
+        _ => (AUDIT_LOG_ALT_INDEX_MEMORY, AUDIT_LOG_ALT_DATA_MEMORY),
+    }
 }
 
-fn _get_smart_storage_item(id: &u64) -> Option<SmartStorageItem> {
-    // Assuming MemoryId::new(1) is reserved for smart storage item storage
-    let item_storage = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)));
-    StableBTreeMap::<u64, SmartStorageItem, Memory>::init(item_storage)
-        .borrow()
-        .get(id)
+// History past the history retention policy is moved to `archive`.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ArchiveConfig {
+    archive: Option<Principal>,
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize)]
-struct ChangeRecord {
-    timestamp: u64,
-    change_type: String,
+// What ARCHIVE_CONFIG holds. history_retention_ns is the history age limit
+// from before the retention policies held it, and is only read to move it
+// there (see `move_legacy_retention_limits`).
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct StoredArchiveConfig {
+    archive: Option<Principal>,
+    history_retention_ns: Option<u64>,
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct ItemStatistics {
-    total_items: usize,
-    average_availability_rate: f64,
+impl Storable for StoredArchiveConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-#[ic_cdk::query]
-fn sort_items_by_name() -> Vec<SmartStorageItem> {
-    let mut items = STORAGE_ITEM_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .map(|(_, item)| item.clone())
-            .collect::<Vec<_>>()
-    });
+const DEFAULT_HISTORY_RETENTION_NS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+const ARCHIVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
 
-    items.sort_by(|a, b| a.name.cmp(&b.name));
-    items
+// Batches sent to the archive are closed once their encoded size passes this,
+// keeping them well under the inter-canister message limit.
+const ARCHIVE_BATCH_BYTES: usize = 1024 * 1024;
+
+// Compaction copies the retained part of the audit log within one message, so
+// it is postponed while that part is longer than this.
+const MAX_AUDIT_COMPACTION_ENTRIES: u64 = 100_000;
+
+// Block of item ids this canister hands out when it runs as a shard behind the
+// router canister. A standalone canister owns every id.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ShardConfig {
+    first_id: u64,
+    // Exclusive.
+    end_id: u64,
+    // New items are refused once stable memory grows past this many bytes.
+    max_stable_memory_bytes: Option<u64>,
+    // Router canister allowed to make routed calls on behalf of its callers.
+    router: Option<Principal>,
 }
 
-#[ic_cdk::query]
-fn get_item_history(id: u64) -> Vec<ChangeRecord> {
-    match _get_smart_storage_item(&id) {
-        Some(item) => {
-            let mut history = Vec::new();
-            if let Some(updated_at) = item.updated_at {
-                history.push(ChangeRecord {
-                    timestamp: updated_at,
-                    change_type: "Update".to_string(),
-                });
-            }
-            history.push(ChangeRecord {
-                timestamp: item.created_at,
-                change_type: "Creation".to_string(),
-            });
-            history
+impl Default for ShardConfig {
+    fn default() -> Self {
+        ShardConfig {
+            first_id: 0,
+            end_id: u64::MAX,
+            max_stable_memory_bytes: None,
+            router: None,
         }
-        None => Vec::new(),
     }
 }
 
-#[ic_cdk::query]
-fn batch_query(queries: Vec<Query>) -> Vec<QueryResult> {
-    let mut results = Vec::new();
-    for query in queries {
-        match query {
-            Query::GetItem(id) => {
-                if let Some(item) = _get_smart_storage_item(&id) {
-                    results.push(QueryResult::Item(item));
-                } else {
-                    results.push(QueryResult::Error(Error::NotFound {
-                        msg: format!("an item with id={} not found", id),
-                    }));
-                }
-            }
-        }
+impl Storable for ShardConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
-    results
 }
 
+// Part of a requested audit log range that has to be read from the archive,
+// through get_archived_audit_log.
 #[derive(candid::CandidType, Serialize, Deserialize)]
-enum Query {
-    GetItem(u64),
+struct ArchivedRange {
+    archive: Principal,
+    start: u64,
+    length: u64,
 }
 
 #[derive(candid::CandidType, Serialize, Deserialize)]
-enum QueryResult {
-    Item(SmartStorageItem),
-    Error(Error),
+struct AuditLogPage {
+    entries: Vec<AuditEntry>,
+    archived: Option<ArchivedRange>,
 }
 
-#[ic_cdk::query]
-fn get_item_statistics() -> ItemStatistics {
-    let items = STORAGE_ITEM_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .map(|(_, item)| item.clone())
-            .collect::<Vec<_>>()
-    });
+// Part of a requested item history page that has to be read from the archive,
+// through get_archived_item_history for the same item with this offset and limit.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ArchivedItemHistory {
+    archive: Principal,
+    offset: u64,
+    limit: u64,
+}
 
-    let total_items = items.len();
-    let total_available_items = items.iter().filter(|item| item.is_available).count();
-    let average_availability_rate =
-        total_available_items as f64 / total_items as f64 * 100.0; // Calculate as a percentage
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ArchiveInfo {
+    config: ArchiveConfig,
+    // Audit entries below this sequence number are in the archive.
+    archived_history_end: u64,
+    pending_retired_items: u64,
+    archived_items: u64,
+}
 
-    ItemStatistics {
-        total_items,
-        average_availability_rate,
-    }
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ArchiveProgress {
+    archived_entries: u64,
+    archived_items: u64,
+    // Whether the archived entries were also dropped from the local log.
+    compacted: bool,
 }
 
 #[derive(candid::CandidType, Serialize, Deserialize)]
-struct TransactionRecord {
-    timestamp: u64,
-    change_type: String, // Add this line
-    transaction_type: String,
+struct AuditChainBreak {
+    seq: u64,
+    reason: String,
 }
 
-#[ic_cdk::query]
-fn get_item_transaction_history(id: u64) -> Vec<TransactionRecord> {
-    match _get_smart_storage_item(&id) {
-        Some(item) => {
-            let mut history = Vec::new();
-            if let Some(updated_at) = item.updated_at {
-                history.push(TransactionRecord {
-                    timestamp: updated_at,
-                    change_type: "Update".to_string(),
-                    transaction_type: "Update".to_string(),
-                });
-            }
-            history.push(TransactionRecord {
-                timestamp: item.created_at,
-                change_type: "Creation".to_string(),
-                transaction_type: "Creation".to_string(),
-            });
-            history
-        }
-        None => Vec::new(),
-    }
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AuditChainVerification {
+    start: u64,
+    checked: u64,
+    first_break: Option<AuditChainBreak>,
 }
 
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-#[ic_cdk::update]
-fn bulk_update_smart_storage_items(updates: Vec<(u64, SmartStorageItemPayload)>) -> Vec<Result<SmartStorageItem, Error>> {
-    let mut results = Vec::new();
-    for (id, payload) in updates {
-        let result = update_smart_storage_item(id, payload);
-        results.push(result);
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
-    results
 }
 
-#[ic_cdk::query]
-fn get_paginated_smart_storage_items(limit: usize, offset: usize) -> Vec<SmartStorageItem> {
-    STORAGE_ITEM_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .skip(offset)
-            .take(limit)
-            .map(|(_, item)| item.clone())
-            .collect()
-    })
-}
+// Upper bounds for the configurable limits. Locations are copied into
+// notifications, which are still bounded records, so they stay short.
+const MAX_NAME_LENGTH_CEILING: u32 = 1024;
+const MAX_DESCRIPTION_LENGTH_CEILING: u32 = 256 * 1024;
+const MAX_LOCATION_LENGTH_CEILING: u32 = 1024;
 
+impl Storable for StoredItem {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
 
-ic_cdk::export_candid!();
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        StoredItem(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for StoredItem {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ItemMigrationState {
+    // Schema version every record is known to be at.
+    schema_version: u8,
+    // Next key to visit while an eager migration is in progress.
+    cursor: Option<u64>,
+    migrated: u64,
+    failed: u64,
+}
+
+impl Storable for ItemMigrationState {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Instructions a single eager migration batch may use before yielding to a new message.
+const MIGRATION_BATCH_INSTRUCTIONS: u64 = 1_000_000_000;
+
+// A soft-deleted item. Its envelope stays in ITEM_BLOBS; `blob_ref` is the map
+// entry that points at it, put back on restore.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TrashEntry {
+    item_id: u64,
+    deleted_by: Principal,
+    deleted_at: u64,
+    blob_ref: Vec<u8>,
+}
+
+impl Storable for TrashEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TrashEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TrashedItem {
+    item: SmartStorageItem,
+    deleted_by: Principal,
+    deleted_at: u64,
+}
+
+const DEFAULT_TRASH_RETENTION_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+// Longest comment body accepted, in bytes. Comments live in their own map, so
+// they never count against the item record's MAX_SIZE.
+const MAX_COMMENT_LENGTH: usize = 1024;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Comment {
+    id: u64,
+    item_id: u64,
+    author: Principal,
+    body: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for Comment {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Comment {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Principal wrapper usable inside composite stable map keys.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct StoredPrincipal(Principal);
+
+// The empty (management canister) principal sorts before every other one, which
+// makes the default usable as the lower bound of a range scan.
+impl Default for StoredPrincipal {
+    fn default() -> Self {
+        StoredPrincipal(Principal::management_canister())
+    }
+}
+
+impl Storable for StoredPrincipal {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        StoredPrincipal(Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+impl BoundedStorable for StoredPrincipal {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ChangeKind {
+    Updated,
+    AvailabilityChanged { is_available: bool },
+    Moved { from: String, to: String },
+    Deleted,
+    Restored,
+    Retired,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Notification {
+    id: u64,
+    item_id: u64,
+    kind: ChangeKind,
+    changed_by: Principal,
+    timestamp: u64,
+    read: bool,
+}
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Notification {
+    const MAX_SIZE: u32 = 3072;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Stable memory regions. Every store gets its own id here, and an id is never
+// reused, even after its store is removed.
+const ID_COUNTER_MEMORY: u8 = 0;
+const ITEMS_MEMORY: u8 = 1;
+const COMMENT_ID_COUNTER_MEMORY: u8 = 2;
+const COMMENTS_MEMORY: u8 = 3;
+const WATCHERS_BY_ITEM_MEMORY: u8 = 4;
+const WATCHLISTS_MEMORY: u8 = 5;
+const NOTIFICATION_ID_COUNTER_MEMORY: u8 = 6;
+const NOTIFICATIONS_MEMORY: u8 = 7;
+const ITEM_MIGRATION_STATE_MEMORY: u8 = 8;
+const ITEM_BLOBS_MEMORY: u8 = 9;
+const ITEM_FIELD_LIMITS_MEMORY: u8 = 10;
+const AUDIT_LOG_INDEX_MEMORY: u8 = 11;
+const AUDIT_LOG_DATA_MEMORY: u8 = 12;
+const AUDIT_LOG_BY_ITEM_MEMORY: u8 = 13;
+const ITEM_SNAPSHOT_INDEX_MEMORY: u8 = 14;
+const ITEM_SNAPSHOTS_INDEX_MEMORY: u8 = 15;
+const ITEM_SNAPSHOTS_DATA_MEMORY: u8 = 16;
+const TRASH_MEMORY: u8 = 17;
+const TRASH_BY_TIME_MEMORY: u8 = 18;
+const TRASH_RETENTION_MEMORY: u8 = 19;
+const ITEM_CACHE_CAPACITY_MEMORY: u8 = 20;
+const RETIRED_ITEMS_MEMORY: u8 = 21;
+const ARCHIVED_ITEMS_MEMORY: u8 = 22;
+const AUDIT_ARCHIVE_STATE_MEMORY: u8 = 23;
+const AUDIT_LOG_ALT_INDEX_MEMORY: u8 = 24;
+const AUDIT_LOG_ALT_DATA_MEMORY: u8 = 25;
+const ARCHIVE_CONFIG_MEMORY: u8 = 26;
+const SHARD_CONFIG_MEMORY: u8 = 27;
+const AUDIT_LOG_BY_CALLER_MEMORY: u8 = 28;
+const UNDO_LINKS_MEMORY: u8 = 29;
+const IDEMPOTENCY_KEYS_MEMORY: u8 = 30;
+const IDEMPOTENCY_REPLIES_MEMORY: u8 = 31;
+const IDEMPOTENCY_BY_TIME_MEMORY: u8 = 32;
+const IDEMPOTENCY_REPLY_ID_COUNTER_MEMORY: u8 = 33;
+const IDEMPOTENCY_WINDOW_MEMORY: u8 = 34;
+const INDEX_VERSIONS_MEMORY: u8 = 35;
+const NOTIFICATIONS_BY_ID_MEMORY: u8 = 36;
+const RETENTION_POLICIES_MEMORY: u8 = 37;
+const RETENTION_RUNS_MEMORY: u8 = 38;
+const FIELD_VISIBILITY_MEMORY: u8 = 39;
+const ROLE_MEMBERS_MEMORY: u8 = 40;
+const ITEMS_BY_LOCATION_MEMORY: u8 = 41;
+const AVAILABLE_ITEMS_MEMORY: u8 = 42;
+
+// Names of the registered regions, checked for clashes on every start.
+const MEMORY_REGIONS: [(u8, &str); 43] = [
+    (ID_COUNTER_MEMORY, "id counter"),
+    (ITEMS_MEMORY, "items"),
+    (COMMENT_ID_COUNTER_MEMORY, "comment id counter"),
+    (COMMENTS_MEMORY, "comments"),
+    (WATCHERS_BY_ITEM_MEMORY, "watchers by item"),
+    (WATCHLISTS_MEMORY, "watchlists"),
+    (NOTIFICATION_ID_COUNTER_MEMORY, "notification id counter"),
+    (NOTIFICATIONS_MEMORY, "notifications"),
+    (ITEM_MIGRATION_STATE_MEMORY, "item migration state"),
+    (ITEM_BLOBS_MEMORY, "item blobs"),
+    (ITEM_FIELD_LIMITS_MEMORY, "item field limits"),
+    (AUDIT_LOG_INDEX_MEMORY, "audit log index"),
+    (AUDIT_LOG_DATA_MEMORY, "audit log data"),
+    (AUDIT_LOG_BY_ITEM_MEMORY, "audit log by item"),
+    (ITEM_SNAPSHOT_INDEX_MEMORY, "item snapshot index"),
+    (ITEM_SNAPSHOTS_INDEX_MEMORY, "item snapshots index"),
+    (ITEM_SNAPSHOTS_DATA_MEMORY, "item snapshots data"),
+    (TRASH_MEMORY, "trash"),
+    (TRASH_BY_TIME_MEMORY, "trash by time"),
+    (TRASH_RETENTION_MEMORY, "trash retention"),
+    (ITEM_CACHE_CAPACITY_MEMORY, "item cache capacity"),
+    (RETIRED_ITEMS_MEMORY, "retired items"),
+    (ARCHIVED_ITEMS_MEMORY, "archived items"),
+    (AUDIT_ARCHIVE_STATE_MEMORY, "audit archive state"),
+    (AUDIT_LOG_ALT_INDEX_MEMORY, "audit log alternate index"),
+    (AUDIT_LOG_ALT_DATA_MEMORY, "audit log alternate data"),
+    (ARCHIVE_CONFIG_MEMORY, "archive config"),
+    (SHARD_CONFIG_MEMORY, "shard config"),
+    (AUDIT_LOG_BY_CALLER_MEMORY, "audit log by caller"),
+    (UNDO_LINKS_MEMORY, "undo links"),
+    (IDEMPOTENCY_KEYS_MEMORY, "idempotency keys"),
+    (IDEMPOTENCY_REPLIES_MEMORY, "idempotency replies"),
+    (IDEMPOTENCY_BY_TIME_MEMORY, "idempotency keys by time"),
+    (IDEMPOTENCY_REPLY_ID_COUNTER_MEMORY, "idempotency reply id counter"),
+    (IDEMPOTENCY_WINDOW_MEMORY, "idempotency window"),
+    (INDEX_VERSIONS_MEMORY, "index versions"),
+    (NOTIFICATIONS_BY_ID_MEMORY, "notifications by id"),
+    (RETENTION_POLICIES_MEMORY, "retention policies"),
+    (RETENTION_RUNS_MEMORY, "retention runs"),
+    (FIELD_VISIBILITY_MEMORY, "field visibility"),
+    (ROLE_MEMBERS_MEMORY, "role members"),
+    (ITEMS_BY_LOCATION_MEMORY, "items by location"),
+    (AVAILABLE_ITEMS_MEMORY, "available items"),
+];
+
+// Traps if two registered regions share an id.
+fn assert_memory_regions() {
+    for (index, (id, name)) in MEMORY_REGIONS.iter().enumerate() {
+        if let Some((_, other)) = MEMORY_REGIONS[..index].iter().find(|(other_id, _)| other_id == id) {
+            ic_cdk::trap(&format!("memory id {} is registered for both {} and {}", id, other, name));
+        }
+    }
+}
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+// Hands out the memory of a registered region; an unregistered id is a bug.
+fn stable_memory(id: u8) -> Memory {
+    assert!(
+        MEMORY_REGIONS.iter().any(|(region, _)| *region == id),
+        "memory id {} is not registered in MEMORY_REGIONS",
+        id
+    );
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(stable_memory(ID_COUNTER_MEMORY), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static STORAGE_ITEM_STORAGE: RefCell<StableBTreeMap<u64, StoredItem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(ITEMS_MEMORY)
+        ));
+
+    static COMMENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(stable_memory(COMMENT_ID_COUNTER_MEMORY), 0)
+            .expect("Cannot create a comment counter")
+    );
+
+    // Comments are keyed by (item_id, comment_id) so a thread is a contiguous range.
+    static COMMENT_STORAGE: RefCell<StableBTreeMap<(u64, u64), Comment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(COMMENTS_MEMORY)
+        ));
+
+    // Watch relations are stored twice: by item for fan-out on change, and by
+    // principal for listing a watchlist.
+    static WATCHERS_BY_ITEM: RefCell<StableBTreeMap<(u64, StoredPrincipal), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(WATCHERS_BY_ITEM_MEMORY)
+        ));
+
+    static WATCHLISTS: RefCell<StableBTreeMap<(StoredPrincipal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(WATCHLISTS_MEMORY)
+        ));
+
+    static NOTIFICATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(stable_memory(NOTIFICATION_ID_COUNTER_MEMORY), 0)
+            .expect("Cannot create a notification counter")
+    );
+
+    static NOTIFICATIONS: RefCell<StableBTreeMap<(StoredPrincipal, u64), Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(NOTIFICATIONS_MEMORY)
+        ));
+
+    static ITEM_MIGRATION_STATE: RefCell<Cell<ItemMigrationState, Memory>> = RefCell::new(
+        Cell::init(
+            stable_memory(ITEM_MIGRATION_STATE_MEMORY),
+            ItemMigrationState::default(),
+        )
+        .expect("Cannot create the item migration state")
+    );
+
+    // Item envelopes keyed by (item_id, chunk_index).
+    static ITEM_BLOBS: RefCell<StableBTreeMap<(u64, u32), ItemChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(ITEM_BLOBS_MEMORY)
+        ));
+
+    static ITEM_FIELD_LIMITS: RefCell<Cell<ItemFieldLimits, Memory>> = RefCell::new(
+        Cell::init(
+            stable_memory(ITEM_FIELD_LIMITS_MEMORY),
+            ItemFieldLimits::default(),
+        )
+        .expect("Cannot create the item field limits")
+    );
+
+    // Append-only audit log holding the entries from AuditArchiveState::base on;
+    // the entry at position i has sequence number base + i. It lives in one of
+    // two pairs of regions, swapped whenever archived entries are compacted away.
+    static AUDIT_LOG: RefCell<Log<AuditEntry, Memory, Memory>> = RefCell::new({
+        let (index, data) = audit_log_regions(AUDIT_ARCHIVE_STATE.with(|state| state.borrow().get().active_log));
+        Log::init(stable_memory(index), stable_memory(data)).expect("Cannot create the audit log")
+    });
+
+    static AUDIT_ARCHIVE_STATE: RefCell<Cell<AuditArchiveState, Memory>> = RefCell::new(
+        Cell::init(
+            stable_memory(AUDIT_ARCHIVE_STATE_MEMORY),
+            AuditArchiveState::default(),
+        )
+        .expect("Cannot create the audit archive state")
+    );
+
+    static AUDIT_LOG_BY_ITEM: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(AUDIT_LOG_BY_ITEM_MEMORY)
+        ));
+
+    // (item_id, seq) -> index into ITEM_SNAPSHOTS of the item's state after
+    // every audit entry below `seq` has been applied.
+    static ITEM_SNAPSHOT_INDEX: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(ITEM_SNAPSHOT_INDEX_MEMORY)
+        ));
+
+    static ITEM_SNAPSHOTS: RefCell<Log<ItemSnapshot, Memory, Memory>> = RefCell::new(
+        Log::init(
+            stable_memory(ITEM_SNAPSHOTS_INDEX_MEMORY),
+            stable_memory(ITEM_SNAPSHOTS_DATA_MEMORY),
+        )
+        .expect("Cannot create the item snapshot log")
+    );
+
+    static TRASH: RefCell<StableBTreeMap<u64, TrashEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(TRASH_MEMORY)
+        ));
+
+    // (deleted_at, item_id), so the retention job only scans expired entries.
+    static TRASH_BY_TIME: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(TRASH_BY_TIME_MEMORY)
+        ));
+
+    // Superseded by the trash retention policy; see `move_legacy_retention_limits`.
+    static LEGACY_TRASH_RETENTION_NS: RefCell<IdCell> = RefCell::new(
+        IdCell::init(
+            stable_memory(TRASH_RETENTION_MEMORY),
+            DEFAULT_TRASH_RETENTION_NS,
+        )
+        .expect("Cannot create the trash retention setting")
+    );
+
+    // Pages of an in-progress snapshot import. This lives on the heap only, so an
+    // upgrade drops a half-finished import.
+    static SNAPSHOT_IMPORT: RefCell<Option<SnapshotImport>> = const { RefCell::new(None) };
+
+    // Zero disables ITEM_CACHE.
+    static ITEM_CACHE_CAPACITY: RefCell<Cell<u32, Memory>> = RefCell::new(
+        Cell::init(stable_memory(ITEM_CACHE_CAPACITY_MEMORY), 0)
+            .expect("Cannot create the item cache capacity setting")
+    );
+
+    static ITEM_CACHE: RefCell<ItemCache> = RefCell::new(ItemCache::default());
+
+    // End user a routed call acts for, set while routed_update or routed_query runs.
+    static ROUTED_CALLER: RefCell<Option<Principal>> = const { RefCell::new(None) };
+
+    // Retired items waiting to be moved to the archive, as blob references into ITEM_BLOBS.
+    static RETIRED_ITEMS: RefCell<StableBTreeMap<u64, StoredItem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(RETIRED_ITEMS_MEMORY)
+        ));
+
+    // Ids of items that now live only in the archive.
+    static ARCHIVED_ITEMS: RefCell<StableBTreeMap<u64, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(ARCHIVED_ITEMS_MEMORY)
+        ));
+
+    static ARCHIVE_CONFIG: RefCell<Cell<StoredArchiveConfig, Memory>> = RefCell::new(
+        Cell::init(
+            stable_memory(ARCHIVE_CONFIG_MEMORY),
+            StoredArchiveConfig::default(),
+        )
+        .expect("Cannot create the archive config")
+    );
+
+    // Set while an archive run is waiting on the archive canister.
+    static ARCHIVE_RUNNING: RefCell<bool> = const { RefCell::new(false) };
+
+    static SHARD_CONFIG: RefCell<Cell<ShardConfig, Memory>> = RefCell::new(
+        Cell::init(
+            stable_memory(SHARD_CONFIG_MEMORY),
+            ShardConfig::default(),
+        )
+        .expect("Cannot create the shard config")
+    );
+
+    static AUDIT_LOG_BY_CALLER: RefCell<StableBTreeMap<(StoredPrincipal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(AUDIT_LOG_BY_CALLER_MEMORY)
+        ));
+
+    // Links an undone audit entry to the entry recording its undo and the
+    // other way round. Linked entries are never undone again.
+    static UNDO_LINKS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(UNDO_LINKS_MEMORY)
+        ));
+
+    static IDEMPOTENCY_KEYS: RefCell<StableBTreeMap<IdempotencyRecordKey, IdempotencyRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(IDEMPOTENCY_KEYS_MEMORY)
+        ));
+
+    // Encoded replies keyed by (reply_id, chunk_index).
+    static IDEMPOTENCY_REPLIES: RefCell<StableBTreeMap<(u64, u32), ItemChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(IDEMPOTENCY_REPLIES_MEMORY)
+        ));
+
+    // (recorded_at, reply_id), so expiry only scans records past the window.
+    static IDEMPOTENCY_BY_TIME: RefCell<StableBTreeMap<(u64, u64), IdempotencyRecordKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(IDEMPOTENCY_BY_TIME_MEMORY)
+        ));
+
+    static IDEMPOTENCY_REPLY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(stable_memory(IDEMPOTENCY_REPLY_ID_COUNTER_MEMORY), 0)
+            .expect("Cannot create the idempotency reply counter")
+    );
+
+    // Superseded by the idempotency record retention policy; see
+    // `move_legacy_retention_limits`.
+    static LEGACY_IDEMPOTENCY_WINDOW_NS: RefCell<IdCell> = RefCell::new(
+        IdCell::init(
+            stable_memory(IDEMPOTENCY_WINDOW_MEMORY),
+            DEFAULT_IDEMPOTENCY_WINDOW_NS,
+        )
+        .expect("Cannot create the idempotency window setting")
+    );
+
+    // Region id of each derived index -> layout version it was built with.
+    static INDEX_VERSIONS: RefCell<StableBTreeMap<u8, u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(INDEX_VERSIONS_MEMORY)
+        ));
+
+    // Notification id -> recipient. Ids grow with time, so this lists
+    // notifications oldest first.
+    static NOTIFICATIONS_BY_ID: RefCell<StableBTreeMap<u64, StoredPrincipal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(NOTIFICATIONS_BY_ID_MEMORY)
+        ));
+
+    static RETENTION_POLICIES: RefCell<Cell<RetentionPolicies, Memory>> = RefCell::new(
+        Cell::init(stable_memory(RETENTION_POLICIES_MEMORY), RetentionPolicies::default())
+            .expect("Cannot create the retention policies")
+    );
+
+    static RETENTION_RUNS: RefCell<StableBTreeMap<u64, RetentionRun, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(RETENTION_RUNS_MEMORY)
+        ));
+
+    static FIELD_VISIBILITY: RefCell<Cell<FieldVisibilityRules, Memory>> = RefCell::new(
+        Cell::init(stable_memory(FIELD_VISIBILITY_MEMORY), FieldVisibilityRules::default())
+            .expect("Cannot create the field visibility rules")
+    );
+
+    static ROLE_MEMBERS: RefCell<StableBTreeMap<(StoredPrincipal, RoleName), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(ROLE_MEMBERS_MEMORY)
+        ));
+
+    // (location, item_id) of every live item.
+    static ITEMS_BY_LOCATION: RefCell<StableBTreeMap<(LocationKey, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(ITEMS_BY_LOCATION_MEMORY)
+        ));
+
+    // Ids of the live items marked available. Its length is the available
+    // count reported by get_item_statistics.
+    static AVAILABLE_ITEMS: RefCell<StableBTreeMap<u64, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            stable_memory(AVAILABLE_ITEMS_MEMORY)
+        ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct SmartStorageItemPayload {
+    name: String,
+    description: String,
+    location: String,
+    is_available: bool,
+}
+
+#[ic_cdk::query]
+fn get_smart_storage_item(id: u64, as_of: Option<u64>) -> Result<SmartStorageItem, Error> {
+    let item = match as_of {
+        Some(timestamp) => item_as_of(id, timestamp)?,
+        None => _get_smart_storage_item(&id)?,
+    };
+    match item {
+        Some(mut item) => {
+            redact_item(&mut item, &hidden_fields());
+            Ok(item)
+        }
+        None => Err(item_not_found(id)),
+    }
+}
+
+#[ic_cdk::query]
+fn get_all_smart_storage_items(as_of: Option<u64>) -> Vec<SmartStorageItem> {
+    redact_items(list_items(as_of))
+}
+
+#[ic_cdk::query]
+fn get_available_smart_storage_items(as_of: Option<u64>) -> Vec<SmartStorageItem> {
+    if as_of.is_some() {
+        return redact_items(list_items(as_of))
+            .into_iter()
+            .filter(|item| item.is_available)
+            .collect();
+    }
+    let ids: Vec<u64> = AVAILABLE_ITEMS.with(|index| index.borrow().iter().map(|(id, _)| id).collect());
+    redact_items(
+        ids.into_iter()
+            .filter_map(|id| _get_smart_storage_item(&id).ok().flatten())
+            .collect(),
+    )
+}
+
+// Matches against the redacted items, so hidden fields cannot be probed.
+#[ic_cdk::query]
+fn search_smart_storage_items(query: String, as_of: Option<u64>) -> Vec<SmartStorageItem> {
+    redact_items(list_items(as_of))
+        .into_iter()
+        .filter(|item| item.name.contains(&query) || item.description.contains(&query))
+        .collect()
+}
+
+// Every item as it is now, or as it was at `as_of` (nanoseconds since epoch).
+fn list_items(as_of: Option<u64>) -> Vec<SmartStorageItem> {
+    match as_of {
+        Some(timestamp) => {
+            // Ids are allocated sequentially, so every item that ever existed is
+            // between the first id of this shard and the counter.
+            let first_id = SHARD_CONFIG.with(|config| config.borrow().get().first_id);
+            let next_id = ID_COUNTER.with(|counter| *counter.borrow().get());
+            (first_id..next_id)
+                .filter_map(|id| item_as_of(id, timestamp).ok().flatten())
+                .collect()
+        }
+        None => STORAGE_ITEM_STORAGE.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter_map(|(id, stored)| decode_item(id, &stored).ok())
+                .collect()
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn add_smart_storage_item(
+    item: SmartStorageItemPayload,
+    idempotency_key: Option<String>,
+) -> Result<SmartStorageItem, Error> {
+    redacted(idempotent(idempotency_key, "add_smart_storage_item", || {
+        validate_item_payload(&item)?;
+        let id = ID_COUNTER.with(|counter| *counter.borrow().get());
+        check_shard_capacity(id)?;
+        ID_COUNTER
+            .with(|counter| counter.borrow_mut().set(id + 1))
+            .expect("cannot increment id counter");
+        Ok(insert_new_item(id, item))
+    }))
+}
+
+// Refuses a new item once this shard's id block or stable memory budget is used up.
+fn check_shard_capacity(next_id: u64) -> Result<(), Error> {
+    let config = SHARD_CONFIG.with(|config| config.borrow().get().clone());
+    if next_id >= config.end_id {
+        return Err(Error::ShardFull {
+            msg: format!("every id below {} has been handed out", config.end_id),
+        });
+    }
+    if let Some(limit) = config.max_stable_memory_bytes {
+        let used = system::stable64_size() * WASM_PAGE_SIZE;
+        if used > limit {
+            return Err(Error::ShardFull {
+                msg: format!("stable memory holds {} bytes, over the limit of {}", used, limit),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Stores a validated payload under an already allocated id.
+fn insert_new_item(id: u64, item: SmartStorageItemPayload) -> SmartStorageItem {
+    let storage_item = SmartStorageItem {
+        id,
+        name: item.name,
+        description: item.description,
+        location: item.location,
+        created_at: time(),
+        updated_at: None,
+        is_available: item.is_available,
+        version: 1,
+    };
+    do_insert_smart_storage_item(&storage_item);
+    update_item_indexes(None, Some(&storage_item));
+    record_audit(AuditOperation::Create, id, None, Some(&storage_item));
+    storage_item
+}
+
+#[ic_cdk::update]
+fn update_smart_storage_item(
+    id: u64,
+    expected_version: u64,
+    payload: SmartStorageItemPayload,
+    idempotency_key: Option<String>,
+) -> Result<SmartStorageItem, Error> {
+    redacted(idempotent(idempotency_key, "update_smart_storage_item", || {
+        _update_item(id, expected_version, payload)
+    }))
+}
+
+// The unredacted update, shared with the bulk endpoint so its stored report
+// keeps the full items.
+fn _update_item(id: u64, expected_version: u64, payload: SmartStorageItemPayload) -> Result<SmartStorageItem, Error> {
+    validate_item_payload(&payload)?;
+    match _get_smart_storage_item(&id)? {
+        Some(item) => {
+            check_item_version(&item, expected_version)?;
+            Ok(apply_item_payload(item, payload, AuditOperation::Update))
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "couldn't update an item with id={}. item not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Writes a validated payload over a live item as its next version.
+fn apply_item_payload(
+    mut item: SmartStorageItem,
+    payload: SmartStorageItemPayload,
+    operation: AuditOperation,
+) -> SmartStorageItem {
+    let before = item.clone();
+    let changes = diff_item_changes(&item, &payload);
+    item.name = payload.name;
+    item.description = payload.description;
+    item.location = payload.location;
+    if matches!(operation, AuditOperation::Update) {
+        item.updated_at = Some(time());
+    }
+    item.is_available = payload.is_available;
+    item.version += 1;
+    do_insert_smart_storage_item(&item);
+    update_item_indexes(Some(&before), Some(&item));
+    record_audit(operation, item.id, Some(&before), Some(&item));
+    for change in changes {
+        notify_watchers(item.id, change);
+    }
+    item
+}
+
+#[ic_cdk::query]
+fn is_item_available(id: u64) -> Result<bool, Error> {
+    match _get_smart_storage_item(&id)? {
+        Some(item) => Ok(item.is_available),
+        None => Err(Error::NotFound {
+            msg: format!("an item with id={} not found", id),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn mark_item_as_available(
+    id: u64,
+    expected_version: u64,
+    idempotency_key: Option<String>,
+) -> Result<SmartStorageItem, Error> {
+    redacted(idempotent(idempotency_key, "mark_item_as_available", || {
+        match _get_smart_storage_item(&id)? {
+            Some(mut item) => {
+                check_item_version(&item, expected_version)?;
+                let before = item.clone();
+                item.is_available = true;
+                item.version += 1;
+                do_insert_smart_storage_item(&item);
+                update_item_indexes(Some(&before), Some(&item));
+                record_audit(AuditOperation::MarkAvailable, id, Some(&before), Some(&item));
+                if !before.is_available {
+                    notify_watchers(id, ChangeKind::AvailabilityChanged { is_available: true });
+                }
+                Ok(item.clone())
+            }
+            None => Err(Error::NotFound {
+                msg: format!("an item with id={} not found", id),
+            }),
+        }
+    }))
+}
+
+#[ic_cdk::update]
+fn mark_item_as_unavailable(
+    id: u64,
+    expected_version: u64,
+    idempotency_key: Option<String>,
+) -> Result<SmartStorageItem, Error> {
+    redacted(idempotent(idempotency_key, "mark_item_as_unavailable", || {
+        if let Some(mut item) = _get_smart_storage_item(&id)? {
+            check_item_version(&item, expected_version)?;
+            let before = item.clone();
+            item.is_available = false;
+            item.version += 1;
+            do_insert_smart_storage_item(&item);
+            update_item_indexes(Some(&before), Some(&item));
+            record_audit(AuditOperation::MarkUnavailable, id, Some(&before), Some(&item));
+            if before.is_available {
+                notify_watchers(id, ChangeKind::AvailabilityChanged { is_available: false });
+            }
+            Ok(item.clone())
+        } else {
+            Err(Error::NotFound {
+                msg: format!("an item with id={} not found", id),
+            })
+        }
+    }))
+}
+
+fn do_insert_smart_storage_item(item: &SmartStorageItem) {
+    let blob_ref = write_item_blob(item);
+    STORAGE_ITEM_STORAGE.with(|service| service.borrow_mut().insert(item.id, blob_ref));
+    ITEM_CACHE.with(|cache| cache.borrow_mut().invalidate(item.id));
+    cache_item(item);
+}
+
+// Writes the item's envelope to ITEM_BLOBS and returns the map entry referencing it.
+fn write_item_blob(item: &SmartStorageItem) -> StoredItem {
+    let envelope = encode_item(item);
+    let chunk_count = envelope.0.chunks(ITEM_BLOB_CHUNK_SIZE).count() as u32;
+    ITEM_BLOBS.with(|blobs| {
+        let mut blobs = blobs.borrow_mut();
+        for (index, chunk) in envelope.0.chunks(ITEM_BLOB_CHUNK_SIZE).enumerate() {
+            blobs.insert((item.id, index as u32), ItemChunk(chunk.to_vec()));
+        }
+    });
+    remove_item_blob_chunks(item.id, chunk_count);
+    let mut blob_ref = vec![ITEM_BLOB_REF_TAG];
+    blob_ref.extend((envelope.0.len() as u32).to_be_bytes());
+    StoredItem(blob_ref)
+}
+
+// Drops the chunks of an item's blob from `first_chunk` onwards.
+fn remove_item_blob_chunks(id: u64, first_chunk: u32) {
+    ITEM_BLOBS.with(|blobs| {
+        let keys: Vec<_> = blobs
+            .borrow()
+            .range((id, first_chunk)..=(id, u32::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        let mut blobs = blobs.borrow_mut();
+        for key in keys {
+            blobs.remove(&key);
+        }
+    });
+}
+
+// Resolves a map entry to the full version-tagged envelope.
+fn load_item_envelope(id: u64, stored: &StoredItem) -> Result<StoredItem, Error> {
+    if stored.0.first() != Some(&ITEM_BLOB_REF_TAG) {
+        return Ok(stored.clone());
+    }
+    let len = match stored.0.get(1..5) {
+        Some(len) => u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize,
+        None => {
+            return Err(Error::DecodeFailed {
+                msg: format!("malformed blob reference for item id={}", id),
+            })
+        }
+    };
+    let bytes: Vec<u8> = ITEM_BLOBS.with(|blobs| {
+        blobs
+            .borrow()
+            .range((id, 0)..=(id, u32::MAX))
+            .flat_map(|(_, chunk)| chunk.0)
+            .collect()
+    });
+    if bytes.len() != len {
+        return Err(Error::DecodeFailed {
+            msg: format!(
+                "blob for item id={} has {} bytes, expected {}",
+                id,
+                bytes.len(),
+                len
+            ),
+        });
+    }
+    Ok(StoredItem(bytes))
+}
+
+fn validate_item_payload(payload: &SmartStorageItemPayload) -> Result<(), Error> {
+    let limits = ITEM_FIELD_LIMITS.with(|limits| limits.borrow().get().clone());
+    let fields = [
+        ("name", payload.name.len(), limits.max_name_length),
+        ("description", payload.description.len(), limits.max_description_length),
+        ("location", payload.location.len(), limits.max_location_length),
+    ];
+    for (field, len, max) in fields {
+        if len > max as usize {
+            return Err(Error::InvalidInput {
+                msg: format!("{} is {} bytes long, the limit is {} bytes", field, len, max),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_item_field_limits() -> ItemFieldLimits {
+    ITEM_FIELD_LIMITS.with(|limits| limits.borrow().get().clone())
+}
+
+#[ic_cdk::update]
+fn set_item_field_limits(limits: ItemFieldLimits) -> Result<ItemFieldLimits, Error> {
+    ensure_controller()?;
+    let ceilings = [
+        ("max_name_length", limits.max_name_length, MAX_NAME_LENGTH_CEILING),
+        ("max_description_length", limits.max_description_length, MAX_DESCRIPTION_LENGTH_CEILING),
+        ("max_location_length", limits.max_location_length, MAX_LOCATION_LENGTH_CEILING),
+    ];
+    for (field, value, ceiling) in ceilings {
+        if value == 0 || value > ceiling {
+            return Err(Error::InvalidInput {
+                msg: format!("{} must be between 1 and {}", field, ceiling),
+            });
+        }
+    }
+    ITEM_FIELD_LIMITS
+        .with(|cell| cell.borrow_mut().set(limits.clone()))
+        .expect("cannot persist item field limits");
+    Ok(limits)
+}
+
+// The principal a call acts for. A call the router passes on through
+// routed_update or routed_query acts for the router's caller.
+fn caller() -> Principal {
+    ROUTED_CALLER.with(|routed| *routed.borrow()).unwrap_or_else(system::caller)
+}
+
+fn ensure_controller() -> Result<(), Error> {
+    if system::is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "only a canister controller may do this".to_string(),
+        })
+    }
+}
+
+#[ic_cdk::update]
+fn delete_smart_storage_item(
+    id: u64,
+    expected_version: u64,
+    idempotency_key: Option<String>,
+) -> Result<SmartStorageItem, Error> {
+    redacted(idempotent(idempotency_key, "delete_smart_storage_item", || {
+        _delete_item(id, expected_version)
+    }))
+}
+
+// The unredacted delete, shared with the bulk endpoint.
+fn _delete_item(id: u64, expected_version: u64) -> Result<SmartStorageItem, Error> {
+    match _get_smart_storage_item(&id)? {
+        Some(item) => {
+            check_item_version(&item, expected_version)?;
+            move_item_to_trash(&item);
+            record_audit(AuditOperation::Delete, id, Some(&item), None);
+            notify_watchers(id, ChangeKind::Deleted);
+            Ok(item)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "couldn't delete an item with id={}. item not found.",
+                id
+            ),
+        }),
+    }
+}
+
+// Takes an item out of circulation. The next archive run moves it to the
+// archive canister, where it stays readable.
+#[ic_cdk::update]
+fn retire_smart_storage_item(
+    id: u64,
+    expected_version: u64,
+    idempotency_key: Option<String>,
+) -> Result<SmartStorageItem, Error> {
+    redacted(idempotent(idempotency_key, "retire_smart_storage_item", || {
+        let item = get_item_at_version(id, expected_version)?;
+        let blob_ref = write_item_blob(&item);
+        STORAGE_ITEM_STORAGE.with(|service| service.borrow_mut().remove(&id));
+        ITEM_CACHE.with(|cache| cache.borrow_mut().invalidate(id));
+        update_item_indexes(Some(&item), None);
+        RETIRED_ITEMS.with(|items| items.borrow_mut().insert(id, blob_ref));
+        record_audit(AuditOperation::Retire, id, Some(&item), None);
+        notify_watchers(id, ChangeKind::Retired);
+        Ok(item)
+    }))
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum Error {
+    NotFound { msg: String },
+    InvalidInput { msg: String },
+    Unauthorized { msg: String },
+    DecodeFailed { msg: String },
+    Conflict { msg: String, current_version: u64 },
+    // The data now lives in the archive canister.
+    Archived { msg: String, archive: Principal },
+    // A call to another canister failed or was rejected.
+    CallFailed { msg: String },
+    // This shard takes no new items; the router moves on to the next shard.
+    ShardFull { msg: String },
+}
+
+fn get_item_at_version(id: u64, expected_version: u64) -> Result<SmartStorageItem, Error> {
+    match _get_smart_storage_item(&id)? {
+        Some(item) => {
+            check_item_version(&item, expected_version)?;
+            Ok(item)
+        }
+        None => Err(item_not_found(id)),
+    }
+}
+
+// Points at the archive for items that were moved there.
+fn item_not_found(id: u64) -> Error {
+    if ARCHIVED_ITEMS.with(|items| items.borrow().contains_key(&id)) {
+        return archived_error(format!("item id={} was retired to the archive", id));
+    }
+    if RETIRED_ITEMS.with(|items| items.borrow().contains_key(&id)) {
+        return Error::NotFound {
+            msg: format!("item id={} is retired and waiting to be archived", id),
+        };
+    }
+    Error::NotFound {
+        msg: format!("an item with id={} not found", id),
+    }
+}
+
+fn archived_error(msg: String) -> Error {
+    match ARCHIVE_CONFIG.with(|config| config.borrow().get().archive) {
+        Some(archive) => Error::Archived { msg, archive },
+        None => Error::NotFound { msg },
+    }
+}
+
+fn check_item_version(item: &SmartStorageItem, expected_version: u64) -> Result<(), Error> {
+    if item.version == expected_version {
+        Ok(())
+    } else {
+        Err(Error::Conflict {
+            msg: format!(
+                "item id={} is at version {}, not {}",
+                item.id, item.version, expected_version
+            ),
+            current_version: item.version,
+        })
+    }
+}
+
+fn _get_smart_storage_item(id: &u64) -> Result<Option<SmartStorageItem>, Error> {
+    if let Some(item) = ITEM_CACHE.with(|cache| cache.borrow_mut().get(*id)) {
+        return Ok(Some(item));
+    }
+    let item = load_stored_item(*id)?;
+    if let Some(item) = &item {
+        cache_item(item);
+    }
+    Ok(item)
+}
+
+// Reads an item straight from the item map, bypassing ITEM_CACHE.
+fn load_stored_item(id: u64) -> Result<Option<SmartStorageItem>, Error> {
+    STORAGE_ITEM_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .map(|stored| decode_item(id, &stored))
+        .transpose()
+}
+
+fn encode_item(item: &SmartStorageItem) -> StoredItem {
+    let mut bytes = vec![ITEM_SCHEMA_VERSION];
+    bytes.extend(Encode!(item).expect("cannot encode item"));
+    StoredItem(bytes)
+}
+
+fn stored_item_version(stored: &StoredItem) -> Option<u8> {
+    if stored.0.starts_with(LEGACY_CANDID_MAGIC) {
+        Some(0)
+    } else {
+        stored.0.first().copied()
+    }
+}
+
+fn decode_versioned_item(stored: &StoredItem) -> Result<VersionedItem, Error> {
+    let decode_failed = |err: candid::Error| Error::DecodeFailed {
+        msg: format!("cannot decode item record: {}", err),
+    };
+    match stored_item_version(stored) {
+        Some(0) => Decode!(&stored.0, SmartStorageItemV0)
+            .map(VersionedItem::V0)
+            .map_err(decode_failed),
+        Some(1) => Decode!(&stored.0[1..], SmartStorageItemV1)
+            .map(VersionedItem::V1)
+            .map_err(decode_failed),
+        Some(2) => Decode!(&stored.0[1..], SmartStorageItem)
+            .map(VersionedItem::V2)
+            .map_err(decode_failed),
+        Some(version) => Err(Error::DecodeFailed {
+            msg: format!("unknown item schema version {}", version),
+        }),
+        None => Err(Error::DecodeFailed {
+            msg: "empty item record".to_string(),
+        }),
+    }
+}
+
+// Moves a record one schema version forward.
+fn upgrade_item_record(record: VersionedItem) -> VersionedItem {
+    match record {
+        VersionedItem::V0(item) => VersionedItem::V1(item),
+        VersionedItem::V1(item) => VersionedItem::V2(SmartStorageItem {
+            id: item.id,
+            name: item.name,
+            description: item.description,
+            location: item.location,
+            created_at: item.created_at,
+            updated_at: item.updated_at,
+            is_available: item.is_available,
+            version: 1,
+        }),
+        current @ VersionedItem::V2(_) => current,
+    }
+}
+
+// Decodes a stored record of any known version, migrating it lazily to the
+// current schema. The record is rewritten at the current version on its next write.
+fn decode_item(id: u64, stored: &StoredItem) -> Result<SmartStorageItem, Error> {
+    let mut record = decode_versioned_item(&load_item_envelope(id, stored)?)?;
+    loop {
+        match record {
+            VersionedItem::V2(item) => return Ok(item),
+            older => record = upgrade_item_record(older),
+        }
+    }
+}
+
+// Settings a controller can pass on install or upgrade. Each one goes through
+// the checks of its setter endpoint; an invalid one makes the install trap.
+#[derive(candid::CandidType, Deserialize, Default)]
+struct InitArgs {
+    field_limits: Option<ItemFieldLimits>,
+    trash_retention_ns: Option<u64>,
+    item_cache_capacity: Option<u32>,
+    archive: Option<ArchiveConfig>,
+    shard: Option<ShardConfig>,
+    idempotency_window_ns: Option<u64>,
+}
+
+// Derived indexes and the layout version this build writes. Bump an index's
+// version whenever its layout or contents change; post_upgrade then rebuilds
+// it from its source store. AUDIT_LOG_BY_ITEM is not listed because its
+// entries for archived history cannot be rebuilt.
+const DERIVED_INDEXES: [(u8, u32); 7] = [
+    (TRASH_BY_TIME_MEMORY, 1),
+    (WATCHLISTS_MEMORY, 1),
+    (AUDIT_LOG_BY_CALLER_MEMORY, 1),
+    (IDEMPOTENCY_BY_TIME_MEMORY, 1),
+    (NOTIFICATIONS_BY_ID_MEMORY, 1),
+    (ITEMS_BY_LOCATION_MEMORY, 1),
+    (AVAILABLE_ITEMS_MEMORY, 1),
+];
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    assert_memory_regions();
+    // Every index starts out empty, which is current for any version.
+    for (index, version) in DERIVED_INDEXES {
+        INDEX_VERSIONS.with(|versions| versions.borrow_mut().insert(index, version));
+    }
+    apply_init_args(args);
+    start_retention_timer();
+    start_archive_timer();
+}
+
+// Refuses the upgrade by trapping when stable state cannot be read, so the
+// canister keeps running the previous build.
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    assert_memory_regions();
+    if let Err(problem) = validate_stable_state() {
+        ic_cdk::trap(&format!("upgrade refused: {}", problem));
+    }
+    rebuild_stale_indexes();
+    move_legacy_retention_limits();
+    apply_init_args(args);
+    // Certified data does not survive an upgrade.
+    system::set_certified_data(&audit_chain_head_hash());
+    start_item_migration();
+    start_retention_timer();
+    start_archive_timer();
+}
+
+fn apply_init_args(args: Option<InitArgs>) {
+    let Some(args) = args else {
+        return;
+    };
+    let mut result = Ok(());
+    if let Some(limits) = args.field_limits {
+        result = result.and(set_item_field_limits(limits).map(|_| ()));
+    }
+    if let Some(retention) = args.trash_retention_ns {
+        result = result.and(set_trash_retention(retention).map(|_| ()));
+    }
+    if let Some(capacity) = args.item_cache_capacity {
+        result = result.and(set_item_cache_capacity(capacity).map(|_| ()));
+    }
+    if let Some(config) = args.archive {
+        result = result.and(set_archive_config(config).map(|_| ()));
+    }
+    if let Some(config) = args.shard {
+        result = result.and(set_shard_config(config).map(|_| ()));
+    }
+    if let Some(window) = args.idempotency_window_ns {
+        result = result.and(set_idempotency_window(window).map(|_| ()));
+    }
+    if let Err(err) = result {
+        ic_cdk::trap(&format!("invalid init arguments: {}", error_message(&err)));
+    }
+}
+
+// Instructions post_upgrade may spend decoding item records, well inside the
+// upgrade's own limit.
+const UPGRADE_VALIDATION_INSTRUCTIONS: u64 = 50_000_000_000;
+
+// Reads every store once and checks the invariants the rest of the code relies
+// on. Cells and logs trap on their own when their contents cannot be decoded.
+fn validate_stable_state() -> Result<(), String> {
+    let next_id = ID_COUNTER.with(|counter| *counter.borrow().get());
+    COMMENT_ID_COUNTER.with(|counter| *counter.borrow().get());
+    NOTIFICATION_ID_COUNTER.with(|counter| *counter.borrow().get());
+    IDEMPOTENCY_REPLY_ID_COUNTER.with(|counter| *counter.borrow().get());
+    ITEM_FIELD_LIMITS.with(|limits| limits.borrow().get().clone());
+    LEGACY_TRASH_RETENTION_NS.with(|retention| *retention.borrow().get());
+    ITEM_CACHE_CAPACITY.with(|capacity| *capacity.borrow().get());
+    ARCHIVE_CONFIG.with(|config| config.borrow().get().clone());
+    LEGACY_IDEMPOTENCY_WINDOW_NS.with(|window| *window.borrow().get());
+    ITEM_SNAPSHOTS.with(|snapshots| snapshots.borrow().len());
+    let migration = ITEM_MIGRATION_STATE.with(|state| state.borrow().get().clone());
+    if migration.schema_version > ITEM_SCHEMA_VERSION {
+        return Err(format!(
+            "item records use schema version {}, newer than this build's {}",
+            migration.schema_version, ITEM_SCHEMA_VERSION
+        ));
+    }
+    // Every item record is decoded while the budget lasts. Past it only the
+    // last record is, and the rest are left to the item migration and
+    // check_integrity, which decode all of them.
+    let cannot_read = |id: u64, err: Error| format!("item id={} cannot be read: {}", id, error_message(&err));
+    let complete = STORAGE_ITEM_STORAGE.with(|service| {
+        for (id, stored) in service.borrow().iter() {
+            if system::instruction_counter() > UPGRADE_VALIDATION_INSTRUCTIONS {
+                return Ok(false);
+            }
+            decode_item(id, &stored).map_err(|err| cannot_read(id, err))?;
+        }
+        Ok::<_, String>(true)
+    })?;
+    if !complete {
+        if let Some((id, stored)) = STORAGE_ITEM_STORAGE.with(|service| service.borrow().last_key_value()) {
+            decode_item(id, &stored).map_err(|err| cannot_read(id, err))?;
+        }
+    }
+    if let Some(largest) = largest_item_id() {
+        if next_id <= largest {
+            return Err(format!("id counter {} is not above the largest item id {}", next_id, largest));
+        }
+    }
+    let shard = SHARD_CONFIG.with(|config| config.borrow().get().clone());
+    if next_id > shard.end_id {
+        return Err(format!("id counter {} is past the end of the id block at {}", next_id, shard.end_id));
+    }
+    if let Some(last) = audit_log_len().checked_sub(1).filter(|seq| *seq >= audit_log_base()) {
+        let entry = audit_entry(last).ok_or_else(|| format!("audit entry seq={} is missing", last))?;
+        if entry.seq != last || audit_entry_hash(&entry) != entry.hash {
+            return Err(format!("audit entry seq={} does not match its hash", last));
+        }
+    }
+    Ok(())
+}
+
+// Rebuilds every derived index whose stored layout version differs from
+// DERIVED_INDEXES, which includes indexes added since the last upgrade.
+fn rebuild_stale_indexes() {
+    for (index, version) in DERIVED_INDEXES {
+        if INDEX_VERSIONS.with(|versions| versions.borrow().get(&index)) == Some(version) {
+            continue;
+        }
+        rebuild_index(index);
+        INDEX_VERSIONS.with(|versions| versions.borrow_mut().insert(index, version));
+    }
+}
+
+fn rebuild_index(index: u8) {
+    match index {
+        TRASH_BY_TIME_MEMORY => {
+            TRASH_BY_TIME.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(TRASH_BY_TIME_MEMORY)));
+            TRASH.with(|trash| {
+                for (id, entry) in trash.borrow().iter() {
+                    TRASH_BY_TIME.with(|map| map.borrow_mut().insert((entry.deleted_at, id), ()));
+                }
+            });
+        }
+        WATCHLISTS_MEMORY => {
+            WATCHLISTS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(WATCHLISTS_MEMORY)));
+            WATCHERS_BY_ITEM.with(|watchers| {
+                for ((item_id, watcher), _) in watchers.borrow().iter() {
+                    WATCHLISTS.with(|map| map.borrow_mut().insert((watcher, item_id), ()));
+                }
+            });
+        }
+        AUDIT_LOG_BY_CALLER_MEMORY => {
+            AUDIT_LOG_BY_CALLER
+                .with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(AUDIT_LOG_BY_CALLER_MEMORY)));
+            for seq in audit_log_base()..audit_log_len() {
+                let entry = audit_entry(seq).expect("entries above the base are local");
+                AUDIT_LOG_BY_CALLER.with(|map| map.borrow_mut().insert((StoredPrincipal(entry.caller), seq), ()));
+            }
+        }
+        IDEMPOTENCY_BY_TIME_MEMORY => {
+            IDEMPOTENCY_BY_TIME
+                .with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(IDEMPOTENCY_BY_TIME_MEMORY)));
+            IDEMPOTENCY_KEYS.with(|keys| {
+                for (key, record) in keys.borrow().iter() {
+                    IDEMPOTENCY_BY_TIME.with(|map| map.borrow_mut().insert((record.recorded_at, record.reply_id), key));
+                }
+            });
+        }
+        NOTIFICATIONS_BY_ID_MEMORY => {
+            NOTIFICATIONS_BY_ID
+                .with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(NOTIFICATIONS_BY_ID_MEMORY)));
+            NOTIFICATIONS.with(|notifications| {
+                for ((owner, id), _) in notifications.borrow().iter() {
+                    NOTIFICATIONS_BY_ID.with(|map| map.borrow_mut().insert(id, owner));
+                }
+            });
+        }
+        ITEMS_BY_LOCATION_MEMORY => {
+            ITEMS_BY_LOCATION
+                .with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(ITEMS_BY_LOCATION_MEMORY)));
+            for item in list_items(None) {
+                index_item_location(None, Some(&item));
+            }
+        }
+        AVAILABLE_ITEMS_MEMORY => {
+            AVAILABLE_ITEMS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(AVAILABLE_ITEMS_MEMORY)));
+            for item in list_items(None) {
+                index_item_availability(None, Some(&item));
+            }
+        }
+        _ => unreachable!("memory id {} is not a derived index", index),
+    }
+}
+
+// Starts (or resumes, if an upgrade interrupted it) the eager rewrite of every
+// record whose envelope is older than ITEM_SCHEMA_VERSION or still stored inline.
+fn start_item_migration() {
+    let state = ITEM_MIGRATION_STATE.with(|state| state.borrow().get().clone());
+    if state.schema_version == ITEM_SCHEMA_VERSION && state.cursor.is_none() {
+        return;
+    }
+    if state.cursor.is_none() {
+        set_item_migration_state(ItemMigrationState {
+            cursor: Some(0),
+            ..state
+        });
+    }
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, run_item_migration_batch);
+}
+
+fn run_item_migration_batch() {
+    let mut state = ITEM_MIGRATION_STATE.with(|state| state.borrow().get().clone());
+    let Some(start) = state.cursor else {
+        return;
+    };
+    let mut next = Some(start);
+    while let Some(key) = next {
+        if system::instruction_counter() > MIGRATION_BATCH_INSTRUCTIONS {
+            break;
+        }
+        let Some((id, stored)) =
+            STORAGE_ITEM_STORAGE.with(|service| service.borrow().range(key..).next())
+        else {
+            next = None;
+            break;
+        };
+        next = id.checked_add(1);
+        let in_blob_store = stored.0.first() == Some(&ITEM_BLOB_REF_TAG);
+        let up_to_date = load_item_envelope(id, &stored)
+            .map(|envelope| stored_item_version(&envelope) == Some(ITEM_SCHEMA_VERSION))
+            .unwrap_or(false);
+        if in_blob_store && up_to_date {
+            continue;
+        }
+        match decode_item(id, &stored) {
+            Ok(item) => {
+                do_insert_smart_storage_item(&item);
+                state.migrated += 1;
+            }
+            Err(_) => state.failed += 1,
+        }
+    }
+    state.cursor = next;
+    if next.is_none() {
+        state.schema_version = ITEM_SCHEMA_VERSION;
+    }
+    set_item_migration_state(state);
+    if next.is_some() {
+        ic_cdk_timers::set_timer(std::time::Duration::ZERO, run_item_migration_batch);
+    }
+}
+
+fn set_item_migration_state(state: ItemMigrationState) {
+    ITEM_MIGRATION_STATE
+        .with(|cell| cell.borrow_mut().set(state))
+        .expect("cannot persist item migration state");
+}
+
+#[ic_cdk::query]
+fn get_item_migration_status() -> ItemMigrationState {
+    ITEM_MIGRATION_STATE.with(|state| state.borrow().get().clone())
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ChangeRecord {
+    seq: u64,
+    timestamp: u64,
+    change_type: String,
+    caller: Principal,
+    changes: Vec<FieldChange>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ItemStatistics {
+    total_items: usize,
+    average_availability_rate: f64,
+}
+
+#[ic_cdk::query]
+fn sort_items_by_name(as_of: Option<u64>) -> Vec<SmartStorageItem> {
+    let mut items = redact_items(list_items(as_of));
+
+    items.sort_by(|a, b| a.name.cmp(&b.name));
+    items
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ItemHistoryPage {
+    entries: Vec<ChangeRecord>,
+    archived: Option<ArchivedItemHistory>,
+}
+
+#[ic_cdk::query]
+fn get_item_history(id: u64, limit: usize, offset: usize) -> ItemHistoryPage {
+    let hidden = hidden_fields();
+    let (entries, archived) = item_audit_entries(id, limit, offset);
+    let entries = entries
+        .into_iter()
+        .map(|entry| redact_audit_entry(entry, &hidden))
+        .map(|entry| ChangeRecord {
+            seq: entry.seq,
+            timestamp: entry.timestamp,
+            change_type: entry.operation.label().to_string(),
+            caller: entry.caller,
+            changes: entry.changes,
+        })
+        .collect();
+    ItemHistoryPage { entries, archived }
+}
+
+#[ic_cdk::query]
+fn batch_query(queries: Vec<Query>) -> Vec<QueryResult> {
+    let hidden = hidden_fields();
+    let mut results = Vec::new();
+    for query in queries {
+        match query {
+            Query::GetItem(id) => {
+                match _get_smart_storage_item(&id) {
+                    Ok(Some(mut item)) => {
+                        redact_item(&mut item, &hidden);
+                        results.push(QueryResult::Item(item))
+                    }
+                    Ok(None) => results.push(QueryResult::Error(Error::NotFound {
+                        msg: format!("an item with id={} not found", id),
+                    })),
+                    Err(err) => results.push(QueryResult::Error(err)),
+                }
+            }
+        }
+    }
+    results
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum BulkMode {
+    // Apply every entry that passes, report the rest.
+    BestEffort,
+    // Validate every entry first and apply nothing if any fails.
+    Atomic,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum BulkEntryStatus {
+    Applied(SmartStorageItem),
+    Failed(Error),
+    // Valid, but skipped because another entry of an atomic batch failed.
+    NotApplied,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct BulkEntryReport {
+    index: u64,
+    status: BulkEntryStatus,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct BulkReport {
+    applied: u64,
+    failed: u64,
+    entries: Vec<BulkEntryReport>,
+    // Index of the first unprocessed entry when the batch did not fit in one message.
+    continuation: Option<u64>,
+}
+
+// Instructions a best-effort bulk call may use before handing back a
+// continuation, leaving headroom under the per-message limit.
+const BULK_BATCH_INSTRUCTIONS: u64 = 4_000_000_000;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum Query {
+    GetItem(u64),
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum QueryResult {
+    Item(SmartStorageItem),
+    Error(Error),
+}
+
+#[ic_cdk::query]
+fn get_item_statistics() -> ItemStatistics {
+    // Both maps keep their length, so this reads no records.
+    let total_items = STORAGE_ITEM_STORAGE.with(|service| service.borrow().len()) as usize;
+    let total_available_items = AVAILABLE_ITEMS.with(|index| index.borrow().len()) as usize;
+    let average_availability_rate =
+        total_available_items as f64 / total_items as f64 * 100.0; // Calculate as a percentage
+
+    ItemStatistics {
+        total_items,
+        average_availability_rate,
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TransactionRecord {
+    seq: u64,
+    timestamp: u64,
+    change_type: String,
+    transaction_type: String,
+    caller: Principal,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TransactionHistoryPage {
+    entries: Vec<TransactionRecord>,
+    archived: Option<ArchivedItemHistory>,
+}
+
+#[ic_cdk::query]
+fn get_item_transaction_history(id: u64, limit: usize, offset: usize) -> TransactionHistoryPage {
+    let (entries, archived) = item_audit_entries(id, limit, offset);
+    let entries = entries
+        .into_iter()
+        .map(|entry| TransactionRecord {
+            seq: entry.seq,
+            timestamp: entry.timestamp,
+            change_type: entry.operation.label().to_string(),
+            transaction_type: entry.operation.label().to_string(),
+            caller: entry.caller,
+        })
+        .collect();
+    TransactionHistoryPage { entries, archived }
+}
+
+// Entries below the local base are not returned; `archived` tells the caller
+// which part of the requested range to fetch with get_archived_audit_log instead.
+#[ic_cdk::query]
+fn get_audit_log(start: u64, limit: usize) -> AuditLogPage {
+    let base = audit_log_base();
+    let archived = match ARCHIVE_CONFIG.with(|config| config.borrow().get().archive) {
+        Some(archive) if start < base => Some(ArchivedRange {
+            archive,
+            start,
+            length: (base - start).min(limit as u64),
+        }),
+        _ => None,
+    };
+    let remaining = limit.saturating_sub(archived.as_ref().map_or(0, |range| range.length as usize));
+    let hidden = hidden_fields();
+    let entries = (start.max(base)..audit_log_len())
+        .take(remaining)
+        .filter_map(audit_entry)
+        .map(|entry| redact_audit_entry(entry, &hidden))
+        .collect();
+    AuditLogPage { entries, archived }
+}
+
+// First sequence number still held locally; everything below it was archived.
+fn audit_log_base() -> u64 {
+    AUDIT_ARCHIVE_STATE.with(|state| state.borrow().get().base)
+}
+
+// Number of audit entries ever recorded, archived ones included.
+fn audit_log_len() -> u64 {
+    audit_log_base() + AUDIT_LOG.with(|log| log.borrow().len())
+}
+
+// The entry with sequence number `seq`, unless it was archived.
+fn audit_entry(seq: u64) -> Option<AuditEntry> {
+    let index = seq.checked_sub(audit_log_base())?;
+    AUDIT_LOG.with(|log| log.borrow().get(index))
+}
+
+fn record_audit(
+    operation: AuditOperation,
+    item_id: u64,
+    before: Option<&SmartStorageItem>,
+    after: Option<&SmartStorageItem>,
+) {
+    let first_entry = AUDIT_LOG_BY_ITEM.with(|index| index.borrow().range((item_id, 0)..=(item_id, u64::MAX)).next().is_none());
+    if first_entry && !matches!(operation, AuditOperation::Create) {
+        // Item predates the audit log: keep its pre-change state as the replay baseline.
+        store_item_snapshot(item_id, audit_log_len(), before);
+    }
+    let base = audit_log_base();
+    let prev_hash = audit_chain_head_hash();
+    let seq = AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let mut entry = AuditEntry {
+            seq: base + log.len(),
+            item_id,
+            operation,
+            caller: caller(),
+            timestamp: time(),
+            changes: diff_item_fields(before, after),
+            prev_hash,
+            hash: Vec::new(),
+        };
+        entry.hash = audit_entry_hash(&entry);
+        log.append(&entry).expect("cannot append to the audit log");
+        AUDIT_LOG_BY_ITEM.with(|index| index.borrow_mut().insert((item_id, entry.seq), ()));
+        AUDIT_LOG_BY_CALLER.with(|index| index.borrow_mut().insert((StoredPrincipal(entry.caller), entry.seq), ()));
+        system::set_certified_data(&entry.hash);
+        entry.seq
+    });
+    let since = ITEM_SNAPSHOT_INDEX.with(|snapshot_index| {
+        snapshot_index
+            .borrow()
+            .range((item_id, 0)..=(item_id, seq))
+            .last()
+            .map_or(0, |((_, snapshot_seq), _)| snapshot_seq)
+    });
+    let restored = matches!(operation, AuditOperation::Restore);
+    if restored || item_audit_seqs(item_id, since).take(ITEM_SNAPSHOT_INTERVAL).count() >= ITEM_SNAPSHOT_INTERVAL {
+        store_item_snapshot(item_id, seq + 1, after);
+    }
+}
+
+// Sequence numbers of the item's audit entries from `from_seq` on, oldest
+// first. Each step looks up the next one, so nothing is collected up front.
+fn item_audit_seqs(item_id: u64, from_seq: u64) -> impl Iterator<Item = u64> {
+    let mut next = Some(from_seq);
+    std::iter::from_fn(move || {
+        let ((_, seq), _) = AUDIT_LOG_BY_ITEM
+            .with(|index| index.borrow().range((item_id, next?)..=(item_id, u64::MAX)).next())?;
+        next = seq.checked_add(1);
+        Some(seq)
+    })
+}
+
+// Sequence numbers of the item's audit entries, newest first.
+fn item_audit_seqs_rev(item_id: u64) -> impl Iterator<Item = u64> {
+    let mut upper = u64::MAX;
+    std::iter::from_fn(move || {
+        let ((owner, seq), _) = AUDIT_LOG_BY_ITEM.with(|index| index.borrow().iter_upper_bound(&(item_id, upper)).next())?;
+        if owner != item_id {
+            return None;
+        }
+        upper = seq;
+        Some(seq)
+    })
+}
+
+fn store_item_snapshot(item_id: u64, seq: u64, item: Option<&SmartStorageItem>) {
+    let snapshot = ItemSnapshot(item.map(|item| encode_item(item).0).unwrap_or_default());
+    let index = ITEM_SNAPSHOTS
+        .with(|snapshots| snapshots.borrow().append(&snapshot))
+        .expect("cannot append an item snapshot");
+    ITEM_SNAPSHOT_INDEX.with(|snapshot_index| snapshot_index.borrow_mut().insert((item_id, seq), index));
+}
+
+// Latest snapshot of the item taken at or before `seq`.
+fn latest_item_snapshot(item_id: u64, seq: u64) -> Option<(u64, Option<SmartStorageItem>)> {
+    let (key, index) = ITEM_SNAPSHOT_INDEX.with(|snapshot_index| {
+        snapshot_index
+            .borrow()
+            .range((item_id, 0)..=(item_id, seq))
+            .last()
+    })?;
+    read_item_snapshot(item_id, index).map(|item| (key.1, item))
+}
+
+fn read_item_snapshot(item_id: u64, index: u64) -> Option<Option<SmartStorageItem>> {
+    let snapshot = ITEM_SNAPSHOTS.with(|snapshots| snapshots.borrow().get(index))?;
+    if snapshot.0.is_empty() {
+        return Some(None);
+    }
+    decode_item(item_id, &StoredItem(snapshot.0)).ok().map(Some)
+}
+
+// Sequence number of the last audit entry recorded at or before `timestamp`.
+// The log is appended in time order, so a binary search is enough. Fails when
+// the answer lies in the archived part of the log.
+fn last_audit_seq_at(timestamp: u64) -> Result<Option<u64>, Error> {
+    let state = AUDIT_ARCHIVE_STATE.with(|state| state.borrow().get().clone());
+    let local = AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let (mut low, mut high) = (0, log.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let recorded_at = log.get(mid).expect("audit log entry missing").timestamp;
+            if recorded_at <= timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low.checked_sub(1)
+    });
+    match local {
+        Some(index) => Ok(Some(state.base + index)),
+        None if state.base == 0 => Ok(None),
+        None if timestamp >= state.last_archived_timestamp => Ok(Some(state.base - 1)),
+        None => Err(archived_error(format!("audit history at {} is archived", timestamp))),
+    }
+}
+
+// Rebuilds an item's state at `timestamp` from the nearest snapshot plus the
+// audit entries recorded after it.
+fn item_as_of(id: u64, timestamp: u64) -> Result<Option<SmartStorageItem>, Error> {
+    let existed_at = |item: Option<SmartStorageItem>| item.filter(|item| item.created_at <= timestamp);
+    let Some(first_seq) = item_audit_seqs(id, 0).next() else {
+        // Never changed since the audit log started.
+        return Ok(existed_at(_get_smart_storage_item(&id)?));
+    };
+    let bound = last_audit_seq_at(timestamp)?;
+    Ok(existed_at(item_state_through(id, first_seq, bound)?))
+}
+
+// The item's state once every audit entry up to and including `bound` has
+// been applied. `first_seq` is the item's first audit entry.
+fn item_state_through(id: u64, first_seq: u64, bound: Option<u64>) -> Result<Option<SmartStorageItem>, Error> {
+    let (state, replay_from) = match bound.and_then(|seq| latest_item_snapshot(id, seq + 1)) {
+        Some((snapshot_seq, item)) => (item, snapshot_seq),
+        None if bound.is_some_and(|seq| seq >= first_seq) => (None, first_seq),
+        None => {
+            // Before the item's first recorded change: only a baseline snapshot can answer.
+            return Ok(latest_item_snapshot(id, first_seq).and_then(|(_, item)| item));
+        }
+    };
+    let bound = bound.expect("bound is set whenever entries are replayed");
+    item_audit_seqs(id, replay_from)
+        .take_while(|seq| *seq <= bound)
+        .try_fold(state, |state, seq| {
+            let entry = audit_entry(seq).ok_or_else(|| archived_error(format!("audit entry seq={} is archived", seq)))?;
+            Ok(apply_audit_entry(state, &entry))
+        })
+}
+
+fn apply_audit_entry(state: Option<SmartStorageItem>, entry: &AuditEntry) -> Option<SmartStorageItem> {
+    let mut item = match entry.operation {
+        AuditOperation::Delete | AuditOperation::Purge | AuditOperation::Retire => return None,
+        // Restores are always followed by a snapshot, so replay normally starts
+        // after them; rebuilding from the diff alone loses created_at and version.
+        AuditOperation::Create | AuditOperation::Restore => SmartStorageItem {
+            id: entry.item_id,
+            created_at: entry.timestamp,
+            version: 1,
+            ..Default::default()
+        },
+        _ => {
+            let mut item = state?;
+            item.version += 1;
+            item
+        }
+    };
+    if matches!(entry.operation, AuditOperation::Update) {
+        item.updated_at = Some(entry.timestamp);
+    }
+    for change in &entry.changes {
+        let Some(value) = change.after.clone() else {
+            continue;
+        };
+        match change.field.as_str() {
+            "name" => item.name = value,
+            "description" => item.description = value,
+            "location" => item.location = value,
+            "is_available" => item.is_available = value == "true",
+            _ => {}
+        }
+    }
+    Some(item)
+}
+
+// Hash over every field of the entry except `hash` itself.
+fn audit_entry_hash(entry: &AuditEntry) -> Vec<u8> {
+    let bytes = Encode!(
+        &entry.seq,
+        &entry.item_id,
+        &entry.operation,
+        &entry.caller,
+        &entry.timestamp,
+        &entry.changes,
+        &entry.prev_hash
+    )
+    .expect("cannot encode audit entry");
+    Sha256::digest(bytes).to_vec()
+}
+
+fn audit_chain_head_hash() -> Vec<u8> {
+    match audit_log_len().checked_sub(1) {
+        None => GENESIS_AUDIT_HASH.to_vec(),
+        Some(seq) => audit_entry_hash_at(seq),
+    }
+}
+
+// Hash of entry `seq`, which may be the last archived one.
+fn audit_entry_hash_at(seq: u64) -> Vec<u8> {
+    match audit_entry(seq) {
+        Some(entry) => entry.hash,
+        None => {
+            let state = AUDIT_ARCHIVE_STATE.with(|state| state.borrow().get().clone());
+            if seq + 1 == state.base {
+                state.last_archived_hash
+            } else {
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[ic_cdk::query]
+fn get_audit_chain_head() -> AuditChainHead {
+    AuditChainHead {
+        length: audit_log_len(),
+        head_hash: audit_chain_head_hash(),
+        certificate: ic_cdk::api::data_certificate(),
+    }
+}
+
+// Recomputes the chain over [start, start + limit) and reports the first entry
+// whose link or hash does not match. Archived entries are skipped, so `start`
+// in the result may be later than requested.
+#[ic_cdk::query]
+fn verify_audit_chain(start: u64, limit: u64) -> AuditChainVerification {
+    let start = start.max(audit_log_base());
+    let end = start.saturating_add(limit).min(audit_log_len());
+    let mut expected_prev = match start {
+        0 => GENESIS_AUDIT_HASH.to_vec(),
+        _ => audit_entry_hash_at(start - 1),
+    };
+    let mut checked = 0;
+    for seq in start..end {
+        let entry = audit_entry(seq).expect("audit log entry missing");
+        let reason = if entry.seq != seq {
+            Some(format!("entry claims seq={}", entry.seq))
+        } else if expected_prev != entry.prev_hash {
+            Some("prev_hash does not match the previous entry".to_string())
+        } else if audit_entry_hash(&entry) != entry.hash {
+            Some("hash does not match the entry contents".to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return AuditChainVerification {
+                start,
+                checked,
+                first_break: Some(AuditChainBreak { seq, reason }),
+            };
+        }
+        checked += 1;
+        expected_prev = entry.hash;
+    }
+    AuditChainVerification {
+        start,
+        checked,
+        first_break: None,
+    }
+}
+
+fn diff_item_fields(before: Option<&SmartStorageItem>, after: Option<&SmartStorageItem>) -> Vec<FieldChange> {
+    let fields = |item: Option<&SmartStorageItem>| -> [Option<String>; 4] {
+        match item {
+            Some(item) => [
+                Some(item.name.clone()),
+                Some(item.description.clone()),
+                Some(item.location.clone()),
+                Some(item.is_available.to_string()),
+            ],
+            None => [None, None, None, None],
+        }
+    };
+    ["name", "description", "location", "is_available"]
+        .into_iter()
+        .zip(fields(before).into_iter().zip(fields(after)))
+        .filter(|(_, (before, after))| before != after)
+        .map(|(field, (before, after))| FieldChange {
+            field: field.to_string(),
+            before,
+            after,
+        })
+        .collect()
+}
+
+// Audit entries for one item, newest first. AUDIT_LOG_BY_ITEM keeps the
+// sequence numbers of archived entries, and those are the item's oldest, so the
+// part of the page past the local entries maps onto the same page of the
+// archive's history for the item.
+fn item_audit_entries(item_id: u64, limit: usize, offset: usize) -> (Vec<AuditEntry>, Option<ArchivedItemHistory>) {
+    let seqs: Vec<u64> = AUDIT_LOG_BY_ITEM.with(|index| {
+        index
+            .borrow()
+            .range((item_id, 0)..=(item_id, u64::MAX))
+            .map(|((_, seq), _)| seq)
+            .collect()
+    });
+    let base = audit_log_base();
+    let local = seqs.iter().filter(|seq| **seq >= base).count();
+    let start = offset.max(local);
+    let end = offset.saturating_add(limit).min(seqs.len());
+    let archived = match ARCHIVE_CONFIG.with(|config| config.borrow().get().archive) {
+        Some(archive) if start < end => Some(ArchivedItemHistory {
+            archive,
+            offset: (start - local) as u64,
+            limit: (end - start) as u64,
+        }),
+        _ => None,
+    };
+    let entries = seqs
+        .into_iter()
+        .rev()
+        .skip(offset)
+        .take(limit)
+        .filter_map(audit_entry)
+        .collect();
+    (entries, archived)
+}
+
+
+#[ic_cdk::update]
+fn bulk_update_smart_storage_items(
+    updates: Vec<(u64, u64, SmartStorageItemPayload)>,
+    mode: BulkMode,
+    resume_from: Option<u64>,
+    idempotency_key: Option<String>,
+) -> Result<BulkReport, Error> {
+    redacted(idempotent(idempotency_key, "bulk_update_smart_storage_items", || {
+        Ok(run_bulk(
+            mode,
+            updates,
+            resume_from,
+            |(id, _, _)| Some(*id),
+            |(id, expected_version, payload)| {
+                validate_item_payload(payload)?;
+                get_item_at_version(*id, *expected_version).map(|_| ())
+            },
+            |(id, expected_version, payload)| _update_item(id, expected_version, payload),
+        ))
+    }))
+}
+
+#[ic_cdk::update]
+fn bulk_add_smart_storage_items(
+    items: Vec<SmartStorageItemPayload>,
+    mode: BulkMode,
+    resume_from: Option<u64>,
+    idempotency_key: Option<String>,
+) -> Result<BulkReport, Error> {
+    redacted(idempotent(idempotency_key, "bulk_add_smart_storage_items", || {
+        // Ids are handed out locally and the counter is written once for the whole batch.
+        let mut next_id = ID_COUNTER.with(|counter| *counter.borrow().get());
+        let atomic = matches!(mode, BulkMode::Atomic);
+        // An atomic batch settles shard capacity up front so it never stops halfway.
+        let planned_id = std::cell::Cell::new(next_id);
+        let check = |payload: &SmartStorageItemPayload| {
+            validate_item_payload(payload)?;
+            let id = planned_id.get();
+            planned_id.set(id + 1);
+            check_shard_capacity(id)
+        };
+        let report = run_bulk(mode, items, resume_from, |_| None, check, |payload| {
+            validate_item_payload(&payload)?;
+            if !atomic {
+                check_shard_capacity(next_id)?;
+            }
+            let id = next_id;
+            next_id += 1;
+            Ok(insert_new_item(id, payload))
+        });
+        ID_COUNTER
+            .with(|counter| counter.borrow_mut().set(next_id))
+            .expect("cannot increment id counter");
+        Ok(report)
+    }))
+}
+
+#[ic_cdk::update]
+fn bulk_delete_smart_storage_items(
+    deletions: Vec<(u64, u64)>,
+    mode: BulkMode,
+    resume_from: Option<u64>,
+    idempotency_key: Option<String>,
+) -> Result<BulkReport, Error> {
+    redacted(idempotent(idempotency_key, "bulk_delete_smart_storage_items", || {
+        Ok(run_bulk(
+            mode,
+            deletions,
+            resume_from,
+            |(id, _)| Some(*id),
+            |(id, expected_version)| get_item_at_version(*id, *expected_version).map(|_| ()),
+            |(id, expected_version)| _delete_item(id, expected_version),
+        ))
+    }))
+}
+
+// Applies `entries` one by one, starting at `resume_from`. In atomic mode every
+// entry is checked first (including for repeated item ids) and nothing is
+// applied unless all pass; an atomic batch that outgrows the message traps and
+// rolls back as a whole. In best-effort mode processing stops once
+// BULK_BATCH_INSTRUCTIONS are used and `continuation` tells the caller which
+// index to resume from with the same batch.
+fn run_bulk<T>(
+    mode: BulkMode,
+    entries: Vec<T>,
+    resume_from: Option<u64>,
+    item_id: impl Fn(&T) -> Option<u64>,
+    check: impl Fn(&T) -> Result<(), Error>,
+    mut apply: impl FnMut(T) -> Result<SmartStorageItem, Error>,
+) -> BulkReport {
+    let mut report = BulkReport::default();
+    let start = resume_from.unwrap_or(0) as usize;
+    let entries: Vec<(usize, T)> = entries.into_iter().enumerate().skip(start).collect();
+    let atomic = matches!(mode, BulkMode::Atomic);
+    if atomic {
+        let mut seen = std::collections::BTreeSet::new();
+        let checks: Vec<(usize, Result<(), Error>)> = entries
+            .iter()
+            .map(|(index, entry)| {
+                let result = match item_id(entry) {
+                    Some(id) if !seen.insert(id) => Err(Error::InvalidInput {
+                        msg: format!("item id={} appears more than once in the batch", id),
+                    }),
+                    _ => check(entry),
+                };
+                (*index, result)
+            })
+            .collect();
+        if checks.iter().any(|(_, result)| result.is_err()) {
+            for (index, result) in checks {
+                let status = match result {
+                    Ok(()) => BulkEntryStatus::NotApplied,
+                    Err(err) => {
+                        report.failed += 1;
+                        BulkEntryStatus::Failed(err)
+                    }
+                };
+                report.entries.push(BulkEntryReport {
+                    index: index as u64,
+                    status,
+                });
+            }
+            return report;
+        }
+    }
+    for (index, entry) in entries {
+        if !atomic && system::instruction_counter() > BULK_BATCH_INSTRUCTIONS {
+            report.continuation = Some(index as u64);
+            break;
+        }
+        let status = match apply(entry) {
+            Ok(item) => {
+                report.applied += 1;
+                BulkEntryStatus::Applied(item)
+            }
+            Err(err) => {
+                report.failed += 1;
+                BulkEntryStatus::Failed(err)
+            }
+        };
+        report.entries.push(BulkEntryReport {
+            index: index as u64,
+            status,
+        });
+    }
+    report
+}
+
+#[ic_cdk::query]
+fn get_paginated_smart_storage_items(limit: usize, offset: usize, as_of: Option<u64>) -> Vec<SmartStorageItem> {
+    if as_of.is_some() {
+        return redact_items(list_items(as_of).into_iter().skip(offset).take(limit).collect());
+    }
+    redact_items(STORAGE_ITEM_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|(id, stored)| decode_item(id, &stored).ok())
+            .collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn add_item_comment(item_id: u64, body: String, idempotency_key: Option<String>) -> Result<Comment, Error> {
+    idempotent(idempotency_key, "add_item_comment", || {
+        validate_comment_body(&body)?;
+        if !STORAGE_ITEM_STORAGE.with(|service| service.borrow().contains_key(&item_id)) {
+            return Err(Error::NotFound {
+                msg: format!("an item with id={} not found", item_id),
+            });
+        }
+        let id = COMMENT_ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("cannot increment comment id counter");
+        let comment = Comment {
+            id,
+            item_id,
+            author: caller(),
+            body,
+            created_at: time(),
+            updated_at: None,
+        };
+        COMMENT_STORAGE.with(|comments| comments.borrow_mut().insert((item_id, id), comment.clone()));
+        Ok(comment)
+    })
+}
+
+#[ic_cdk::update]
+fn edit_item_comment(
+    item_id: u64,
+    comment_id: u64,
+    body: String,
+    idempotency_key: Option<String>,
+) -> Result<Comment, Error> {
+    idempotent(idempotency_key, "edit_item_comment", || {
+        validate_comment_body(&body)?;
+        let mut comment = get_own_comment(item_id, comment_id)?;
+        comment.body = body;
+        comment.updated_at = Some(time());
+        COMMENT_STORAGE.with(|comments| comments.borrow_mut().insert((item_id, comment_id), comment.clone()));
+        Ok(comment)
+    })
+}
+
+#[ic_cdk::update]
+fn delete_item_comment(item_id: u64, comment_id: u64, idempotency_key: Option<String>) -> Result<Comment, Error> {
+    idempotent(idempotency_key, "delete_item_comment", || {
+        let comment = get_own_comment(item_id, comment_id)?;
+        COMMENT_STORAGE.with(|comments| comments.borrow_mut().remove(&(item_id, comment_id)));
+        Ok(comment)
+    })
+}
+
+#[ic_cdk::query]
+fn get_item_comments(item_id: u64, limit: usize, offset: usize) -> Vec<Comment> {
+    COMMENT_STORAGE.with(|comments| {
+        comments
+            .borrow()
+            .range((item_id, 0)..=(item_id, u64::MAX))
+            .skip(offset)
+            .take(limit)
+            .map(|(_, comment)| comment)
+            .collect()
+    })
+}
+
+fn validate_comment_body(body: &str) -> Result<(), Error> {
+    if body.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "comment body must not be empty".to_string(),
+        });
+    }
+    if body.len() > MAX_COMMENT_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!("comment body exceeds {} bytes", MAX_COMMENT_LENGTH),
+        });
+    }
+    Ok(())
+}
+
+fn get_own_comment(item_id: u64, comment_id: u64) -> Result<Comment, Error> {
+    match COMMENT_STORAGE.with(|comments| comments.borrow().get(&(item_id, comment_id))) {
+        Some(comment) if comment.author == caller() => Ok(comment),
+        Some(_) => Err(Error::Unauthorized {
+            msg: format!("comment id={} belongs to another principal", comment_id),
+        }),
+        None => Err(Error::NotFound {
+            msg: format!("a comment with id={} on item id={} not found", comment_id, item_id),
+        }),
+    }
+}
+
+fn remove_item_comments(item_id: u64) {
+    COMMENT_STORAGE.with(|comments| {
+        let keys: Vec<_> = comments
+            .borrow()
+            .range((item_id, 0)..=(item_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        let mut comments = comments.borrow_mut();
+        for key in keys {
+            comments.remove(&key);
+        }
+    });
+}
+
+#[ic_cdk::update]
+fn watch_item(item_id: u64, idempotency_key: Option<String>) -> Result<(), Error> {
+    idempotent(idempotency_key, "watch_item", || {
+        if !STORAGE_ITEM_STORAGE.with(|service| service.borrow().contains_key(&item_id)) {
+            return Err(Error::NotFound {
+                msg: format!("an item with id={} not found", item_id),
+            });
+        }
+        let watcher = StoredPrincipal(caller());
+        WATCHERS_BY_ITEM.with(|watchers| watchers.borrow_mut().insert((item_id, watcher.clone()), ()));
+        WATCHLISTS.with(|watchlists| watchlists.borrow_mut().insert((watcher, item_id), ()));
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn unwatch_item(item_id: u64, idempotency_key: Option<String>) -> Result<(), Error> {
+    idempotent(idempotency_key, "unwatch_item", || {
+        let watcher = StoredPrincipal(caller());
+        match WATCHLISTS.with(|watchlists| watchlists.borrow_mut().remove(&(watcher.clone(), item_id))) {
+            Some(()) => {
+                WATCHERS_BY_ITEM.with(|watchers| watchers.borrow_mut().remove(&(item_id, watcher)));
+                Ok(())
+            }
+            None => Err(Error::NotFound {
+                msg: format!("item id={} is not on your watchlist", item_id),
+            }),
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_watchlist() -> Vec<u64> {
+    let watcher = StoredPrincipal(caller());
+    WATCHLISTS.with(|watchlists| {
+        watchlists
+            .borrow()
+            .range((watcher.clone(), 0)..=(watcher, u64::MAX))
+            .map(|((_, item_id), _)| item_id)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_notifications(unread_only: bool, limit: usize, offset: usize) -> Vec<Notification> {
+    let owner = StoredPrincipal(caller());
+    redacted(NOTIFICATIONS.with(|notifications| {
+        notifications
+            .borrow()
+            .range((owner.clone(), 0)..=(owner, u64::MAX))
+            .filter(|(_, notification)| !unread_only || !notification.read)
+            .skip(offset)
+            .take(limit)
+            .map(|(_, notification)| notification)
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_unread_notification_count() -> u64 {
+    let owner = StoredPrincipal(caller());
+    NOTIFICATIONS.with(|notifications| {
+        notifications
+            .borrow()
+            .range((owner.clone(), 0)..=(owner, u64::MAX))
+            .filter(|(_, notification)| !notification.read)
+            .count() as u64
+    })
+}
+
+#[ic_cdk::update]
+fn mark_notification_as_read(id: u64, idempotency_key: Option<String>) -> Result<Notification, Error> {
+    redacted(idempotent(idempotency_key, "mark_notification_as_read", || {
+        let key = (StoredPrincipal(caller()), id);
+        match NOTIFICATIONS.with(|notifications| notifications.borrow().get(&key)) {
+            Some(mut notification) => {
+                notification.read = true;
+                NOTIFICATIONS.with(|notifications| notifications.borrow_mut().insert(key, notification.clone()));
+                Ok(notification)
+            }
+            None => Err(Error::NotFound {
+                msg: format!("a notification with id={} not found", id),
+            }),
+        }
+    }))
+}
+
+#[ic_cdk::update]
+fn mark_all_notifications_as_read(idempotency_key: Option<String>) -> Result<u64, Error> {
+    idempotent(idempotency_key, "mark_all_notifications_as_read", || {
+        let owner = StoredPrincipal(caller());
+        Ok(NOTIFICATIONS.with(|notifications| {
+            let unread: Vec<_> = notifications
+                .borrow()
+                .range((owner.clone(), 0)..=(owner, u64::MAX))
+                .filter(|(_, notification)| !notification.read)
+                .collect();
+            let count = unread.len() as u64;
+            let mut notifications = notifications.borrow_mut();
+            for (key, mut notification) in unread {
+                notification.read = true;
+                notifications.insert(key, notification);
+            }
+            count
+        }))
+    })
+}
+
+fn diff_item_changes(item: &SmartStorageItem, payload: &SmartStorageItemPayload) -> Vec<ChangeKind> {
+    let mut changes = Vec::new();
+    if item.name != payload.name || item.description != payload.description {
+        changes.push(ChangeKind::Updated);
+    }
+    if item.location != payload.location {
+        changes.push(ChangeKind::Moved {
+            from: item.location.clone(),
+            to: payload.location.clone(),
+        });
+    }
+    if item.is_available != payload.is_available {
+        changes.push(ChangeKind::AvailabilityChanged {
+            is_available: payload.is_available,
+        });
+    }
+    changes
+}
+
+fn notify_watchers(item_id: u64, kind: ChangeKind) {
+    let watchers: Vec<StoredPrincipal> = WATCHERS_BY_ITEM.with(|watchers| {
+        watchers
+            .borrow()
+            .range((item_id, StoredPrincipal::default())..)
+            .take_while(|((watched_id, _), _)| *watched_id == item_id)
+            .map(|((_, watcher), _)| watcher)
+            .collect()
+    });
+    let changed_by = caller();
+    let timestamp = time();
+    for watcher in watchers {
+        let id = next_notification_id();
+        let notification = Notification {
+            id,
+            item_id,
+            kind: kind.clone(),
+            changed_by,
+            timestamp,
+            read: false,
+        };
+        NOTIFICATIONS_BY_ID.with(|index| index.borrow_mut().insert(id, watcher.clone()));
+        NOTIFICATIONS.with(|notifications| notifications.borrow_mut().insert((watcher, id), notification));
+    }
+}
+
+fn next_notification_id() -> u64 {
+    NOTIFICATION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment notification id counter")
+}
+
+fn remove_item_watchers(item_id: u64) {
+    let watchers: Vec<StoredPrincipal> = WATCHERS_BY_ITEM.with(|watchers| {
+        watchers
+            .borrow()
+            .range((item_id, StoredPrincipal::default())..)
+            .take_while(|((watched_id, _), _)| *watched_id == item_id)
+            .map(|((_, watcher), _)| watcher)
+            .collect()
+    });
+    for watcher in watchers {
+        WATCHERS_BY_ITEM.with(|watchers| watchers.borrow_mut().remove(&(item_id, watcher.clone())));
+        WATCHLISTS.with(|watchlists| watchlists.borrow_mut().remove(&(watcher, item_id)));
+    }
+}
+
+// Comments and watchers are kept while an item is in the trash so a restore
+// brings them back; they are dropped on purge.
+fn move_item_to_trash(item: &SmartStorageItem) {
+    let blob_ref = write_item_blob(item);
+    STORAGE_ITEM_STORAGE.with(|service| service.borrow_mut().remove(&item.id));
+    ITEM_CACHE.with(|cache| cache.borrow_mut().invalidate(item.id));
+    update_item_indexes(Some(item), None);
+    let entry = TrashEntry {
+        item_id: item.id,
+        deleted_by: caller(),
+        deleted_at: time(),
+        blob_ref: blob_ref.0,
+    };
+    TRASH_BY_TIME.with(|trash| trash.borrow_mut().insert((entry.deleted_at, item.id), ()));
+    TRASH.with(|trash| trash.borrow_mut().insert(item.id, entry));
+}
+
+fn get_trashed_item(id: u64) -> Result<(TrashEntry, SmartStorageItem), Error> {
+    let Some(entry) = TRASH.with(|trash| trash.borrow().get(&id)) else {
+        return Err(Error::NotFound {
+            msg: format!("an item with id={} not found in the trash", id),
+        });
+    };
+    let item = decode_item(id, &StoredItem(entry.blob_ref.clone()))?;
+    Ok((entry, item))
+}
+
+#[ic_cdk::query]
+fn list_trash(limit: usize, offset: usize) -> Vec<TrashedItem> {
+    let hidden = hidden_fields();
+    TRASH.with(|trash| {
+        trash
+            .borrow()
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|(id, entry)| {
+                let mut item = decode_item(id, &StoredItem(entry.blob_ref)).ok()?;
+                redact_item(&mut item, &hidden);
+                Some(TrashedItem {
+                    item,
+                    deleted_by: entry.deleted_by,
+                    deleted_at: entry.deleted_at,
+                })
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn restore_smart_storage_item(
+    id: u64,
+    expected_version: u64,
+    idempotency_key: Option<String>,
+) -> Result<SmartStorageItem, Error> {
+    redacted(idempotent(idempotency_key, "restore_smart_storage_item", || {
+        let (entry, item) = get_trashed_item(id)?;
+        check_item_version(&item, expected_version)?;
+        Ok(restore_trashed_item(&entry, item))
+    }))
+}
+
+fn restore_trashed_item(entry: &TrashEntry, mut item: SmartStorageItem) -> SmartStorageItem {
+    let id = entry.item_id;
+    TRASH.with(|trash| trash.borrow_mut().remove(&id));
+    TRASH_BY_TIME.with(|trash| trash.borrow_mut().remove(&(entry.deleted_at, id)));
+    item.version += 1;
+    do_insert_smart_storage_item(&item);
+    update_item_indexes(None, Some(&item));
+    record_audit(AuditOperation::Restore, id, None, Some(&item));
+    notify_watchers(id, ChangeKind::Restored);
+    item
+}
+
+// Permanently removes a trashed item. Only the principal that deleted it or a
+// controller may do this.
+#[ic_cdk::update]
+fn purge_smart_storage_item(
+    id: u64,
+    expected_version: u64,
+    idempotency_key: Option<String>,
+) -> Result<SmartStorageItem, Error> {
+    redacted(idempotent(idempotency_key, "purge_smart_storage_item", || {
+        let (entry, item) = get_trashed_item(id)?;
+        check_item_version(&item, expected_version)?;
+        if entry.deleted_by != caller() {
+            ensure_controller()?;
+        }
+        purge_trashed_item(&entry);
+        Ok(item)
+    }))
+}
+
+fn purge_trashed_item(entry: &TrashEntry) {
+    let id = entry.item_id;
+    TRASH.with(|trash| trash.borrow_mut().remove(&id));
+    TRASH_BY_TIME.with(|trash| trash.borrow_mut().remove(&(entry.deleted_at, id)));
+    remove_item_blob_chunks(id, 0);
+    remove_item_comments(id);
+    remove_item_watchers(id);
+    record_audit(AuditOperation::Purge, id, None, None);
+}
+
+// The age limit of the trash retention policy, with u64::MAX for no limit.
+#[ic_cdk::query]
+fn get_trash_retention() -> u64 {
+    RETENTION_POLICIES.with(|policies| policies.borrow().get().trash.max_age_ns.unwrap_or(u64::MAX))
+}
+
+#[ic_cdk::update]
+fn set_trash_retention(retention_ns: u64) -> Result<u64, Error> {
+    ensure_controller()?;
+    update_retention_policies(|policies| policies.trash.max_age_ns = age_limit(retention_ns));
+    Ok(retention_ns)
+}
+
+// A snapshot stream starts with SNAPSHOT_MAGIC, SNAPSHOT_FORMAT_VERSION and the
+// exporter's ITEM_SCHEMA_VERSION, followed by records laid out as
+// [section: u8][key length: u32 BE][key][value length: u32 BE][value].
+const SNAPSHOT_MAGIC: &[u8] = b"SISN";
+// Version 2 added the settings, role and retention run sections; a version 1
+// stream is a subset of it and still imports.
+const SNAPSHOT_FORMAT_VERSION: u8 = 2;
+const SNAPSHOT_HEADER_LEN: usize = SNAPSHOT_MAGIC.len() + 2;
+
+// Sections are numbered after the memory holding the store, in export order.
+// Items and trash come before blobs and the trash index so that a merge knows
+// which item ids it took over.
+const SNAPSHOT_SECTIONS: [u8; 30] = [
+    ID_COUNTER_MEMORY,
+    COMMENT_ID_COUNTER_MEMORY,
+    NOTIFICATION_ID_COUNTER_MEMORY,
+    ITEM_FIELD_LIMITS_MEMORY,
+    TRASH_RETENTION_MEMORY,
+    ITEM_CACHE_CAPACITY_MEMORY,
+    ARCHIVE_CONFIG_MEMORY,
+    SHARD_CONFIG_MEMORY,
+    IDEMPOTENCY_WINDOW_MEMORY,
+    RETENTION_POLICIES_MEMORY,
+    FIELD_VISIBILITY_MEMORY,
+    AUDIT_ARCHIVE_STATE_MEMORY,
+    ITEMS_MEMORY,
+    TRASH_MEMORY,
+    RETIRED_ITEMS_MEMORY,
+    ITEM_BLOBS_MEMORY,
+    TRASH_BY_TIME_MEMORY,
+    ARCHIVED_ITEMS_MEMORY,
+    COMMENTS_MEMORY,
+    WATCHERS_BY_ITEM_MEMORY,
+    WATCHLISTS_MEMORY,
+    NOTIFICATIONS_MEMORY,
+    AUDIT_LOG_INDEX_MEMORY,
+    AUDIT_LOG_BY_ITEM_MEMORY,
+    AUDIT_LOG_BY_CALLER_MEMORY,
+    UNDO_LINKS_MEMORY,
+    ITEM_SNAPSHOT_INDEX_MEMORY,
+    ITEM_SNAPSHOTS_INDEX_MEMORY,
+    ROLE_MEMBERS_MEMORY,
+    RETENTION_RUNS_MEMORY,
+];
+
+// An exported page is closed once its data grows past this size.
+const SNAPSHOT_PAGE_SIZE: usize = 1024 * 1024;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SnapshotCursor {
+    section: u8,
+    // Raw key of the last record exported from `section`.
+    after: Option<Vec<u8>>,
+    // Checksum of the page this cursor was returned with.
+    checksum: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SnapshotPage {
+    data: Vec<u8>,
+    // sha256 over the previous page's checksum followed by `data`, so the last
+    // page's checksum covers the whole stream.
+    checksum: Vec<u8>,
+    next: Option<SnapshotCursor>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum SnapshotImportMode {
+    // Wipe every store and load the snapshot as-is.
+    Replace,
+    // Keep local data: only items (with their blobs) and records whose keys are
+    // not already present are added, counters take the larger value, and the
+    // audit log, its indexes and the settings are left alone.
+    Merge,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct SnapshotImportSummary {
+    imported: u64,
+    skipped: u64,
+}
+
+struct SnapshotImport {
+    mode: SnapshotImportMode,
+    data: Vec<u8>,
+    checksum: Vec<u8>,
+    complete: bool,
+}
+
+// Returns one page of the snapshot stream. Pass the previous page's `next`
+// cursor to continue; a page without one is the last.
+#[ic_cdk::query]
+fn export_snapshot(cursor: Option<SnapshotCursor>) -> Result<SnapshotPage, Error> {
+    ensure_controller()?;
+    let mut data = Vec::new();
+    let (mut position, mut after, previous) = match cursor {
+        Some(cursor) => {
+            let Some(position) = SNAPSHOT_SECTIONS.iter().position(|section| *section == cursor.section) else {
+                return Err(Error::InvalidInput {
+                    msg: format!("unknown snapshot section {}", cursor.section),
+                });
+            };
+            (position, cursor.after, cursor.checksum)
+        }
+        None => {
+            data.extend_from_slice(SNAPSHOT_MAGIC);
+            data.push(SNAPSHOT_FORMAT_VERSION);
+            data.push(ITEM_SCHEMA_VERSION);
+            (0, None, Vec::new())
+        }
+    };
+    let mut next = None;
+    while let Some(&section) = SNAPSHOT_SECTIONS.get(position) {
+        if let Some(last_key) = export_snapshot_section(section, after.take(), &mut data) {
+            next = Some((section, last_key));
+            break;
+        }
+        position += 1;
+    }
+    let checksum = snapshot_checksum(&previous, &data);
+    Ok(SnapshotPage {
+        next: next.map(|(section, last_key)| SnapshotCursor {
+            section,
+            after: Some(last_key),
+            checksum: checksum.clone(),
+        }),
+        data,
+        checksum,
+    })
+}
+
+// Appends the records of `section` that follow `after`. Returns the key of the
+// last record written if the page filled up before the section ended.
+fn export_snapshot_section(section: u8, after: Option<Vec<u8>>, out: &mut Vec<u8>) -> Option<Vec<u8>> {
+    match section {
+        ID_COUNTER_MEMORY => ID_COUNTER.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        COMMENT_ID_COUNTER_MEMORY => COMMENT_ID_COUNTER.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        NOTIFICATION_ID_COUNTER_MEMORY => NOTIFICATION_ID_COUNTER.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        ITEM_FIELD_LIMITS_MEMORY => ITEM_FIELD_LIMITS.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        TRASH_RETENTION_MEMORY => LEGACY_TRASH_RETENTION_NS.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        ITEM_CACHE_CAPACITY_MEMORY => ITEM_CACHE_CAPACITY.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        ARCHIVE_CONFIG_MEMORY => ARCHIVE_CONFIG.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        SHARD_CONFIG_MEMORY => SHARD_CONFIG.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        IDEMPOTENCY_WINDOW_MEMORY => LEGACY_IDEMPOTENCY_WINDOW_NS.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        RETENTION_POLICIES_MEMORY => RETENTION_POLICIES.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        FIELD_VISIBILITY_MEMORY => FIELD_VISIBILITY.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        ROLE_MEMBERS_MEMORY => ROLE_MEMBERS.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        RETENTION_RUNS_MEMORY => RETENTION_RUNS.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        AUDIT_ARCHIVE_STATE_MEMORY => AUDIT_ARCHIVE_STATE.with(|cell| export_snapshot_cell(&cell.borrow(), section, out)),
+        RETIRED_ITEMS_MEMORY => RETIRED_ITEMS.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        ARCHIVED_ITEMS_MEMORY => ARCHIVED_ITEMS.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        ITEMS_MEMORY => STORAGE_ITEM_STORAGE.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        TRASH_MEMORY => TRASH.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        ITEM_BLOBS_MEMORY => ITEM_BLOBS.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        TRASH_BY_TIME_MEMORY => TRASH_BY_TIME.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        COMMENTS_MEMORY => COMMENT_STORAGE.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        WATCHERS_BY_ITEM_MEMORY => WATCHERS_BY_ITEM.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        WATCHLISTS_MEMORY => WATCHLISTS.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        NOTIFICATIONS_MEMORY => NOTIFICATIONS.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        AUDIT_LOG_INDEX_MEMORY => AUDIT_LOG.with(|log| export_snapshot_log(&log.borrow(), section, after, out)),
+        AUDIT_LOG_BY_ITEM_MEMORY => AUDIT_LOG_BY_ITEM.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        AUDIT_LOG_BY_CALLER_MEMORY => AUDIT_LOG_BY_CALLER.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        UNDO_LINKS_MEMORY => UNDO_LINKS.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        ITEM_SNAPSHOT_INDEX_MEMORY => ITEM_SNAPSHOT_INDEX.with(|map| export_snapshot_map(&map.borrow(), section, after, out)),
+        ITEM_SNAPSHOTS_INDEX_MEMORY => ITEM_SNAPSHOTS.with(|log| export_snapshot_log(&log.borrow(), section, after, out)),
+        _ => None,
+    }
+}
+
+fn export_snapshot_cell<T: Storable>(cell: &Cell<T, Memory>, section: u8, out: &mut Vec<u8>) -> Option<Vec<u8>> {
+    push_snapshot_record(out, section, &[], &cell.get().to_bytes());
+    None
+}
+
+fn export_snapshot_map<K, V>(
+    map: &StableBTreeMap<K, V, Memory>,
+    section: u8,
+    after: Option<Vec<u8>>,
+    out: &mut Vec<u8>,
+) -> Option<Vec<u8>>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let start = match after {
+        Some(key) => Bound::Excluded(K::from_bytes(Cow::Owned(key))),
+        None => Bound::Unbounded,
+    };
+    for (key, value) in map.range((start, Bound::Unbounded)) {
+        let key = key.to_bytes();
+        push_snapshot_record(out, section, &key, &value.to_bytes());
+        if out.len() >= SNAPSHOT_PAGE_SIZE {
+            return Some(key.into_owned());
+        }
+    }
+    None
+}
+
+// Log records are keyed by their index in the log.
+fn export_snapshot_log<T: Storable>(
+    log: &Log<T, Memory, Memory>,
+    section: u8,
+    after: Option<Vec<u8>>,
+    out: &mut Vec<u8>,
+) -> Option<Vec<u8>> {
+    let start = after.map_or(0, |key| u64::from_bytes(Cow::Owned(key)) + 1);
+    let mut entry = Vec::new();
+    for index in start..log.len() {
+        log.read_entry(index, &mut entry).expect("log entry below the log length");
+        let key = index.to_bytes();
+        push_snapshot_record(out, section, &key, &entry);
+        if out.len() >= SNAPSHOT_PAGE_SIZE {
+            return Some(key.into_owned());
+        }
+    }
+    None
+}
+
+fn push_snapshot_record(out: &mut Vec<u8>, section: u8, key: &[u8], value: &[u8]) {
+    out.push(section);
+    out.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    out.extend_from_slice(key);
+    out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+fn snapshot_checksum(previous: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(previous);
+    hasher.update(data);
+    hasher.finalize().to_vec()
+}
+
+fn check_snapshot_header(data: &[u8]) -> Result<(), Error> {
+    if data.len() < SNAPSHOT_HEADER_LEN || !data.starts_with(SNAPSHOT_MAGIC) {
+        return Err(Error::InvalidInput {
+            msg: "data does not start with a snapshot header".to_string(),
+        });
+    }
+    let format_version = data[SNAPSHOT_MAGIC.len()];
+    if format_version == 0 || format_version > SNAPSHOT_FORMAT_VERSION {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "snapshot format version {} is not supported (expected {})",
+                format_version, SNAPSHOT_FORMAT_VERSION
+            ),
+        });
+    }
+    let schema_version = data[SNAPSHOT_MAGIC.len() + 1];
+    if schema_version > ITEM_SCHEMA_VERSION {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "snapshot item schema version {} is newer than this canister's {}",
+                schema_version, ITEM_SCHEMA_VERSION
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Starts a new import, discarding any pages staged by an earlier one.
+#[ic_cdk::update]
+fn begin_snapshot_import(mode: SnapshotImportMode) -> Result<(), Error> {
+    ensure_controller()?;
+    SNAPSHOT_IMPORT.with(|import| {
+        *import.borrow_mut() = Some(SnapshotImport {
+            mode,
+            data: Vec::new(),
+            checksum: Vec::new(),
+            complete: false,
+        })
+    });
+    Ok(())
+}
+
+// Stages one exported page, in order. Nothing is applied until
+// `finish_snapshot_import`.
+#[ic_cdk::update]
+fn import_snapshot_page(page: SnapshotPage) -> Result<(), Error> {
+    ensure_controller()?;
+    SNAPSHOT_IMPORT.with(|import| {
+        let mut import = import.borrow_mut();
+        let Some(import) = import.as_mut() else {
+            return Err(Error::InvalidInput {
+                msg: "no snapshot import in progress".to_string(),
+            });
+        };
+        if import.complete {
+            return Err(Error::InvalidInput {
+                msg: "the last snapshot page was already imported".to_string(),
+            });
+        }
+        if import.data.is_empty() {
+            check_snapshot_header(&page.data)?;
+        }
+        if snapshot_checksum(&import.checksum, &page.data) != page.checksum {
+            return Err(Error::InvalidInput {
+                msg: "snapshot page checksum mismatch".to_string(),
+            });
+        }
+        import.data.extend_from_slice(&page.data);
+        import.checksum = page.checksum;
+        import.complete = page.next.is_none();
+        Ok(())
+    })
+}
+
+// Applies a fully staged import. A record that fails to decode traps, which
+// rolls the whole call back and leaves the current state untouched.
+#[ic_cdk::update]
+fn finish_snapshot_import() -> Result<SnapshotImportSummary, Error> {
+    ensure_controller()?;
+    let complete = SNAPSHOT_IMPORT.with(|import| import.borrow().as_ref().map(|import| import.complete));
+    match complete {
+        None => {
+            return Err(Error::InvalidInput {
+                msg: "no snapshot import in progress".to_string(),
+            })
+        }
+        Some(false) => {
+            return Err(Error::InvalidInput {
+                msg: "the last snapshot page has not been imported yet".to_string(),
+            })
+        }
+        Some(true) => {}
+    }
+    // Check the stream before taking it, so a rejected import stays staged.
+    SNAPSHOT_IMPORT.with(|import| {
+        let import = import.borrow();
+        let data = &import.as_ref().expect("import checked above").data;
+        check_snapshot_header(data)?;
+        parse_snapshot_records(&data[SNAPSHOT_HEADER_LEN..]).map(|_| ())
+    })?;
+    let import = SNAPSHOT_IMPORT
+        .with(|import| import.borrow_mut().take())
+        .expect("import checked above");
+    let Ok(records) = parse_snapshot_records(&import.data[SNAPSHOT_HEADER_LEN..]) else {
+        unreachable!("records checked above");
+    };
+    let merge = matches!(import.mode, SnapshotImportMode::Merge);
+    if !merge {
+        reset_snapshot_stores();
+    }
+    ITEM_CACHE.with(|cache| cache.borrow_mut().clear());
+    let mut summary = SnapshotImportSummary::default();
+    let mut taken_items = std::collections::BTreeSet::new();
+    for (section, key, value) in records {
+        if apply_snapshot_record(section, key, value, merge, &mut taken_items) {
+            summary.imported += 1;
+        } else {
+            summary.skipped += 1;
+        }
+    }
+    rebuild_index(NOTIFICATIONS_BY_ID_MEMORY);
+    rebuild_index(ITEMS_BY_LOCATION_MEMORY);
+    rebuild_index(AVAILABLE_ITEMS_MEMORY);
+    if !merge {
+        system::set_certified_data(&audit_chain_head_hash());
+        move_legacy_retention_limits();
+    }
+    // Imported records may use an older envelope; have the migration rewrite them.
+    let state = ITEM_MIGRATION_STATE.with(|state| state.borrow().get().clone());
+    if state.cursor.is_none() {
+        set_item_migration_state(ItemMigrationState {
+            cursor: Some(0),
+            ..state
+        });
+        start_item_migration();
+    }
+    Ok(summary)
+}
+
+// (section, key, value) borrowed from the staged stream.
+type SnapshotRecord<'a> = (u8, &'a [u8], &'a [u8]);
+
+fn parse_snapshot_records(mut data: &[u8]) -> Result<Vec<SnapshotRecord<'_>>, Error> {
+    let mut records = Vec::new();
+    while let Some((&section, rest)) = data.split_first() {
+        if !SNAPSHOT_SECTIONS.contains(&section) {
+            return Err(Error::InvalidInput {
+                msg: format!("unknown snapshot section {}", section),
+            });
+        }
+        let (key, rest) = split_snapshot_field(rest)?;
+        let (value, rest) = split_snapshot_field(rest)?;
+        records.push((section, key, value));
+        data = rest;
+    }
+    Ok(records)
+}
+
+fn split_snapshot_field(data: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let truncated = || Error::InvalidInput {
+        msg: "snapshot record is truncated".to_string(),
+    };
+    let (len, rest) = data.split_at_checked(4).ok_or_else(truncated)?;
+    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+    rest.split_at_checked(len).ok_or_else(truncated)
+}
+
+fn reset_snapshot_stores() {
+    STORAGE_ITEM_STORAGE.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(ITEMS_MEMORY)));
+    COMMENT_STORAGE.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(COMMENTS_MEMORY)));
+    WATCHERS_BY_ITEM.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(WATCHERS_BY_ITEM_MEMORY)));
+    WATCHLISTS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(WATCHLISTS_MEMORY)));
+    NOTIFICATIONS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(NOTIFICATIONS_MEMORY)));
+    ITEM_BLOBS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(ITEM_BLOBS_MEMORY)));
+    AUDIT_ARCHIVE_STATE
+        .with(|cell| cell.borrow_mut().set(AuditArchiveState::default()))
+        .expect("cannot persist the audit archive state");
+    AUDIT_LOG.with(|log| *log.borrow_mut() = Log::new(stable_memory(AUDIT_LOG_INDEX_MEMORY), stable_memory(AUDIT_LOG_DATA_MEMORY)));
+    AUDIT_LOG_BY_ITEM.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(AUDIT_LOG_BY_ITEM_MEMORY)));
+    AUDIT_LOG_BY_CALLER.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(AUDIT_LOG_BY_CALLER_MEMORY)));
+    UNDO_LINKS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(UNDO_LINKS_MEMORY)));
+    ITEM_SNAPSHOT_INDEX.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(ITEM_SNAPSHOT_INDEX_MEMORY)));
+    ITEM_SNAPSHOTS.with(|log| *log.borrow_mut() = Log::new(stable_memory(ITEM_SNAPSHOTS_INDEX_MEMORY), stable_memory(ITEM_SNAPSHOTS_DATA_MEMORY)));
+    TRASH.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(TRASH_MEMORY)));
+    TRASH_BY_TIME.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(TRASH_BY_TIME_MEMORY)));
+    RETIRED_ITEMS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(RETIRED_ITEMS_MEMORY)));
+    ARCHIVED_ITEMS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(ARCHIVED_ITEMS_MEMORY)));
+    ROLE_MEMBERS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(ROLE_MEMBERS_MEMORY)));
+    RETENTION_RUNS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(RETENTION_RUNS_MEMORY)));
+    // Replies recorded before the import describe state that is gone.
+    IDEMPOTENCY_KEYS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(IDEMPOTENCY_KEYS_MEMORY)));
+    IDEMPOTENCY_REPLIES.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(IDEMPOTENCY_REPLIES_MEMORY)));
+    IDEMPOTENCY_BY_TIME.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(IDEMPOTENCY_BY_TIME_MEMORY)));
+}
+
+// Applies one record and reports whether it was taken. `taken_items` collects
+// the item ids whose blobs, trash index entries, comments, watchers and
+// notifications belong to the import.
+fn apply_snapshot_record(
+    section: u8,
+    key: &[u8],
+    value: &[u8],
+    merge: bool,
+    taken_items: &mut std::collections::BTreeSet<u64>,
+) -> bool {
+    match section {
+        ID_COUNTER_MEMORY => ID_COUNTER.with(|cell| import_snapshot_counter(&mut cell.borrow_mut(), value, merge)),
+        COMMENT_ID_COUNTER_MEMORY => COMMENT_ID_COUNTER.with(|cell| import_snapshot_counter(&mut cell.borrow_mut(), value, merge)),
+        NOTIFICATION_ID_COUNTER_MEMORY => NOTIFICATION_ID_COUNTER.with(|cell| import_snapshot_counter(&mut cell.borrow_mut(), value, merge)),
+        ITEM_FIELD_LIMITS_MEMORY
+        | TRASH_RETENTION_MEMORY
+        | ITEM_CACHE_CAPACITY_MEMORY
+        | ARCHIVE_CONFIG_MEMORY
+        | SHARD_CONFIG_MEMORY
+        | IDEMPOTENCY_WINDOW_MEMORY
+        | RETENTION_POLICIES_MEMORY
+        | FIELD_VISIBILITY_MEMORY
+        | RETENTION_RUNS_MEMORY
+        | AUDIT_ARCHIVE_STATE_MEMORY
+        | AUDIT_LOG_INDEX_MEMORY
+        | AUDIT_LOG_BY_ITEM_MEMORY
+        | AUDIT_LOG_BY_CALLER_MEMORY
+        | UNDO_LINKS_MEMORY
+        | ITEM_SNAPSHOT_INDEX_MEMORY
+        | ITEM_SNAPSHOTS_INDEX_MEMORY
+            if merge =>
+        {
+            false
+        }
+        ITEM_FIELD_LIMITS_MEMORY => ITEM_FIELD_LIMITS.with(|cell| import_snapshot_cell(&mut cell.borrow_mut(), value)),
+        TRASH_RETENTION_MEMORY => LEGACY_TRASH_RETENTION_NS.with(|cell| import_snapshot_cell(&mut cell.borrow_mut(), value)),
+        ITEM_CACHE_CAPACITY_MEMORY => ITEM_CACHE_CAPACITY.with(|cell| import_snapshot_cell(&mut cell.borrow_mut(), value)),
+        ARCHIVE_CONFIG_MEMORY => ARCHIVE_CONFIG.with(|cell| import_snapshot_cell(&mut cell.borrow_mut(), value)),
+        SHARD_CONFIG_MEMORY => SHARD_CONFIG.with(|cell| import_snapshot_cell(&mut cell.borrow_mut(), value)),
+        IDEMPOTENCY_WINDOW_MEMORY => LEGACY_IDEMPOTENCY_WINDOW_NS.with(|cell| import_snapshot_cell(&mut cell.borrow_mut(), value)),
+        RETENTION_POLICIES_MEMORY => RETENTION_POLICIES.with(|cell| import_snapshot_cell(&mut cell.borrow_mut(), value)),
+        FIELD_VISIBILITY_MEMORY => FIELD_VISIBILITY.with(|cell| import_snapshot_cell(&mut cell.borrow_mut(), value)),
+        ROLE_MEMBERS_MEMORY => ROLE_MEMBERS.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, merge)),
+        RETENTION_RUNS_MEMORY => RETENTION_RUNS.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, false)),
+        AUDIT_ARCHIVE_STATE_MEMORY => {
+            // The imported log always starts out in the primary regions.
+            let state = AuditArchiveState {
+                active_log: 0,
+                ..AuditArchiveState::from_bytes(Cow::Borrowed(value))
+            };
+            AUDIT_ARCHIVE_STATE
+                .with(|cell| cell.borrow_mut().set(state))
+                .expect("cannot persist the audit archive state");
+            true
+        }
+        ITEMS_MEMORY | TRASH_MEMORY | RETIRED_ITEMS_MEMORY => {
+            let id = u64::from_bytes(Cow::Borrowed(key));
+            let exists = item_exists(id) || ARCHIVED_ITEMS.with(|map| map.borrow().contains_key(&id));
+            if merge && exists {
+                return false;
+            }
+            taken_items.insert(id);
+            match section {
+                ITEMS_MEMORY => {
+                    STORAGE_ITEM_STORAGE.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, false))
+                }
+                TRASH_MEMORY => TRASH.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, false)),
+                _ => RETIRED_ITEMS.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, false)),
+            }
+        }
+        ARCHIVED_ITEMS_MEMORY => {
+            ARCHIVED_ITEMS.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, merge))
+        }
+        ITEM_BLOBS_MEMORY => {
+            let (id, _) = <(u64, u32)>::from_bytes(Cow::Borrowed(key));
+            taken_items.contains(&id)
+                && ITEM_BLOBS.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, false))
+        }
+        TRASH_BY_TIME_MEMORY => {
+            let (_, id) = <(u64, u64)>::from_bytes(Cow::Borrowed(key));
+            taken_items.contains(&id)
+                && TRASH_BY_TIME.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, false))
+        }
+        // On a merge, records of an item the import did not take would end up
+        // attached to the unrelated local item with the same id.
+        COMMENTS_MEMORY => {
+            let (item_id, _) = <(u64, u64)>::from_bytes(Cow::Borrowed(key));
+            (!merge || taken_items.contains(&item_id))
+                && COMMENT_STORAGE.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, merge))
+        }
+        WATCHERS_BY_ITEM_MEMORY => {
+            let (item_id, _) = <(u64, StoredPrincipal)>::from_bytes(Cow::Borrowed(key));
+            (!merge || taken_items.contains(&item_id))
+                && WATCHERS_BY_ITEM.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, merge))
+        }
+        WATCHLISTS_MEMORY => {
+            let (_, item_id) = <(StoredPrincipal, u64)>::from_bytes(Cow::Borrowed(key));
+            (!merge || taken_items.contains(&item_id))
+                && WATCHLISTS.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, merge))
+        }
+        NOTIFICATIONS_MEMORY if merge => {
+            let mut notification = Notification::from_bytes(Cow::Borrowed(value));
+            if !taken_items.contains(&notification.item_id) {
+                return false;
+            }
+            // The imported id may already be taken here, so the notification gets a local one.
+            let (owner, _) = <(StoredPrincipal, u64)>::from_bytes(Cow::Borrowed(key));
+            notification.id = next_notification_id();
+            NOTIFICATIONS.with(|map| map.borrow_mut().insert((owner, notification.id), notification));
+            true
+        }
+        NOTIFICATIONS_MEMORY => NOTIFICATIONS.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, false)),
+        AUDIT_LOG_INDEX_MEMORY => AUDIT_LOG.with(|log| import_snapshot_log_record(&log.borrow(), key, value)),
+        AUDIT_LOG_BY_ITEM_MEMORY => AUDIT_LOG_BY_ITEM.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, false)),
+        AUDIT_LOG_BY_CALLER_MEMORY => AUDIT_LOG_BY_CALLER.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, false)),
+        UNDO_LINKS_MEMORY => UNDO_LINKS.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, false)),
+        ITEM_SNAPSHOT_INDEX_MEMORY => ITEM_SNAPSHOT_INDEX.with(|map| import_snapshot_map_record(&mut map.borrow_mut(), key, value, false)),
+        ITEM_SNAPSHOTS_INDEX_MEMORY => ITEM_SNAPSHOTS.with(|log| import_snapshot_log_record(&log.borrow(), key, value)),
+        _ => false,
+    }
+}
+
+fn import_snapshot_counter(cell: &mut IdCell, value: &[u8], merge: bool) -> bool {
+    let imported = u64::from_bytes(Cow::Borrowed(value));
+    let value = if merge { imported.max(*cell.get()) } else { imported };
+    cell.set(value).expect("cannot persist counter");
+    true
+}
+
+fn import_snapshot_cell<T: Storable>(cell: &mut Cell<T, Memory>, value: &[u8]) -> bool {
+    cell.set(T::from_bytes(Cow::Borrowed(value)))
+        .expect("cannot persist an imported setting");
+    true
+}
+
+fn import_snapshot_map_record<K, V>(map: &mut StableBTreeMap<K, V, Memory>, key: &[u8], value: &[u8], merge: bool) -> bool
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let key = K::from_bytes(Cow::Borrowed(key));
+    if merge && map.contains_key(&key) {
+        return false;
+    }
+    map.insert(key, V::from_bytes(Cow::Borrowed(value)));
+    true
+}
+
+// Logs are only imported into an emptied log, so entries must arrive in index order.
+fn import_snapshot_log_record<T: Storable>(log: &Log<T, Memory, Memory>, key: &[u8], value: &[u8]) -> bool {
+    let index = log
+        .append(&T::from_bytes(Cow::Borrowed(value)))
+        .expect("cannot append to log");
+    if index != u64::from_bytes(Cow::Borrowed(key)) {
+        ic_cdk::trap("snapshot log entries are out of order");
+    }
+    true
+}
+
+// Instructions one integrity check call may use before handing back a cursor.
+const INTEGRITY_BATCH_INSTRUCTIONS: u64 = 2_000_000_000;
+
+// Stores are walked in this order; each phase resumes from the last key it checked.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum IntegrityPhase {
+    Counters,
+    Items,
+    ItemsByLocation,
+    AvailableItems,
+    Blobs,
+    Trash,
+    TrashIndex,
+    Comments,
+    Watchers,
+    Watchlists,
+    Notifications,
+    AuditIndex,
+    AuditCallerIndex,
+    AuditLog,
+}
+
+impl IntegrityPhase {
+    fn next(self) -> Option<Self> {
+        match self {
+            IntegrityPhase::Counters => Some(IntegrityPhase::Items),
+            IntegrityPhase::Items => Some(IntegrityPhase::ItemsByLocation),
+            IntegrityPhase::ItemsByLocation => Some(IntegrityPhase::AvailableItems),
+            IntegrityPhase::AvailableItems => Some(IntegrityPhase::Blobs),
+            IntegrityPhase::Blobs => Some(IntegrityPhase::Trash),
+            IntegrityPhase::Trash => Some(IntegrityPhase::TrashIndex),
+            IntegrityPhase::TrashIndex => Some(IntegrityPhase::Comments),
+            IntegrityPhase::Comments => Some(IntegrityPhase::Watchers),
+            IntegrityPhase::Watchers => Some(IntegrityPhase::Watchlists),
+            IntegrityPhase::Watchlists => Some(IntegrityPhase::Notifications),
+            IntegrityPhase::Notifications => Some(IntegrityPhase::AuditIndex),
+            IntegrityPhase::AuditIndex => Some(IntegrityPhase::AuditCallerIndex),
+            IntegrityPhase::AuditCallerIndex => Some(IntegrityPhase::AuditLog),
+            IntegrityPhase::AuditLog => None,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IntegrityCursor {
+    phase: IntegrityPhase,
+    // Raw key of the last entry checked in `phase`.
+    after: Option<Vec<u8>>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IntegrityIssue {
+    store: String,
+    key: String,
+    problem: String,
+    repaired: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct IntegrityReport {
+    checked: u64,
+    repaired: u64,
+    issues: Vec<IntegrityIssue>,
+    // Set when the walk ran out of instructions; pass it back to continue.
+    next: Option<IntegrityCursor>,
+}
+
+// Walks the stores looking for inconsistencies. With `repair` set, problems
+// that have an unambiguous fix (a lagging counter, a missing or stale index
+// entry, orphaned data) are fixed; the rest, such as records that do not
+// decode, are only reported.
+#[ic_cdk::update]
+fn check_integrity(cursor: Option<IntegrityCursor>, repair: bool) -> Result<IntegrityReport, Error> {
+    ensure_controller()?;
+    let mut report = IntegrityReport::default();
+    let (mut phase, mut after) = match cursor {
+        Some(cursor) => (cursor.phase, cursor.after),
+        None => (IntegrityPhase::Counters, None),
+    };
+    loop {
+        if let Some(resume_after) = check_integrity_phase(phase, after.take(), repair, &mut report) {
+            report.next = Some(IntegrityCursor {
+                phase,
+                after: resume_after,
+            });
+            break;
+        }
+        match phase.next() {
+            Some(next) => phase = next,
+            None => break,
+        }
+    }
+    Ok(report)
+}
+
+// Returns the position to resume from if the instruction budget ran out.
+fn check_integrity_phase(
+    phase: IntegrityPhase,
+    after: Option<Vec<u8>>,
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Option<Option<Vec<u8>>> {
+    match phase {
+        IntegrityPhase::Counters => {
+            check_id_counter(repair, report);
+            None
+        }
+        IntegrityPhase::Items => walk_integrity(&STORAGE_ITEM_STORAGE, after, report, |id, stored, report| {
+            match decode_item(*id, &stored) {
+                Ok(item) if item.id != *id => report_integrity_issue(
+                    report,
+                    "items",
+                    id.to_string(),
+                    format!("record holds item id={}", item.id),
+                    false,
+                ),
+                Ok(item) => check_item_indexed(&item, repair, report),
+                Err(err) => report_integrity_issue(report, "items", id.to_string(), error_message(&err), false),
+            }
+            if TRASH.with(|trash| trash.borrow().contains_key(id)) {
+                report_integrity_issue(report, "items", id.to_string(), "item is also in the trash".to_string(), false);
+            }
+        }),
+        IntegrityPhase::ItemsByLocation => walk_integrity(&ITEMS_BY_LOCATION, after, report, |key, _, report| {
+            let (LocationKey(location), id) = key;
+            // Items that do not decode are reported by the Items phase.
+            let Ok(item) = load_stored_item(*id) else {
+                return;
+            };
+            if item.as_ref().is_some_and(|item| item.location == *location) {
+                return;
+            }
+            if repair {
+                let indexed = SmartStorageItem {
+                    id: *id,
+                    location: location.clone(),
+                    ..Default::default()
+                };
+                index_item_location(Some(&indexed), item.as_ref());
+            }
+            report_integrity_issue(
+                report,
+                "location index",
+                format!("{}/{}", location, id),
+                "entry points at no live item at this location".to_string(),
+                repair,
+            );
+        }),
+        IntegrityPhase::AvailableItems => walk_integrity(&AVAILABLE_ITEMS, after, report, |id, _, report| {
+            let Ok(item) = load_stored_item(*id) else {
+                return;
+            };
+            if item.as_ref().is_some_and(|item| item.is_available) {
+                return;
+            }
+            if repair {
+                let indexed = SmartStorageItem {
+                    id: *id,
+                    ..Default::default()
+                };
+                index_item_availability(Some(&indexed), item.as_ref());
+            }
+            report_integrity_issue(
+                report,
+                "availability index",
+                id.to_string(),
+                "entry points at no available live item".to_string(),
+                repair,
+            );
+        }),
+        IntegrityPhase::Blobs => walk_integrity(&ITEM_BLOBS, after, report, |key, _, report| {
+            if item_exists(key.0) {
+                return;
+            }
+            if repair {
+                ITEM_BLOBS.with(|blobs| blobs.borrow_mut().remove(key));
+            }
+            report_integrity_issue(
+                report,
+                "item blobs",
+                format!("{}/{}", key.0, key.1),
+                "chunk belongs to no item".to_string(),
+                repair,
+            );
+        }),
+        IntegrityPhase::Trash => walk_integrity(&TRASH, after, report, |id, entry, report| {
+            if entry.item_id != *id {
+                report_integrity_issue(
+                    report,
+                    "trash",
+                    id.to_string(),
+                    format!("entry holds item id={}", entry.item_id),
+                    false,
+                );
+            }
+            if let Err(err) = decode_item(*id, &StoredItem(entry.blob_ref.clone())) {
+                report_integrity_issue(report, "trash", id.to_string(), error_message(&err), false);
+            }
+            let key = (entry.deleted_at, *id);
+            if !TRASH_BY_TIME.with(|trash| trash.borrow().contains_key(&key)) {
+                if repair {
+                    TRASH_BY_TIME.with(|trash| trash.borrow_mut().insert(key, ()));
+                }
+                report_integrity_issue(
+                    report,
+                    "trash",
+                    id.to_string(),
+                    "missing from the trash time index".to_string(),
+                    repair,
+                );
+            }
+        }),
+        IntegrityPhase::TrashIndex => walk_integrity(&TRASH_BY_TIME, after, report, |key, _, report| {
+            let (deleted_at, id) = *key;
+            let indexed = TRASH.with(|trash| trash.borrow().get(&id)).is_some_and(|entry| entry.deleted_at == deleted_at);
+            if indexed {
+                return;
+            }
+            if repair {
+                TRASH_BY_TIME.with(|trash| trash.borrow_mut().remove(key));
+            }
+            report_integrity_issue(
+                report,
+                "trash time index",
+                format!("{}/{}", deleted_at, id),
+                "entry points at no trashed item".to_string(),
+                repair,
+            );
+        }),
+        IntegrityPhase::Comments => walk_integrity(&COMMENT_STORAGE, after, report, |key, comment, report| {
+            let (item_id, comment_id) = *key;
+            let label = format!("{}/{}", item_id, comment_id);
+            if comment.item_id != item_id || comment.id != comment_id {
+                report_integrity_issue(report, "comments", label.clone(), "record does not match its key".to_string(), false);
+            }
+            if !item_exists(item_id) {
+                if repair {
+                    COMMENT_STORAGE.with(|comments| comments.borrow_mut().remove(key));
+                }
+                report_integrity_issue(report, "comments", label, "comment belongs to no item".to_string(), repair);
+                return;
+            }
+            if comment_id >= COMMENT_ID_COUNTER.with(|counter| *counter.borrow().get()) {
+                if repair {
+                    COMMENT_ID_COUNTER
+                        .with(|counter| counter.borrow_mut().set(comment_id + 1))
+                        .expect("cannot increment comment id counter");
+                }
+                report_integrity_issue(
+                    report,
+                    "comments",
+                    label,
+                    "id is not below the comment counter".to_string(),
+                    repair,
+                );
+            }
+        }),
+        IntegrityPhase::Watchers => walk_integrity(&WATCHERS_BY_ITEM, after, report, |key, _, report| {
+            let (item_id, watcher) = key.clone();
+            let label = format!("{}/{}", item_id, watcher.0);
+            if !item_exists(item_id) {
+                if repair {
+                    WATCHERS_BY_ITEM.with(|watchers| watchers.borrow_mut().remove(key));
+                    WATCHLISTS.with(|watchlists| watchlists.borrow_mut().remove(&(watcher, item_id)));
+                }
+                report_integrity_issue(report, "watchers", label, "watches no item".to_string(), repair);
+            } else if !WATCHLISTS.with(|watchlists| watchlists.borrow().contains_key(&(watcher.clone(), item_id))) {
+                if repair {
+                    WATCHLISTS.with(|watchlists| watchlists.borrow_mut().insert((watcher, item_id), ()));
+                }
+                report_integrity_issue(report, "watchers", label, "missing from the watchlist index".to_string(), repair);
+            }
+        }),
+        IntegrityPhase::Watchlists => walk_integrity(&WATCHLISTS, after, report, |key, _, report| {
+            let (watcher, item_id) = key.clone();
+            let label = format!("{}/{}", watcher.0, item_id);
+            if !item_exists(item_id) {
+                if repair {
+                    WATCHLISTS.with(|watchlists| watchlists.borrow_mut().remove(key));
+                }
+                report_integrity_issue(report, "watchlists", label, "watches no item".to_string(), repair);
+            } else if !WATCHERS_BY_ITEM.with(|watchers| watchers.borrow().contains_key(&(item_id, watcher.clone()))) {
+                if repair {
+                    WATCHERS_BY_ITEM.with(|watchers| watchers.borrow_mut().insert((item_id, watcher), ()));
+                }
+                report_integrity_issue(report, "watchlists", label, "missing from the watcher index".to_string(), repair);
+            }
+        }),
+        IntegrityPhase::Notifications => walk_integrity(&NOTIFICATIONS, after, report, |key, _, report| {
+            let id = key.1;
+            if NOTIFICATIONS_BY_ID.with(|index| index.borrow().get(&id)).as_ref() != Some(&key.0) {
+                if repair {
+                    NOTIFICATIONS_BY_ID.with(|index| index.borrow_mut().insert(id, key.0.clone()));
+                }
+                report_integrity_issue(
+                    report,
+                    "notifications",
+                    format!("{}/{}", key.0 .0, id),
+                    "missing from the notification id index".to_string(),
+                    repair,
+                );
+            }
+            if id < NOTIFICATION_ID_COUNTER.with(|counter| *counter.borrow().get()) {
+                return;
+            }
+            if repair {
+                NOTIFICATION_ID_COUNTER
+                    .with(|counter| counter.borrow_mut().set(id + 1))
+                    .expect("cannot increment notification id counter");
+            }
+            report_integrity_issue(
+                report,
+                "notifications",
+                format!("{}/{}", key.0 .0, id),
+                "id is not below the notification counter".to_string(),
+                repair,
+            );
+        }),
+        IntegrityPhase::AuditIndex => walk_integrity(&AUDIT_LOG_BY_ITEM, after, report, |key, _, report| {
+            let (item_id, seq) = *key;
+            // Index entries of archived history are kept for as-of lookups.
+            if seq < audit_log_base() || audit_entry(seq).is_some_and(|entry| entry.item_id == item_id) {
+                return;
+            }
+            if repair {
+                AUDIT_LOG_BY_ITEM.with(|index| index.borrow_mut().remove(key));
+            }
+            report_integrity_issue(
+                report,
+                "audit index",
+                format!("{}/{}", item_id, seq),
+                "entry points at no audit record of this item".to_string(),
+                repair,
+            );
+        }),
+        IntegrityPhase::AuditCallerIndex => walk_integrity(&AUDIT_LOG_BY_CALLER, after, report, |key, _, report| {
+            let (StoredPrincipal(principal), seq) = key;
+            if *seq < audit_log_base() || audit_entry(*seq).is_some_and(|entry| entry.caller == *principal) {
+                return;
+            }
+            if repair {
+                AUDIT_LOG_BY_CALLER.with(|index| index.borrow_mut().remove(key));
+            }
+            report_integrity_issue(
+                report,
+                "audit caller index",
+                format!("{}/{}", principal, seq),
+                "entry points at no audit record of this caller".to_string(),
+                repair,
+            );
+        }),
+        IntegrityPhase::AuditLog => walk_audit_log_integrity(after, repair, report),
+    }
+}
+
+// A live item must be indexed under its location, and in AVAILABLE_ITEMS while
+// it is available. Stale entries are left to the index phases.
+fn check_item_indexed(item: &SmartStorageItem, repair: bool, report: &mut IntegrityReport) {
+    let key = (LocationKey(item.location.clone()), item.id);
+    if !ITEMS_BY_LOCATION.with(|index| index.borrow().contains_key(&key)) {
+        if repair {
+            index_item_location(None, Some(item));
+        }
+        report_integrity_issue(
+            report,
+            "items",
+            item.id.to_string(),
+            "missing from the location index".to_string(),
+            repair,
+        );
+    }
+    if item.is_available && !AVAILABLE_ITEMS.with(|index| index.borrow().contains_key(&item.id)) {
+        if repair {
+            index_item_availability(None, Some(item));
+        }
+        report_integrity_issue(
+            report,
+            "items",
+            item.id.to_string(),
+            "missing from the availability index".to_string(),
+            repair,
+        );
+    }
+}
+
+// Visits the entries of `store` after `after` one at a time, so `check` is free
+// to modify the store. Returns the resume position if the budget ran out.
+fn walk_integrity<K, V>(
+    store: &'static std::thread::LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    after: Option<Vec<u8>>,
+    report: &mut IntegrityReport,
+    mut check: impl FnMut(&K, V, &mut IntegrityReport),
+) -> Option<Option<Vec<u8>>>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let mut after = after.map(|key| K::from_bytes(Cow::Owned(key)));
+    loop {
+        if system::instruction_counter() > INTEGRITY_BATCH_INSTRUCTIONS {
+            return Some(after.map(|key| key.to_bytes().into_owned()));
+        }
+        let start = match &after {
+            Some(key) => Bound::Excluded(key.clone()),
+            None => Bound::Unbounded,
+        };
+        let (key, value) = store.with(|map| map.borrow().range((start, Bound::Unbounded)).next())?;
+        report.checked += 1;
+        check(&key, value, report);
+        after = Some(key);
+    }
+}
+
+// Every audit record must be reachable through AUDIT_LOG_BY_ITEM and
+// AUDIT_LOG_BY_CALLER. Repair also indexes records written before the caller
+// index existed.
+fn walk_audit_log_integrity(
+    after: Option<Vec<u8>>,
+    repair: bool,
+    report: &mut IntegrityReport,
+) -> Option<Option<Vec<u8>>> {
+    let len = audit_log_len();
+    let base = audit_log_base();
+    let mut seq = after.map_or(base, |key| u64::from_bytes(Cow::Owned(key)) + 1).max(base);
+    while seq < len {
+        if system::instruction_counter() > INTEGRITY_BATCH_INSTRUCTIONS {
+            return Some(seq.checked_sub(1).map(|last| last.to_bytes().into_owned()));
+        }
+        let entry = audit_entry(seq).expect("audit entry below the log length");
+        report.checked += 1;
+        if entry.seq != seq {
+            report_integrity_issue(
+                report,
+                "audit log",
+                seq.to_string(),
+                format!("record holds seq={}", entry.seq),
+                false,
+            );
+        }
+        let key = (entry.item_id, seq);
+        if !AUDIT_LOG_BY_ITEM.with(|index| index.borrow().contains_key(&key)) {
+            if repair {
+                AUDIT_LOG_BY_ITEM.with(|index| index.borrow_mut().insert(key, ()));
+            }
+            report_integrity_issue(
+                report,
+                "audit log",
+                seq.to_string(),
+                "missing from the per-item audit index".to_string(),
+                repair,
+            );
+        }
+        let key = (StoredPrincipal(entry.caller), seq);
+        if !AUDIT_LOG_BY_CALLER.with(|index| index.borrow().contains_key(&key)) {
+            if repair {
+                AUDIT_LOG_BY_CALLER.with(|index| index.borrow_mut().insert(key, ()));
+            }
+            report_integrity_issue(
+                report,
+                "audit log",
+                seq.to_string(),
+                "missing from the per-caller audit index".to_string(),
+                repair,
+            );
+        }
+        seq += 1;
+    }
+    None
+}
+
+// ID_COUNTER has to stay above every id in use, including retired and archived ones.
+fn check_id_counter(repair: bool, report: &mut IntegrityReport) {
+    let counter = ID_COUNTER.with(|counter| *counter.borrow().get());
+    report.checked += 1;
+    let Some(largest) = largest_item_id() else {
+        return;
+    };
+    if counter > largest {
+        return;
+    }
+    if repair {
+        ID_COUNTER
+            .with(|counter| counter.borrow_mut().set(largest + 1))
+            .expect("cannot increment id counter");
+    }
+    report_integrity_issue(
+        report,
+        "id counter",
+        counter.to_string(),
+        format!("counter is not above the largest item id {}", largest),
+        repair,
+    );
+}
+
+// Largest id in use by a live, trashed, retired or archived item.
+fn largest_item_id() -> Option<u64> {
+    let largest_live = STORAGE_ITEM_STORAGE.with(|service| service.borrow().last_key_value().map(|(id, _)| id));
+    let largest_trashed = TRASH.with(|trash| trash.borrow().last_key_value().map(|(id, _)| id));
+    let largest_retired = RETIRED_ITEMS.with(|items| items.borrow().last_key_value().map(|(id, _)| id));
+    let largest_archived = ARCHIVED_ITEMS.with(|items| items.borrow().last_key_value().map(|(id, _)| id));
+    largest_live.max(largest_trashed).max(largest_retired).max(largest_archived)
+}
+
+// Whether the item's data is still held here: live, trashed or waiting to be archived.
+fn item_exists(id: u64) -> bool {
+    STORAGE_ITEM_STORAGE.with(|service| service.borrow().contains_key(&id))
+        || TRASH.with(|trash| trash.borrow().contains_key(&id))
+        || RETIRED_ITEMS.with(|items| items.borrow().contains_key(&id))
+}
+
+fn report_integrity_issue(report: &mut IntegrityReport, store: &str, key: String, problem: String, repaired: bool) {
+    if repaired {
+        report.repaired += 1;
+    }
+    report.issues.push(IntegrityIssue {
+        store: store.to_string(),
+        key,
+        problem,
+        repaired,
+    });
+}
+
+fn error_message(err: &Error) -> String {
+    match err {
+        Error::NotFound { msg }
+        | Error::InvalidInput { msg }
+        | Error::Unauthorized { msg }
+        | Error::DecodeFailed { msg }
+        | Error::Conflict { msg, .. }
+        | Error::Archived { msg, .. }
+        | Error::CallFailed { msg }
+        | Error::ShardFull { msg } => msg.clone(),
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MemoryRegionUsage {
+    id: u8,
+    name: String,
+    pages: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MemoryUsage {
+    regions: Vec<MemoryRegionUsage>,
+    // Whole stable memory, including the memory manager's own bookkeeping.
+    stable_memory_pages: u64,
+    stable_memory_bytes: u64,
+}
+
+// Sizes are in 64 KiB WebAssembly pages.
+#[ic_cdk::query]
+fn get_memory_usage() -> MemoryUsage {
+    let regions = MEMORY_REGIONS
+        .iter()
+        .map(|(id, name)| MemoryRegionUsage {
+            id: *id,
+            name: name.to_string(),
+            pages: stable_memory(*id).size(),
+        })
+        .collect();
+    let stable_memory_pages = system::stable64_size();
+    MemoryUsage {
+        regions,
+        stable_memory_pages,
+        stable_memory_bytes: stable_memory_pages * WASM_PAGE_SIZE,
+    }
+}
+
+// Decoded items kept on the heap so hot reads skip the item map and the envelope
+// decode. Heap changes made by queries are discarded, so only update calls fill
+// the cache: writes drop the stale entry and store the item they just wrote.
+#[derive(Default)]
+struct ItemCache {
+    // Item id -> (item, tick of its last use).
+    entries: std::collections::HashMap<u64, (SmartStorageItem, u64)>,
+    // Tick of last use -> item id, least recently used first.
+    recency: std::collections::BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl ItemCache {
+    fn get(&mut self, id: u64) -> Option<SmartStorageItem> {
+        let (item, used) = self.entries.get_mut(&id)?;
+        self.recency.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.recency.insert(self.tick, id);
+        Some(item.clone())
+    }
+
+    fn insert(&mut self, item: SmartStorageItem, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        self.invalidate(item.id);
+        self.tick += 1;
+        self.recency.insert(self.tick, item.id);
+        self.entries.insert(item.id, (item, self.tick));
+        self.trim(capacity);
+    }
+
+    fn invalidate(&mut self, id: u64) {
+        if let Some((_, used)) = self.entries.remove(&id) {
+            self.recency.remove(&used);
+        }
+    }
+
+    fn trim(&mut self, capacity: usize) {
+        while self.entries.len() > capacity {
+            let Some((_, id)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&id);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+}
+
+// Upper bound for the cache capacity; items can be large, so the heap cost adds up.
+const MAX_ITEM_CACHE_CAPACITY: u32 = 4096;
+
+fn cache_item(item: &SmartStorageItem) {
+    let capacity = ITEM_CACHE_CAPACITY.with(|capacity| *capacity.borrow().get()) as usize;
+    ITEM_CACHE.with(|cache| cache.borrow_mut().insert(item.clone(), capacity));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ItemCacheStats {
+    capacity: u32,
+    cached: u64,
+}
+
+#[ic_cdk::query]
+fn get_item_cache_stats() -> ItemCacheStats {
+    ItemCacheStats {
+        capacity: ITEM_CACHE_CAPACITY.with(|capacity| *capacity.borrow().get()),
+        cached: ITEM_CACHE.with(|cache| cache.borrow().entries.len() as u64),
+    }
+}
+
+// Sets how many decoded items the heap cache may hold; 0 turns it off.
+#[ic_cdk::update]
+fn set_item_cache_capacity(capacity: u32) -> Result<u32, Error> {
+    ensure_controller()?;
+    if capacity > MAX_ITEM_CACHE_CAPACITY {
+        return Err(Error::InvalidInput {
+            msg: format!("capacity {} exceeds the ceiling of {}", capacity, MAX_ITEM_CACHE_CAPACITY),
+        });
+    }
+    ITEM_CACHE_CAPACITY
+        .with(|cell| cell.borrow_mut().set(capacity))
+        .expect("cannot persist item cache capacity");
+    ITEM_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if capacity == 0 {
+            cache.clear();
+        } else {
+            cache.trim(capacity as usize);
+        }
+    });
+    Ok(capacity)
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ItemLookupProfile {
+    // Instructions for reading and decoding the item from the item map.
+    stable_instructions: u64,
+    // Instructions for the same lookup served from the heap cache, if the item is cached.
+    cached_instructions: Option<u64>,
+}
+
+// Measures one lookup of `id` with and without the heap cache.
+#[ic_cdk::query]
+fn profile_item_lookup(id: u64) -> Result<ItemLookupProfile, Error> {
+    let start = system::instruction_counter();
+    let item = load_stored_item(id)?;
+    let stable_instructions = system::instruction_counter() - start;
+    if item.is_none() {
+        return Err(Error::NotFound {
+            msg: format!("an item with id={} not found", id),
+        });
+    }
+    let start = system::instruction_counter();
+    let cached = ITEM_CACHE.with(|cache| cache.borrow_mut().get(id));
+    let cached_instructions = system::instruction_counter() - start;
+    Ok(ItemLookupProfile {
+        stable_instructions,
+        cached_instructions: cached.map(|_| cached_instructions),
+    })
+}
+
+#[ic_cdk::query]
+fn get_archive_info() -> ArchiveInfo {
+    ArchiveInfo {
+        config: ArchiveConfig {
+            archive: ARCHIVE_CONFIG.with(|config| config.borrow().get().archive),
+        },
+        archived_history_end: audit_log_base(),
+        pending_retired_items: RETIRED_ITEMS.with(|items| items.borrow().len()),
+        archived_items: ARCHIVED_ITEMS.with(|items| items.borrow().len()),
+    }
+}
+
+// Once anything has been archived the archive canister can no longer be
+// changed, since clients are pointed at it for the archived data.
+#[ic_cdk::update]
+fn set_archive_config(config: ArchiveConfig) -> Result<ArchiveConfig, Error> {
+    ensure_controller()?;
+    let current = ARCHIVE_CONFIG.with(|config| config.borrow().get().archive);
+    let archived = audit_log_base() > 0 || !ARCHIVED_ITEMS.with(|items| items.borrow().is_empty());
+    if archived && config.archive != current {
+        return Err(Error::InvalidInput {
+            msg: "data was already archived; the archive canister cannot be changed".to_string(),
+        });
+    }
+    let stored = StoredArchiveConfig {
+        archive: config.archive,
+        history_retention_ns: None,
+    };
+    ARCHIVE_CONFIG
+        .with(|cell| cell.borrow_mut().set(stored))
+        .expect("cannot persist the archive config");
+    Ok(config)
+}
+
+// Runs an archive pass now instead of waiting for the timer.
+#[ic_cdk::update]
+async fn run_archive() -> Result<ArchiveProgress, Error> {
+    ensure_controller()?;
+    archive_pass().await
+}
+
+fn start_archive_timer() {
+    ic_cdk_timers::set_timer_interval(ARCHIVE_INTERVAL, || {
+        ic_cdk::spawn(async {
+            let _ = archive_pass().await;
+        })
+    });
+}
+
+async fn archive_pass() -> Result<ArchiveProgress, Error> {
+    let Some(archive) = ARCHIVE_CONFIG.with(|config| config.borrow().get().archive) else {
+        return Err(Error::InvalidInput {
+            msg: "no archive canister is configured".to_string(),
+        });
+    };
+    let Some(_running) = ArchiveRunGuard::acquire() else {
+        return Err(Error::InvalidInput {
+            msg: "an archive run is already in progress".to_string(),
+        });
+    };
+    let mut progress = ArchiveProgress::default();
+    let mut result = archive_history(archive, &mut progress).await;
+    if result.is_ok() {
+        result = archive_retired_items(archive, &mut progress).await;
+    }
+    result.map(|()| progress)
+}
+
+// Holds ARCHIVE_RUNNING for one pass. When a callback traps, the system runs
+// the call's cleanup, which drops the pass and with it the guard, so a failed
+// pass never leaves the flag set.
+struct ArchiveRunGuard;
+
+impl ArchiveRunGuard {
+    fn acquire() -> Option<Self> {
+        let running = ARCHIVE_RUNNING.with(|running| running.replace(true));
+        if running {
+            // Not `then_some`, which would build a guard here too and clear
+            // the flag of the run in progress when dropping it.
+            return None;
+        }
+        Some(ArchiveRunGuard)
+    }
+}
+
+impl Drop for ArchiveRunGuard {
+    fn drop(&mut self) {
+        ARCHIVE_RUNNING.with(|running| *running.borrow_mut() = false);
+    }
+}
+
+// Sends every local audit entry older than the retention period, or past the
+// history policy's max_count, to the archive, then compacts them out of the
+// local log.
+async fn archive_history(archive: Principal, progress: &mut ArchiveProgress) -> Result<(), Error> {
+    let policy = RETENTION_POLICIES.with(|policies| policies.borrow().get().history.clone());
+    let mut end = match policy.max_age_ns.map(|age| last_audit_seq_at(time().saturating_sub(age))) {
+        Some(Ok(Some(last))) => last + 1,
+        _ => 0,
+    };
+    if let Some(max_count) = policy.max_count {
+        end = end.max(audit_log_len().saturating_sub(max_count));
+    }
+    let base = audit_log_base();
+    if end <= base {
+        return Ok(());
+    }
+    let mut next = base;
+    while next < end {
+        let mut batch = Vec::new();
+        let mut size = 0;
+        while next < end && size < ARCHIVE_BATCH_BYTES {
+            let Some(entry) = audit_entry(next) else {
+                return Err(Error::InvalidInput {
+                    msg: format!("audit entry seq={} is no longer available", next),
+                });
+            };
+            size += entry.to_bytes().len();
+            batch.push(entry);
+            next += 1;
+        }
+        let count = batch.len() as u64;
+        let archived = call_archive(archive, "append_audit_entries", batch).await?;
+        if archived < next {
+            return Err(Error::CallFailed {
+                msg: format!("archive holds {} audit entries, expected at least {}", archived, next),
+            });
+        }
+        progress.archived_entries += count;
+    }
+    progress.compacted = compact_audit_log(end);
+    if progress.compacted {
+        record_retention_run(RetentionRun {
+            history: end - base,
+            ..RetentionRun::default()
+        });
+    }
+    Ok(())
+}
+
+// Copies the entries from `end` on into the other pair of audit log regions
+// and switches AUDIT_LOG over to it, dropping everything below `end`. The
+// regions left behind are overwritten by the next compaction.
+fn compact_audit_log(end: u64) -> bool {
+    let state = AUDIT_ARCHIVE_STATE.with(|state| state.borrow().get().clone());
+    let len = audit_log_len();
+    if end <= state.base || end > len || len - end > MAX_AUDIT_COMPACTION_ENTRIES {
+        return false;
+    }
+    let last_archived = audit_entry(end - 1).expect("entries above the base are local");
+    let active_log = 1 - state.active_log;
+    let (index, data) = audit_log_regions(active_log);
+    let compacted = Log::new(stable_memory(index), stable_memory(data));
+    for seq in end..len {
+        let entry = audit_entry(seq).expect("entries above the base are local");
+        compacted.append(&entry).expect("cannot append to the audit log");
+    }
+    AUDIT_LOG.with(|log| *log.borrow_mut() = compacted);
+    AUDIT_ARCHIVE_STATE
+        .with(|cell| {
+            cell.borrow_mut().set(AuditArchiveState {
+                base: end,
+                active_log,
+                last_archived_hash: last_archived.hash,
+                last_archived_timestamp: last_archived.timestamp,
+            })
+        })
+        .expect("cannot persist the audit archive state");
+    true
+}
+
+// Sends retired items to the archive and drops their local data once it has them.
+async fn archive_retired_items(archive: Principal, progress: &mut ArchiveProgress) -> Result<(), Error> {
+    let mut cursor = 0;
+    loop {
+        let mut batch = Vec::new();
+        let mut size = 0;
+        while size < ARCHIVE_BATCH_BYTES {
+            let Some((id, stored)) = RETIRED_ITEMS.with(|items| items.borrow().range(cursor..).next()) else {
+                break;
+            };
+            cursor = id + 1;
+            // Records that do not decode stay here for the integrity check to report.
+            if let Ok(item) = decode_item(id, &stored) {
+                size += encode_item(&item).0.len();
+                batch.push(item);
+            }
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<u64> = batch.iter().map(|item| item.id).collect();
+        call_archive(archive, "append_retired_items", batch).await?;
+        for id in ids {
+            if RETIRED_ITEMS.with(|items| items.borrow_mut().remove(&id)).is_none() {
+                continue;
+            }
+            remove_item_blob_chunks(id, 0);
+            remove_item_comments(id);
+            remove_item_watchers(id);
+            ARCHIVED_ITEMS.with(|items| items.borrow_mut().insert(id, ()));
+            progress.archived_items += 1;
+        }
+    }
+}
+
+// Calls an append method of the archive canister, which replies with its new size.
+async fn call_archive<T: candid::CandidType>(archive: Principal, method: &str, batch: Vec<T>) -> Result<u64, Error> {
+    let (result,): (Result<u64, String>,) = ic_cdk::call(archive, method, (batch,))
+        .await
+        .map_err(|(code, msg)| Error::CallFailed {
+            msg: format!("{} on the archive failed ({:?}): {}", method, code, msg),
+        })?;
+    result.map_err(|msg| Error::CallFailed {
+        msg: format!("{} was rejected by the archive: {}", method, msg),
+    })
+}
+
+// The archive only serves this canister and its own controllers, so callers
+// read archived data through the queries below, which clear the fields they may
+// not read. The archive has to be on this canister's subnet.
+#[ic_cdk::query(composite = true)]
+async fn get_archived_audit_log(start: u64, limit: usize) -> Result<Vec<AuditEntry>, Error> {
+    let hidden = hidden_fields();
+    let mut entries: Vec<AuditEntry> = query_archive("get_audit_log", (start, limit)).await?;
+    entries.redact(&hidden);
+    Ok(entries)
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_archived_item_history(item_id: u64, limit: usize, offset: usize) -> Result<Vec<AuditEntry>, Error> {
+    let hidden = hidden_fields();
+    let mut entries: Vec<AuditEntry> = query_archive("get_item_history", (item_id, limit, offset)).await?;
+    entries.redact(&hidden);
+    Ok(entries)
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_archived_item(id: u64) -> Result<SmartStorageItem, Error> {
+    let hidden = hidden_fields();
+    let item: Option<SmartStorageItem> = query_archive("get_retired_item", (id,)).await?;
+    let Some(mut item) = item else {
+        return Err(Error::NotFound {
+            msg: format!("the archive holds no item with id={}", id),
+        });
+    };
+    item.redact(&hidden);
+    Ok(item)
+}
+
+#[ic_cdk::query(composite = true)]
+async fn list_archived_items(limit: usize, offset: usize) -> Result<Vec<SmartStorageItem>, Error> {
+    let hidden = hidden_fields();
+    let mut items: Vec<SmartStorageItem> = query_archive("list_retired_items", (limit, offset)).await?;
+    items.redact(&hidden);
+    Ok(items)
+}
+
+async fn query_archive<A, R>(method: &str, args: A) -> Result<R, Error>
+where
+    A: candid::utils::ArgumentEncoder,
+    R: candid::CandidType + serde::de::DeserializeOwned,
+{
+    let Some(archive) = ARCHIVE_CONFIG.with(|config| config.borrow().get().archive) else {
+        return Err(Error::NotFound {
+            msg: "no archive canister is configured".to_string(),
+        });
+    };
+    let (result,): (Result<R, String>,) = ic_cdk::call(archive, method, args)
+        .await
+        .map_err(|(code, msg)| Error::CallFailed {
+            msg: format!("{} on the archive failed ({:?}): {}", method, code, msg),
+        })?;
+    result.map_err(|msg| Error::CallFailed {
+        msg: format!("{} was rejected by the archive: {}", method, msg),
+    })
+}
+
+#[ic_cdk::query]
+fn get_shard_config() -> ShardConfig {
+    SHARD_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+// Called by the router when it takes this canister on as a shard. Once ids
+// have been handed out the block can grow or shrink but no longer move.
+#[ic_cdk::update]
+fn set_shard_config(config: ShardConfig) -> Result<ShardConfig, Error> {
+    ensure_controller()?;
+    if config.first_id >= config.end_id {
+        return Err(Error::InvalidInput {
+            msg: format!("the id block {}..{} is empty", config.first_id, config.end_id),
+        });
+    }
+    let current = SHARD_CONFIG.with(|config| config.borrow().get().clone());
+    let next_id = ID_COUNTER.with(|counter| *counter.borrow().get());
+    let allocated = next_id > current.first_id;
+    if allocated && config.first_id != current.first_id {
+        return Err(Error::InvalidInput {
+            msg: format!("ids from {} were already handed out; the first id cannot change", current.first_id),
+        });
+    }
+    if next_id > config.end_id {
+        return Err(Error::InvalidInput {
+            msg: format!("ids up to {} were already handed out", next_id),
+        });
+    }
+    if !allocated {
+        ID_COUNTER
+            .with(|counter| counter.borrow_mut().set(config.first_id))
+            .expect("cannot set the id counter");
+    }
+    SHARD_CONFIG
+        .with(|cell| cell.borrow_mut().set(config.clone()))
+        .expect("cannot persist the shard config");
+    Ok(config)
+}
+
+fn ensure_router() -> Result<(), Error> {
+    let router = SHARD_CONFIG.with(|config| config.borrow().get().router);
+    if router.is_some_and(|router| router == system::caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "only the router canister may make routed calls".to_string(),
+        })
+    }
+}
+
+// Runs `method` with the candid-encoded `arg` as if `caller` had called it
+// directly, so permissions, idempotency keys, watchlists and history all see
+// the end user rather than the router. Only the router in SHARD_CONFIG may
+// name a caller.
+#[ic_cdk::update]
+fn routed_update(caller: Principal, method: String, arg: Vec<u8>) -> Result<Vec<u8>, Error> {
+    ensure_router()?;
+    with_routed_caller(caller, || match method.as_str() {
+        "add_smart_storage_item" => {
+            let (payload, key) = routed_args(&arg)?;
+            routed_reply(add_smart_storage_item(payload, key))
+        }
+        "update_smart_storage_item" => {
+            let (id, expected_version, payload, key) = routed_args(&arg)?;
+            routed_reply(update_smart_storage_item(id, expected_version, payload, key))
+        }
+        "delete_smart_storage_item" => {
+            let (id, expected_version, key) = routed_args(&arg)?;
+            routed_reply(delete_smart_storage_item(id, expected_version, key))
+        }
+        "watch_item" => {
+            let (item_id, key) = routed_args(&arg)?;
+            routed_reply(watch_item(item_id, key))
+        }
+        "unwatch_item" => {
+            let (item_id, key) = routed_args(&arg)?;
+            routed_reply(unwatch_item(item_id, key))
+        }
+        "mark_notification_as_read" => {
+            let (id, key) = routed_args(&arg)?;
+            routed_reply(mark_notification_as_read(id, key))
+        }
+        "mark_all_notifications_as_read" => {
+            let (key,) = routed_args(&arg)?;
+            routed_reply(mark_all_notifications_as_read(key))
+        }
+        _ => Err(Error::InvalidInput {
+            msg: format!("{} cannot be routed as an update", method),
+        }),
+    })
+}
+
+#[ic_cdk::query]
+fn routed_query(caller: Principal, method: String, arg: Vec<u8>) -> Result<Vec<u8>, Error> {
+    ensure_router()?;
+    with_routed_caller(caller, || match method.as_str() {
+        "get_smart_storage_item" => {
+            let (id, as_of) = routed_args(&arg)?;
+            routed_reply(get_smart_storage_item(id, as_of))
+        }
+        "get_paginated_smart_storage_items" => {
+            let (limit, offset, as_of) = routed_args(&arg)?;
+            routed_reply(get_paginated_smart_storage_items(limit, offset, as_of))
+        }
+        "search_smart_storage_items" => {
+            let (query, as_of) = routed_args(&arg)?;
+            routed_reply(search_smart_storage_items(query, as_of))
+        }
+        _ => Err(Error::InvalidInput {
+            msg: format!("{} cannot be routed as a query", method),
+        }),
+    })
+}
+
+fn with_routed_caller<T>(caller: Principal, run: impl FnOnce() -> T) -> T {
+    ROUTED_CALLER.with(|routed| *routed.borrow_mut() = Some(caller));
+    let result = run();
+    ROUTED_CALLER.with(|routed| *routed.borrow_mut() = None);
+    result
+}
+
+fn routed_args<'a, T: candid::utils::ArgumentDecoder<'a>>(arg: &'a [u8]) -> Result<T, Error> {
+    candid::decode_args(arg).map_err(|err| Error::DecodeFailed {
+        msg: format!("cannot decode the routed arguments: {}", err),
+    })
+}
+
+fn routed_reply<T: candid::CandidType>(reply: T) -> Result<Vec<u8>, Error> {
+    Ok(candid::encode_one(reply).expect("cannot encode the routed reply"))
+}
+
+// Most operations one undo_last_operations call takes back.
+const MAX_UNDO_OPERATIONS: u32 = 20;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct UndoneOperation {
+    // The undone audit entry and the entry recording the undo.
+    seq: u64,
+    undo_seq: u64,
+    item_id: u64,
+    operation: AuditOperation,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct UndoRefusal {
+    seq: u64,
+    error: Error,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct UndoReport {
+    undone: Vec<UndoneOperation>,
+    // The operation undo stopped at, if it could not be taken back.
+    refused: Option<UndoRefusal>,
+}
+
+// The caller's operations that undo_last_operations would take back, newest first.
+#[ic_cdk::query]
+fn get_undoable_operations(limit: usize) -> Vec<AuditEntry> {
+    redacted(undoable_seqs(caller()).take(limit).filter_map(audit_entry).collect())
+}
+
+// Takes back the caller's last `count` operations, newest first, from the
+// before-images in the audit log. Every undo is recorded as a new operation.
+// Stops at the first operation that cannot be undone, e.g. because someone
+// else changed the item since.
+#[ic_cdk::update]
+fn undo_last_operations(count: u32, idempotency_key: Option<String>) -> Result<UndoReport, Error> {
+    idempotent(idempotency_key, "undo_last_operations", || {
+        let principal = caller();
+        if principal == Principal::anonymous() {
+            return Err(Error::Unauthorized {
+                msg: "anonymous callers cannot undo operations".to_string(),
+            });
+        }
+        if count == 0 || count > MAX_UNDO_OPERATIONS {
+            return Err(Error::InvalidInput {
+                msg: format!("count must be between 1 and {}", MAX_UNDO_OPERATIONS),
+            });
+        }
+        let mut report = UndoReport::default();
+        for _ in 0..count {
+            let Some(seq) = undoable_seqs(principal).next() else {
+                break;
+            };
+            match undo_operation(seq) {
+                Ok(undone) => report.undone.push(undone),
+                Err(error) => {
+                    report.refused = Some(UndoRefusal { seq, error });
+                    break;
+                }
+            }
+        }
+        Ok(report)
+    })
+}
+
+// The principal's audit entries that are not linked to an undo, newest first.
+fn undoable_seqs(principal: Principal) -> impl Iterator<Item = u64> {
+    let principal = StoredPrincipal(principal);
+    let mut upper = u64::MAX;
+    std::iter::from_fn(move || loop {
+        let ((owner, seq), _) = AUDIT_LOG_BY_CALLER
+            .with(|index| index.borrow().iter_upper_bound(&(principal.clone(), upper)).next())?;
+        if owner != principal {
+            return None;
+        }
+        upper = seq;
+        if !UNDO_LINKS.with(|links| links.borrow().contains_key(&seq)) {
+            return Some(seq);
+        }
+    })
+}
+
+fn undo_operation(seq: u64) -> Result<UndoneOperation, Error> {
+    let entry = audit_entry(seq).ok_or_else(|| archived_error(format!("audit entry seq={} is archived", seq)))?;
+    let id = entry.item_id;
+    // Later changes are fine only when they were undone themselves, which
+    // leaves the item as this operation left it.
+    let unlinked = |later: &u64| !UNDO_LINKS.with(|links| links.borrow().contains_key(later));
+    if let Some(later) = item_audit_seqs(id, seq + 1).find(unlinked) {
+        let changed_by = audit_entry(later).map(|later| later.caller);
+        return Err(Error::Conflict {
+            msg: format!(
+                "item id={} was changed by {} after this operation",
+                id,
+                changed_by.map_or("someone else".to_string(), |principal| principal.to_string())
+            ),
+            current_version: _get_smart_storage_item(&id)?.map_or(0, |item| item.version),
+        });
+    }
+    let live_item = || _get_smart_storage_item(&id)?.ok_or_else(|| item_not_found(id));
+    match entry.operation {
+        AuditOperation::Create | AuditOperation::Restore => {
+            let item = live_item()?;
+            move_item_to_trash(&item);
+            record_audit(AuditOperation::Delete, id, Some(&item), None);
+            notify_watchers(id, ChangeKind::Deleted);
+        }
+        AuditOperation::Delete => {
+            let (trash_entry, item) = get_trashed_item(id)?;
+            restore_trashed_item(&trash_entry, item);
+        }
+        AuditOperation::Update | AuditOperation::MarkAvailable | AuditOperation::MarkUnavailable => {
+            let item = live_item()?;
+            let current = diff_item_fields(None, Some(&item));
+            let mut payload = SmartStorageItemPayload {
+                name: item.name.clone(),
+                description: item.description.clone(),
+                location: item.location.clone(),
+                is_available: item.is_available,
+            };
+            for change in &entry.changes {
+                let now = current.iter().find(|field| field.field == change.field);
+                if now.map(|field| &field.after) != Some(&change.after) {
+                    return Err(Error::Conflict {
+                        msg: format!("{} of item id={} no longer matches this operation", change.field, id),
+                        current_version: item.version,
+                    });
+                }
+                let Some(value) = change.before.clone() else {
+                    continue;
+                };
+                match change.field.as_str() {
+                    "name" => payload.name = value,
+                    "description" => payload.description = value,
+                    "location" => payload.location = value,
+                    "is_available" => payload.is_available = value == "true",
+                    _ => {}
+                }
+            }
+            validate_item_payload(&payload)?;
+            let operation = match entry.operation {
+                AuditOperation::Update => AuditOperation::Update,
+                _ if payload.is_available => AuditOperation::MarkAvailable,
+                _ => AuditOperation::MarkUnavailable,
+            };
+            apply_item_payload(item, payload, operation);
+        }
+        AuditOperation::Purge | AuditOperation::Retire => {
+            return Err(Error::InvalidInput {
+                msg: format!("{} of item id={} cannot be undone", entry.operation.label(), id),
+            });
+        }
+    }
+    let undo_seq = audit_log_len() - 1;
+    UNDO_LINKS.with(|links| {
+        let mut links = links.borrow_mut();
+        links.insert(seq, undo_seq);
+        links.insert(undo_seq, seq);
+    });
+    Ok(UndoneOperation {
+        seq,
+        undo_seq,
+        item_id: id,
+        operation: entry.operation,
+    })
+}
+
+// One historical version of an item and the audit entry that produced it.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ItemVersion {
+    seq: u64,
+    timestamp: u64,
+    caller: Principal,
+    change_type: String,
+    item: SmartStorageItem,
+}
+
+// Versions of an item recorded in the audit log, newest first. Entries that
+// removed the item (deletion, purge, retirement) are not versions and are skipped.
+#[ic_cdk::query]
+fn get_item_versions(id: u64, limit: usize, offset: usize) -> Result<Vec<ItemVersion>, Error> {
+    let hidden = hidden_fields();
+    item_versions(id)
+        .skip(offset)
+        .take(limit)
+        .map(|version| {
+            version.map(|mut version| {
+                redact_item(&mut version.item, &hidden);
+                version
+            })
+        })
+        .collect()
+}
+
+fn item_versions(id: u64) -> impl Iterator<Item = Result<ItemVersion, Error>> {
+    let first_seq = item_audit_seqs(id, 0).next().unwrap_or_default();
+    item_audit_seqs_rev(id)
+        .filter_map(move |seq| {
+            let entry = audit_entry(seq)?;
+            match item_state_through(id, first_seq, Some(seq)) {
+                Ok(item) => item.map(|item| {
+                    Ok(ItemVersion {
+                        seq,
+                        timestamp: entry.timestamp,
+                        caller: entry.caller,
+                        change_type: entry.operation.label().to_string(),
+                        item,
+                    })
+                }),
+                Err(err) => Some(Err(err)),
+            }
+        })
+}
+
+// Writes the fields of an earlier version over the item as a new version,
+// recorded as an update. History is left as it is. Locations only exist while
+// some live item is stored there, so a revert to a location that has since been
+// emptied is refused. Items have no category field, so there is no category to
+// check.
+#[ic_cdk::update]
+fn revert_smart_storage_item(
+    id: u64,
+    expected_version: u64,
+    target_version: u64,
+    idempotency_key: Option<String>,
+) -> Result<SmartStorageItem, Error> {
+    redacted(idempotent(idempotency_key, "revert_smart_storage_item", || {
+        let item = get_item_at_version(id, expected_version)?;
+        if target_version >= item.version {
+            return Err(Error::InvalidInput {
+                msg: format!("item id={} is at version {}; only earlier versions can be restored", id, item.version),
+            });
+        }
+        let mut target = None;
+        for version in item_versions(id) {
+            let version = version?;
+            if version.item.version <= target_version {
+                target = Some(version).filter(|version| version.item.version == target_version);
+                break;
+            }
+        }
+        let Some(target) = target else {
+            return Err(Error::NotFound {
+                msg: format!("version {} of item id={} is not in the audit log", target_version, id),
+            });
+        };
+        let payload = SmartStorageItemPayload {
+            name: target.item.name,
+            description: target.item.description,
+            location: target.item.location,
+            is_available: target.item.is_available,
+        };
+        validate_item_payload(&payload)?;
+        if payload.location != item.location && !location_in_use(&payload.location) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "location \"{}\" of version {} no longer holds any item",
+                    payload.location, target_version
+                ),
+            });
+        }
+        Ok(apply_item_payload(item, payload, AuditOperation::Update))
+    }))
+}
+
+// Client-chosen key naming one logical call, so a retry can be recognised.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct IdempotencyKey(String);
+
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 64;
+
+// Keys are scoped to the caller, so clients cannot collide with each other.
+type IdempotencyRecordKey = (StoredPrincipal, IdempotencyKey);
+
+impl Storable for IdempotencyKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        IdempotencyKey(String::from_utf8(bytes.into_owned()).expect("idempotency keys are UTF-8"))
+    }
+}
+
+impl BoundedStorable for IdempotencyKey {
+    const MAX_SIZE: u32 = MAX_IDEMPOTENCY_KEY_LENGTH as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IdempotencyRecord {
+    reply_id: u64,
+    recorded_at: u64,
+    // SHA-256 over the method name and the raw call arguments.
+    fingerprint: Vec<u8>,
+}
+
+impl Storable for IdempotencyRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IdempotencyRecord {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+const DEFAULT_IDEMPOTENCY_WINDOW_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Runs `run` once per caller and idempotency key. A repeat within the window
+// gets the stored reply back, errors included, without running again. A bad
+// key, or a key reused for a different call, is rejected and nothing is stored.
+fn idempotent<T>(key: Option<String>, method: &str, run: impl FnOnce() -> Result<T, Error>) -> Result<T, Error>
+where
+    T: candid::CandidType + serde::de::DeserializeOwned,
+{
+    let Some(key) = key else {
+        return run();
+    };
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!("idempotency keys must be 1 to {} bytes long", MAX_IDEMPOTENCY_KEY_LENGTH),
+        });
+    }
+    let record_key = (StoredPrincipal(caller()), IdempotencyKey(key));
+    let fingerprint = Sha256::digest([method.as_bytes(), &system::arg_data_raw()].concat()).to_vec();
+    let window = get_idempotency_window();
+    let stored = IDEMPOTENCY_KEYS.with(|keys| keys.borrow().get(&record_key));
+    match stored {
+        Some(record) if time().saturating_sub(record.recorded_at) < window => {
+            if record.fingerprint != fingerprint {
+                return Err(Error::InvalidInput {
+                    msg: format!("idempotency key {:?} was used for a different call", record_key.1 .0),
+                });
+            }
+            let reply = read_idempotent_reply(record.reply_id);
+            return Decode!(&reply, Result<T, Error>).unwrap_or_else(|err| {
+                Err(Error::DecodeFailed {
+                    msg: format!("cannot decode the stored reply: {}", err),
+                })
+            });
+        }
+        Some(record) => remove_idempotency_record(&record_key, &record),
+        None => {}
+    }
+    let result = run();
+    let reply_id = IDEMPOTENCY_REPLY_ID_COUNTER.with(|counter| *counter.borrow().get());
+    IDEMPOTENCY_REPLY_ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(reply_id + 1))
+        .expect("cannot increment idempotency reply counter");
+    let reply = Encode!(&result).expect("cannot encode the reply");
+    IDEMPOTENCY_REPLIES.with(|replies| {
+        let mut replies = replies.borrow_mut();
+        for (index, chunk) in reply.chunks(ITEM_BLOB_CHUNK_SIZE).enumerate() {
+            replies.insert((reply_id, index as u32), ItemChunk(chunk.to_vec()));
+        }
+    });
+    let record = IdempotencyRecord {
+        reply_id,
+        recorded_at: time(),
+        fingerprint,
+    };
+    IDEMPOTENCY_BY_TIME.with(|index| index.borrow_mut().insert((record.recorded_at, reply_id), record_key.clone()));
+    IDEMPOTENCY_KEYS.with(|keys| keys.borrow_mut().insert(record_key, record));
+    result
+}
+
+fn read_idempotent_reply(reply_id: u64) -> Vec<u8> {
+    IDEMPOTENCY_REPLIES.with(|replies| {
+        replies
+            .borrow()
+            .range((reply_id, 0)..=(reply_id, u32::MAX))
+            .flat_map(|(_, chunk)| chunk.0)
+            .collect()
+    })
+}
+
+fn remove_idempotency_record(key: &IdempotencyRecordKey, record: &IdempotencyRecord) {
+    IDEMPOTENCY_KEYS.with(|keys| keys.borrow_mut().remove(key));
+    IDEMPOTENCY_BY_TIME.with(|index| index.borrow_mut().remove(&(record.recorded_at, record.reply_id)));
+    IDEMPOTENCY_REPLIES.with(|replies| {
+        let chunks: Vec<_> = replies
+            .borrow()
+            .range((record.reply_id, 0)..=(record.reply_id, u32::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        let mut replies = replies.borrow_mut();
+        for chunk in chunks {
+            replies.remove(&chunk);
+        }
+    });
+}
+
+// The age limit of the idempotency record retention policy, with u64::MAX for
+// no limit.
+#[ic_cdk::query]
+fn get_idempotency_window() -> u64 {
+    RETENTION_POLICIES.with(|policies| policies.borrow().get().idempotency_records.max_age_ns.unwrap_or(u64::MAX))
+}
+
+#[ic_cdk::update]
+fn set_idempotency_window(window_ns: u64) -> Result<u64, Error> {
+    ensure_controller()?;
+    update_retention_policies(|policies| policies.idempotency_records.max_age_ns = age_limit(window_ns));
+    Ok(window_ns)
+}
+
+const RETENTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+// Instructions one retention run may use; whatever is left over waits for the
+// next tick.
+const RETENTION_BATCH_INSTRUCTIONS: u64 = 2_000_000_000;
+
+// Runs kept in RETENTION_RUNS; older ones are dropped.
+const MAX_RETENTION_RUNS: u64 = 100;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum RetainedData {
+    History,
+    Notifications,
+    Trash,
+    IdempotencyRecords,
+}
+
+// Records older than max_age_ns, and the oldest records past max_count, are
+// purged. None means no limit.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RetentionPolicy {
+    max_age_ns: Option<u64>,
+    max_count: Option<u64>,
+}
+
+// Every retention limit is kept here. The trash retention and idempotency
+// window settings read and write the age limits of their policies.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RetentionPolicies {
+    history: RetentionPolicy,
+    notifications: RetentionPolicy,
+    trash: RetentionPolicy,
+    idempotency_records: RetentionPolicy,
+}
+
+impl Default for RetentionPolicies {
+    fn default() -> Self {
+        let max_age = |age_ns| RetentionPolicy {
+            max_age_ns: Some(age_ns),
+            max_count: None,
+        };
+        RetentionPolicies {
+            history: max_age(DEFAULT_HISTORY_RETENTION_NS),
+            notifications: RetentionPolicy::default(),
+            trash: max_age(DEFAULT_TRASH_RETENTION_NS),
+            idempotency_records: max_age(DEFAULT_IDEMPOTENCY_WINDOW_NS),
+        }
+    }
+}
+
+impl Storable for RetentionPolicies {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// What one retention run removed. History is removed by the archive pass,
+// which records its own runs.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RetentionRun {
+    timestamp: u64,
+    history: u64,
+    notifications: u64,
+    trash: u64,
+    idempotency_records: u64,
+}
+
+impl Storable for RetentionRun {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RetentionRun {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// An age limit of u64::MAX in the older settings means no limit.
+fn age_limit(age_ns: u64) -> Option<u64> {
+    (age_ns != u64::MAX).then_some(age_ns)
+}
+
+fn update_retention_policies(update: impl FnOnce(&mut RetentionPolicies)) {
+    let mut policies = RETENTION_POLICIES.with(|policies| policies.borrow().get().clone());
+    update(&mut policies);
+    RETENTION_POLICIES
+        .with(|cell| cell.borrow_mut().set(policies))
+        .expect("cannot persist the retention policies");
+}
+
+// The trash, idempotency and history age limits used to live in the trash
+// retention and idempotency window cells and in the archive config. A stored
+// archive config that still carries its history_retention_ns has not been
+// moved over yet; all three limits are moved then, and it is cleared.
+fn move_legacy_retention_limits() {
+    let config = ARCHIVE_CONFIG.with(|config| config.borrow().get().clone());
+    let Some(history_retention_ns) = config.history_retention_ns else {
+        return;
+    };
+    let trash_retention_ns = LEGACY_TRASH_RETENTION_NS.with(|retention| *retention.borrow().get());
+    let idempotency_window_ns = LEGACY_IDEMPOTENCY_WINDOW_NS.with(|window| *window.borrow().get());
+    update_retention_policies(|policies| {
+        policies.history.max_age_ns = age_limit(history_retention_ns);
+        policies.trash.max_age_ns = age_limit(trash_retention_ns);
+        policies.idempotency_records.max_age_ns = age_limit(idempotency_window_ns);
+    });
+    let config = StoredArchiveConfig {
+        history_retention_ns: None,
+        ..config
+    };
+    ARCHIVE_CONFIG
+        .with(|cell| cell.borrow_mut().set(config))
+        .expect("cannot persist the archive config");
+}
+
+#[ic_cdk::query]
+fn get_retention_policies() -> RetentionPolicies {
+    RETENTION_POLICIES.with(|policies| policies.borrow().get().clone())
+}
+
+// History past its policy is moved to the archive canister rather than
+// deleted, so a history policy can only be set while one is configured.
+// Without one, history is kept in full.
+#[ic_cdk::update]
+fn set_retention_policy(data: RetainedData, policy: RetentionPolicy) -> Result<RetentionPolicy, Error> {
+    ensure_controller()?;
+    if matches!(data, RetainedData::History) && ARCHIVE_CONFIG.with(|config| config.borrow().get().archive).is_none() {
+        return Err(Error::InvalidInput {
+            msg: "history is only bounded by moving it to an archive canister; set one first".to_string(),
+        });
+    }
+    update_retention_policies(|policies| {
+        let stored = match data {
+            RetainedData::History => &mut policies.history,
+            RetainedData::Notifications => &mut policies.notifications,
+            RetainedData::Trash => &mut policies.trash,
+            RetainedData::IdempotencyRecords => &mut policies.idempotency_records,
+        };
+        *stored = policy.clone();
+    });
+    Ok(policy)
+}
+
+// Past retention runs that removed anything, newest first.
+#[ic_cdk::query]
+fn get_retention_runs(limit: usize, offset: usize) -> Vec<RetentionRun> {
+    RETENTION_RUNS.with(|runs| {
+        let runs = runs.borrow();
+        let len = runs.len() as usize;
+        let end = len.saturating_sub(offset);
+        let start = end.saturating_sub(limit);
+        let mut page: Vec<RetentionRun> = runs.iter().skip(start).take(end - start).map(|(_, run)| run).collect();
+        page.reverse();
+        page
+    })
+}
+
+// Runs the retention job now instead of waiting for the timer.
+#[ic_cdk::update]
+fn run_retention() -> Result<RetentionRun, Error> {
+    ensure_controller()?;
+    Ok(enforce_retention_policies())
+}
+
+fn start_retention_timer() {
+    ic_cdk_timers::set_timer_interval(RETENTION_INTERVAL, || {
+        enforce_retention_policies();
+    });
+}
+
+// Purges notifications, trash and idempotency records past their policies,
+// oldest first. A run stops at RETENTION_BATCH_INSTRUCTIONS; the rest is
+// picked up on the next tick.
+fn enforce_retention_policies() -> RetentionRun {
+    let policies = get_retention_policies();
+    let now = time();
+    let cutoff = |policy: &RetentionPolicy| policy.max_age_ns.map(|age| now.saturating_sub(age));
+    let mut run = RetentionRun {
+        timestamp: now,
+        ..RetentionRun::default()
+    };
+    let expired = |policy: &RetentionPolicy, recorded_at: u64, count: u64| {
+        cutoff(policy).is_some_and(|cutoff| recorded_at <= cutoff) || policy.max_count.is_some_and(|max| count > max)
+    };
+    while system::instruction_counter() < RETENTION_BATCH_INSTRUCTIONS {
+        let Some((id, owner)) = NOTIFICATIONS_BY_ID.with(|index| index.borrow().first_key_value()) else {
+            break;
+        };
+        let count = NOTIFICATIONS_BY_ID.with(|index| index.borrow().len());
+        let key = (owner, id);
+        match NOTIFICATIONS.with(|notifications| notifications.borrow().get(&key)) {
+            Some(notification) if expired(&policies.notifications, notification.timestamp, count) => {
+                NOTIFICATIONS.with(|notifications| notifications.borrow_mut().remove(&key));
+                run.notifications += 1;
+            }
+            Some(_) => break,
+            None => {}
+        }
+        NOTIFICATIONS_BY_ID.with(|index| index.borrow_mut().remove(&id));
+    }
+    while system::instruction_counter() < RETENTION_BATCH_INSTRUCTIONS {
+        let Some(((deleted_at, id), _)) = TRASH_BY_TIME.with(|trash| trash.borrow().first_key_value()) else {
+            break;
+        };
+        let count = TRASH.with(|trash| trash.borrow().len());
+        if !expired(&policies.trash, deleted_at, count) {
+            break;
+        }
+        match TRASH.with(|trash| trash.borrow().get(&id)) {
+            Some(entry) => {
+                purge_trashed_item(&entry);
+                run.trash += 1;
+            }
+            None => {
+                TRASH_BY_TIME.with(|trash| trash.borrow_mut().remove(&(deleted_at, id)));
+            }
+        }
+    }
+    while system::instruction_counter() < RETENTION_BATCH_INSTRUCTIONS {
+        let Some(((recorded_at, reply_id), key)) = IDEMPOTENCY_BY_TIME.with(|index| index.borrow().first_key_value()) else {
+            break;
+        };
+        let count = IDEMPOTENCY_KEYS.with(|keys| keys.borrow().len());
+        if !expired(&policies.idempotency_records, recorded_at, count) {
+            break;
+        }
+        match IDEMPOTENCY_KEYS.with(|keys| keys.borrow().get(&key)) {
+            Some(record) if record.reply_id == reply_id => {
+                remove_idempotency_record(&key, &record);
+                run.idempotency_records += 1;
+            }
+            _ => {
+                IDEMPOTENCY_BY_TIME.with(|index| index.borrow_mut().remove(&(recorded_at, reply_id)));
+            }
+        }
+    }
+    record_retention_run(run.clone());
+    run
+}
+
+fn record_retention_run(run: RetentionRun) {
+    if run.history + run.notifications + run.trash + run.idempotency_records == 0 {
+        return;
+    }
+    RETENTION_RUNS.with(|runs| {
+        let mut runs = runs.borrow_mut();
+        let next = runs.last_key_value().map_or(0, |(id, _)| id + 1);
+        runs.insert(next, run);
+        while runs.len() > MAX_RETENTION_RUNS {
+            let (oldest, _) = runs.first_key_value().expect("runs are not empty");
+            runs.remove(&oldest);
+        }
+    });
+}
+
+// Longest role name accepted, in bytes.
+const MAX_ROLE_NAME_LENGTH: usize = 32;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum ItemField {
+    Name,
+    Description,
+    Location,
+}
+
+impl ItemField {
+    // The field name used in audit FieldChange records.
+    fn label(self) -> &'static str {
+        match self {
+            ItemField::Name => "name",
+            ItemField::Description => "description",
+            ItemField::Location => "location",
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FieldAccessGrant {
+    role: String,
+    fields: Vec<ItemField>,
+}
+
+// Restricted fields are blanked for every caller that is neither a controller
+// nor holds a role granted read access to them.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FieldVisibilityRules {
+    restricted: Vec<ItemField>,
+    grants: Vec<FieldAccessGrant>,
+}
+
+impl Storable for FieldVisibilityRules {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Role name usable inside composite stable map keys.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+struct RoleName(String);
+
+impl Storable for RoleName {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        RoleName(String::from_utf8(bytes.into_owned()).expect("role names are UTF-8"))
+    }
+}
+
+impl BoundedStorable for RoleName {
+    const MAX_SIZE: u32 = MAX_ROLE_NAME_LENGTH as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+fn role_name(role: String) -> Result<RoleName, Error> {
+    if role.is_empty() || role.len() > MAX_ROLE_NAME_LENGTH {
+        return Err(Error::InvalidInput {
+            msg: format!("role names must be 1 to {} bytes long", MAX_ROLE_NAME_LENGTH),
+        });
+    }
+    Ok(RoleName(role))
+}
+
+#[ic_cdk::query]
+fn get_field_visibility_rules() -> FieldVisibilityRules {
+    FIELD_VISIBILITY.with(|rules| rules.borrow().get().clone())
+}
+
+#[ic_cdk::update]
+fn set_field_restricted(field: ItemField, restricted: bool) -> Result<FieldVisibilityRules, Error> {
+    ensure_controller()?;
+    let mut rules = get_field_visibility_rules();
+    rules.restricted.retain(|restricted| *restricted != field);
+    if restricted {
+        rules.restricted.push(field);
+    }
+    save_field_visibility_rules(rules)
+}
+
+// Replaces the fields a role may read; an empty list removes the grant.
+#[ic_cdk::update]
+fn set_role_field_access(role: String, fields: Vec<ItemField>) -> Result<FieldVisibilityRules, Error> {
+    ensure_controller()?;
+    let role = role_name(role)?.0;
+    let mut rules = get_field_visibility_rules();
+    rules.grants.retain(|grant| grant.role != role);
+    if !fields.is_empty() {
+        rules.grants.push(FieldAccessGrant { role, fields });
+    }
+    save_field_visibility_rules(rules)
+}
+
+fn save_field_visibility_rules(rules: FieldVisibilityRules) -> Result<FieldVisibilityRules, Error> {
+    FIELD_VISIBILITY
+        .with(|cell| cell.borrow_mut().set(rules.clone()))
+        .expect("cannot persist the field visibility rules");
+    Ok(rules)
+}
+
+#[ic_cdk::update]
+fn assign_role(principal: Principal, role: String) -> Result<(), Error> {
+    ensure_controller()?;
+    let role = role_name(role)?;
+    ROLE_MEMBERS.with(|members| members.borrow_mut().insert((StoredPrincipal(principal), role), ()));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn unassign_role(principal: Principal, role: String) -> Result<(), Error> {
+    ensure_controller()?;
+    let role = role_name(role)?;
+    match ROLE_MEMBERS.with(|members| members.borrow_mut().remove(&(StoredPrincipal(principal), role.clone()))) {
+        Some(()) => Ok(()),
+        None => Err(Error::NotFound {
+            msg: format!("{} does not hold the role {:?}", principal, role.0),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_principal_roles(principal: Principal) -> Vec<String> {
+    principal_roles(principal).into_iter().map(|role| role.0).collect()
+}
+
+fn principal_roles(principal: Principal) -> Vec<RoleName> {
+    let principal = StoredPrincipal(principal);
+    ROLE_MEMBERS.with(|members| {
+        members
+            .borrow()
+            .range((principal.clone(), RoleName::default())..)
+            .take_while(|((member, _), _)| *member == principal)
+            .map(|((_, role), _)| role)
+            .collect()
+    })
+}
+
+// Restricted fields the caller may not read. The router controls the shards
+// it creates but reads on behalf of its callers, so it is not exempt.
+fn hidden_fields() -> Vec<ItemField> {
+    let rules = get_field_visibility_rules();
+    let router = SHARD_CONFIG.with(|config| config.borrow().get().router);
+    if rules.restricted.is_empty() || (system::is_controller(&caller()) && router != Some(caller())) {
+        return Vec::new();
+    }
+    let roles = principal_roles(caller());
+    rules
+        .restricted
+        .into_iter()
+        .filter(|field| {
+            !rules
+                .grants
+                .iter()
+                .any(|grant| grant.fields.contains(field) && roles.iter().any(|role| role.0 == grant.role))
+        })
+        .collect()
+}
+
+fn redact_item(item: &mut SmartStorageItem, hidden: &[ItemField]) {
+    for field in hidden {
+        match field {
+            ItemField::Name => item.name.clear(),
+            ItemField::Description => item.description.clear(),
+            ItemField::Location => item.location.clear(),
+        }
+    }
+}
+
+fn redact_items(mut items: Vec<SmartStorageItem>) -> Vec<SmartStorageItem> {
+    let hidden = hidden_fields();
+    if !hidden.is_empty() {
+        for item in &mut items {
+            redact_item(item, &hidden);
+        }
+    }
+    items
+}
+
+// Drops the values of hidden fields from an entry's changes. The entry's hash
+// no longer matches afterwards, so only authorised callers can verify it.
+fn redact_audit_entry(mut entry: AuditEntry, hidden: &[ItemField]) -> AuditEntry {
+    entry.redact(hidden);
+    entry
+}
+
+// Replies that can carry restricted item fields.
+trait Redact {
+    fn redact(&mut self, hidden: &[ItemField]);
+}
+
+impl Redact for SmartStorageItem {
+    fn redact(&mut self, hidden: &[ItemField]) {
+        redact_item(self, hidden);
+    }
+}
+
+impl Redact for BulkReport {
+    fn redact(&mut self, hidden: &[ItemField]) {
+        for entry in &mut self.entries {
+            if let BulkEntryStatus::Applied(item) = &mut entry.status {
+                redact_item(item, hidden);
+            }
+        }
+    }
+}
+
+impl Redact for Notification {
+    fn redact(&mut self, hidden: &[ItemField]) {
+        if let ChangeKind::Moved { from, to } = &mut self.kind {
+            if hidden.contains(&ItemField::Location) {
+                from.clear();
+                to.clear();
+            }
+        }
+    }
+}
+
+impl Redact for AuditEntry {
+    fn redact(&mut self, hidden: &[ItemField]) {
+        for change in &mut self.changes {
+            if hidden.iter().any(|field| field.label() == change.field) {
+                change.before = None;
+                change.after = None;
+            }
+        }
+    }
+}
+
+impl<T: Redact> Redact for Vec<T> {
+    fn redact(&mut self, hidden: &[ItemField]) {
+        for value in self {
+            value.redact(hidden);
+        }
+    }
+}
+
+impl<T: Redact> Redact for Result<T, Error> {
+    fn redact(&mut self, hidden: &[ItemField]) {
+        if let Ok(value) = self {
+            value.redact(hidden);
+        }
+    }
+}
+
+// `reply` with the fields the caller may not read cleared. Mutating endpoints
+// wrap their idempotent() call in it, so stored replies are redacted for
+// whoever replays them.
+fn redacted<T: Redact>(mut reply: T) -> T {
+    let hidden = hidden_fields();
+    if !hidden.is_empty() {
+        reply.redact(&hidden);
+    }
+    reply
+}
+
+// Location usable inside composite stable map keys.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+struct LocationKey(String);
+
+impl Storable for LocationKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        LocationKey(String::from_utf8(bytes.into_owned()).expect("locations are UTF-8"))
+    }
+}
+
+impl BoundedStorable for LocationKey {
+    const MAX_SIZE: u32 = MAX_LOCATION_LENGTH_CEILING;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Moves a live item between the secondary indexes. `before` is the item as
+// it was indexed (None for a new or returning item) and `after` the item as
+// it is now (None once it left the live store).
+fn update_item_indexes(before: Option<&SmartStorageItem>, after: Option<&SmartStorageItem>) {
+    index_item_location(before, after);
+    index_item_availability(before, after);
+}
+
+fn index_item_location(before: Option<&SmartStorageItem>, after: Option<&SmartStorageItem>) {
+    ITEMS_BY_LOCATION.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(before) = before {
+            index.remove(&(LocationKey(before.location.clone()), before.id));
+        }
+        if let Some(after) = after {
+            index.insert((LocationKey(after.location.clone()), after.id), ());
+        }
+    });
+}
+
+fn index_item_availability(before: Option<&SmartStorageItem>, after: Option<&SmartStorageItem>) {
+    AVAILABLE_ITEMS.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(before) = before {
+            index.remove(&before.id);
+        }
+        if let Some(after) = after.filter(|item| item.is_available) {
+            index.insert(after.id, ());
+        }
+    });
+}
+
+fn location_in_use(location: &str) -> bool {
+    let location = LocationKey(location.to_string());
+    ITEMS_BY_LOCATION.with(|index| index.borrow().range((location.clone(), 0)..=(location, u64::MAX)).next().is_some())
+}
+
+// Live items whose location starts with `prefix`, ordered by location and id.
+// Callers that may not read locations cannot filter by them.
+#[ic_cdk::query]
+fn get_items_by_location(prefix: String, limit: usize, offset: usize) -> Result<Vec<SmartStorageItem>, Error> {
+    let hidden = hidden_fields();
+    if hidden.contains(&ItemField::Location) {
+        return Err(Error::Unauthorized {
+            msg: "the location field is restricted".to_string(),
+        });
+    }
+    let ids: Vec<u64> = ITEMS_BY_LOCATION.with(|index| {
+        index
+            .borrow()
+            .range((LocationKey(prefix.clone()), 0)..)
+            .take_while(|((location, _), _)| location.0.starts_with(&prefix))
+            .skip(offset)
+            .take(limit)
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    let mut items = Vec::with_capacity(ids.len());
+    for id in ids {
+        if let Some(mut item) = _get_smart_storage_item(&id)? {
+            redact_item(&mut item, &hidden);
+            items.push(item);
+        }
+    }
+    Ok(items)
+}
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item_v0(id: u64) -> SmartStorageItemV0 {
+        SmartStorageItemV0 {
+            id,
+            name: "Drill".to_string(),
+            description: "Cordless".to_string(),
+            location: "Shelf A".to_string(),
+            created_at: 10,
+            updated_at: Some(20),
+            is_available: true,
+        }
+    }
+
+    fn decode_and_upgrade(stored: &StoredItem) -> SmartStorageItem {
+        let mut record = decode_versioned_item(stored).unwrap_or_else(|_| panic!("record does not decode"));
+        loop {
+            match record {
+                VersionedItem::V2(item) => return item,
+                older => record = upgrade_item_record(older),
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_records_decode_as_version_0_and_upgrade_to_version_1_items() {
+        let stored = StoredItem(Encode!(&item_v0(7)).unwrap());
+        assert_eq!(stored_item_version(&stored), Some(0));
+        assert!(matches!(decode_versioned_item(&stored), Ok(VersionedItem::V0(_))));
+        let item = decode_and_upgrade(&stored);
+        assert_eq!(item.id, 7);
+        assert_eq!(item.name, "Drill");
+        assert_eq!(item.location, "Shelf A");
+        assert_eq!(item.updated_at, Some(20));
+        assert!(item.is_available);
+        assert_eq!(item.version, 1);
+    }
+
+    #[test]
+    fn version_1_records_upgrade_with_an_initial_item_version() {
+        let mut bytes = vec![1];
+        bytes.extend(Encode!(&item_v0(3)).unwrap());
+        let stored = StoredItem(bytes);
+        assert!(matches!(decode_versioned_item(&stored), Ok(VersionedItem::V1(_))));
+        assert_eq!(decode_and_upgrade(&stored).version, 1);
+    }
+
+    #[test]
+    fn current_records_round_trip_unchanged() {
+        let item = SmartStorageItem {
+            id: 9,
+            name: "Saw".to_string(),
+            version: 5,
+            ..Default::default()
+        };
+        let stored = encode_item(&item);
+        assert_eq!(stored_item_version(&stored), Some(ITEM_SCHEMA_VERSION));
+        let decoded = decode_and_upgrade(&stored);
+        assert_eq!(decoded.id, 9);
+        assert_eq!(decoded.name, "Saw");
+        assert_eq!(decoded.version, 5);
+    }
+
+    #[test]
+    fn unknown_versions_and_empty_records_are_decode_errors() {
+        assert!(matches!(
+            decode_versioned_item(&StoredItem(vec![ITEM_SCHEMA_VERSION + 1, 0])),
+            Err(Error::DecodeFailed { .. })
+        ));
+        assert!(matches!(decode_versioned_item(&StoredItem(Vec::new())), Err(Error::DecodeFailed { .. })));
+        assert!(matches!(decode_versioned_item(&StoredItem(vec![2, 1, 2, 3])), Err(Error::DecodeFailed { .. })));
+    }
+
+    fn audit_entry_for(
+        operation: AuditOperation,
+        before: Option<&SmartStorageItem>,
+        after: Option<&SmartStorageItem>,
+        timestamp: u64,
+    ) -> AuditEntry {
+        AuditEntry {
+            seq: 0,
+            item_id: 1,
+            operation,
+            caller: Principal::anonymous(),
+            timestamp,
+            changes: diff_item_fields(before, after),
+            prev_hash: Vec::new(),
+            hash: Vec::new(),
+        }
+    }
+
+    fn shelf_item(location: &str, is_available: bool) -> SmartStorageItem {
+        SmartStorageItem {
+            id: 1,
+            name: "Drill".to_string(),
+            description: "Cordless".to_string(),
+            location: location.to_string(),
+            created_at: 100,
+            is_available,
+            version: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn diff_lists_only_changed_fields() {
+        let before = shelf_item("Shelf A", true);
+        let after = shelf_item("Shelf B", true);
+        let changes = diff_item_fields(Some(&before), Some(&after));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].field, "location");
+        assert_eq!(changes[0].before.as_deref(), Some("Shelf A"));
+        assert_eq!(changes[0].after.as_deref(), Some("Shelf B"));
+        assert!(diff_item_fields(Some(&before), Some(&before)).is_empty());
+    }
+
+    #[test]
+    fn diff_against_no_item_lists_every_field() {
+        let item = shelf_item("Shelf A", false);
+        let created = diff_item_fields(None, Some(&item));
+        assert_eq!(created.len(), 4);
+        assert!(created.iter().all(|change| change.before.is_none() && change.after.is_some()));
+        let removed = diff_item_fields(Some(&item), None);
+        assert_eq!(removed.len(), 4);
+        assert!(removed.iter().all(|change| change.before.is_some() && change.after.is_none()));
+    }
+
+    #[test]
+    fn replaying_entries_rebuilds_the_item() {
+        let created = shelf_item("Shelf A", true);
+        let state = apply_audit_entry(None, &audit_entry_for(AuditOperation::Create, None, Some(&created), 100));
+        let state = state.expect("a create yields an item");
+        assert_eq!(state.location, "Shelf A");
+        assert_eq!(state.created_at, 100);
+        assert_eq!(state.version, 1);
+
+        let mut moved = created.clone();
+        moved.location = "Shelf B".to_string();
+        let state = apply_audit_entry(
+            Some(state),
+            &audit_entry_for(AuditOperation::Update, Some(&created), Some(&moved), 200),
+        )
+        .expect("an update keeps the item");
+        assert_eq!(state.location, "Shelf B");
+        assert_eq!(state.updated_at, Some(200));
+        assert_eq!(state.version, 2);
+
+        let mut unavailable = moved.clone();
+        unavailable.is_available = false;
+        let state = apply_audit_entry(
+            Some(state),
+            &audit_entry_for(AuditOperation::MarkUnavailable, Some(&moved), Some(&unavailable), 300),
+        )
+        .expect("marking keeps the item");
+        assert!(!state.is_available);
+        assert_eq!(state.updated_at, Some(200));
+        assert_eq!(state.version, 3);
+
+        let deleted = audit_entry_for(AuditOperation::Delete, Some(&unavailable), None, 400);
+        assert!(apply_audit_entry(Some(state), &deleted).is_none());
+    }
+
+    #[test]
+    fn updates_without_a_prior_state_yield_nothing() {
+        let item = shelf_item("Shelf A", true);
+        let entry = audit_entry_for(AuditOperation::Update, Some(&item), Some(&item), 100);
+        assert!(apply_audit_entry(None, &entry).is_none());
+    }
+
+    #[test]
+    fn snapshot_records_parse_back_in_order() {
+        let mut data = Vec::new();
+        push_snapshot_record(&mut data, ID_COUNTER_MEMORY, &[], &7u64.to_be_bytes());
+        push_snapshot_record(&mut data, ITEMS_MEMORY, &1u64.to_be_bytes(), b"item");
+        push_snapshot_record(&mut data, ROLE_MEMBERS_MEMORY, b"grant", &[]);
+        let records = parse_snapshot_records(&data).unwrap_or_else(|_| panic!("records do not parse"));
+        assert_eq!(
+            records,
+            vec![
+                (ID_COUNTER_MEMORY, &[][..], &7u64.to_be_bytes()[..]),
+                (ITEMS_MEMORY, &1u64.to_be_bytes()[..], &b"item"[..]),
+                (ROLE_MEMBERS_MEMORY, &b"grant"[..], &[][..]),
+            ]
+        );
+        assert!(matches!(parse_snapshot_records(&[]), Ok(records) if records.is_empty()));
+    }
+
+    #[test]
+    fn split_snapshot_field_reads_one_length_prefixed_field() {
+        let mut data = 3u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"abcde");
+        assert!(matches!(split_snapshot_field(&data), Ok((b"abc", b"de"))));
+        assert!(matches!(split_snapshot_field(&0u32.to_be_bytes()), Ok((b"", b""))));
+    }
+
+    #[test]
+    fn truncated_snapshot_records_are_rejected() {
+        assert!(matches!(split_snapshot_field(&[0, 0, 0]), Err(Error::InvalidInput { .. })));
+        assert!(matches!(split_snapshot_field(&[0, 0, 0, 4, 1, 2]), Err(Error::InvalidInput { .. })));
+
+        let mut data = Vec::new();
+        push_snapshot_record(&mut data, ITEMS_MEMORY, &1u64.to_be_bytes(), b"item");
+        data.pop();
+        assert!(matches!(parse_snapshot_records(&data), Err(Error::InvalidInput { .. })));
+        // A section byte with nothing after it.
+        assert!(matches!(parse_snapshot_records(&[ITEMS_MEMORY]), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn unknown_snapshot_sections_are_rejected() {
+        let mut data = Vec::new();
+        push_snapshot_record(&mut data, IDEMPOTENCY_KEYS_MEMORY, b"key", b"reply");
+        assert!(matches!(parse_snapshot_records(&data), Err(Error::InvalidInput { msg }) if msg.contains("section")));
+    }
+
+    fn cached_item(id: u64, name: &str) -> SmartStorageItem {
+        SmartStorageItem {
+            id,
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn cached_ids(cache: &ItemCache) -> Vec<u64> {
+        cache.recency.values().copied().collect()
+    }
+
+    #[test]
+    fn item_cache_evicts_the_least_recently_used_item() {
+        let mut cache = ItemCache::default();
+        for id in 1..=3 {
+            cache.insert(cached_item(id, "Drill"), 3);
+        }
+        assert!(cache.get(1).is_some());
+        cache.insert(cached_item(4, "Saw"), 3);
+        assert_eq!(cached_ids(&cache), vec![3, 1, 4]);
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.entries.len(), 3);
+    }
+
+    #[test]
+    fn item_cache_reinsert_replaces_the_entry_and_refreshes_it() {
+        let mut cache = ItemCache::default();
+        cache.insert(cached_item(1, "Drill"), 2);
+        cache.insert(cached_item(2, "Saw"), 2);
+        cache.insert(cached_item(1, "Hammer"), 2);
+        assert_eq!(cached_ids(&cache), vec![2, 1]);
+        assert_eq!(cache.entries.len(), cache.recency.len());
+        assert_eq!(cache.get(1).map(|item| item.name), Some("Hammer".to_string()));
+    }
+
+    #[test]
+    fn item_cache_trim_shrinks_to_a_lower_capacity() {
+        let mut cache = ItemCache::default();
+        for id in 1..=4 {
+            cache.insert(cached_item(id, "Drill"), 4);
+        }
+        cache.trim(2);
+        assert_eq!(cached_ids(&cache), vec![3, 4]);
+        cache.invalidate(3);
+        assert_eq!(cached_ids(&cache), vec![4]);
+        assert_eq!(cache.entries.len(), 1);
+        cache.clear();
+        assert!(cache.entries.is_empty() && cache.recency.is_empty());
+    }
+
+    #[test]
+    fn item_cache_with_zero_capacity_stores_nothing() {
+        let mut cache = ItemCache::default();
+        cache.insert(cached_item(1, "Drill"), 0);
+        assert!(cache.get(1).is_none());
+        assert!(cache.recency.is_empty());
+    }
+
+    // Endpoint tests run as a caller at a time of their choosing; state is per
+    // test thread, so every test starts from an empty canister.
+    fn user(byte: u8) -> Principal {
+        Principal::from_slice(&[byte])
+    }
+
+    fn alice() -> Principal {
+        user(1)
+    }
+
+    fn bob() -> Principal {
+        user(2)
+    }
+
+    fn admin() -> Principal {
+        let admin = user(9);
+        system::CONTROLLERS.with(|controllers| controllers.borrow_mut().push(admin));
+        admin
+    }
+
+    fn call_as(principal: Principal) {
+        system::CALLER.with(|caller| caller.set(principal));
+    }
+
+    fn set_time(timestamp: u64) {
+        system::TIME.with(|time| time.set(timestamp));
+    }
+
+    fn ok<T>(result: Result<T, Error>) -> T {
+        result.unwrap_or_else(|err| panic!("unexpected error: {}", error_message(&err)))
+    }
+
+    fn payload(name: &str, location: &str) -> SmartStorageItemPayload {
+        SmartStorageItemPayload {
+            name: name.to_string(),
+            description: "Cordless".to_string(),
+            location: location.to_string(),
+            is_available: true,
+        }
+    }
+
+    fn add(name: &str, location: &str) -> SmartStorageItem {
+        ok(add_smart_storage_item(payload(name, location), None))
+    }
+
+    #[test]
+    fn comments_record_their_author_and_only_the_author_changes_them() {
+        call_as(alice());
+        let item = add("Drill", "Shelf A");
+        set_time(50);
+        let comment = ok(add_item_comment(item.id, "handle is cracked".to_string(), None));
+        assert_eq!(comment.author, alice());
+        assert_eq!(comment.created_at, 50);
+
+        call_as(bob());
+        let edited = edit_item_comment(item.id, comment.id, "fine".to_string(), None);
+        assert!(matches!(edited, Err(Error::Unauthorized { .. })));
+        assert!(matches!(delete_item_comment(item.id, comment.id, None), Err(Error::Unauthorized { .. })));
+
+        call_as(alice());
+        set_time(60);
+        let edited = ok(edit_item_comment(item.id, comment.id, "handle is taped".to_string(), None));
+        assert_eq!(edited.body, "handle is taped");
+        assert_eq!(edited.updated_at, Some(60));
+        let comments = get_item_comments(item.id, 10, 0);
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].body, "handle is taped");
+
+        ok(delete_item_comment(item.id, comment.id, None));
+        assert!(get_item_comments(item.id, 10, 0).is_empty());
+    }
+
+    #[test]
+    fn comments_need_a_live_item_and_a_body() {
+        call_as(alice());
+        assert!(matches!(add_item_comment(7, "hello".to_string(), None), Err(Error::NotFound { .. })));
+        let item = add("Drill", "Shelf A");
+        assert!(matches!(add_item_comment(item.id, "  ".to_string(), None), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn watchers_are_notified_of_changes_by_others() {
+        call_as(alice());
+        let item = add("Drill", "Shelf A");
+        ok(watch_item(item.id, None));
+        assert_eq!(get_watchlist(), vec![item.id]);
+
+        call_as(bob());
+        set_time(100);
+        let moved = ok(update_smart_storage_item(item.id, item.version, payload("Drill", "Shelf B"), None));
+        let unavailable = ok(mark_item_as_unavailable(item.id, moved.version, None));
+        ok(delete_smart_storage_item(item.id, unavailable.version, None));
+        assert!(get_notifications(false, 10, 0).is_empty());
+
+        call_as(alice());
+        let notifications = get_notifications(false, 10, 0);
+        assert_eq!(notifications.len(), 3);
+        assert!(notifications.iter().all(|notification| notification.changed_by == bob() && notification.timestamp == 100));
+        assert!(matches!(&notifications[0].kind, ChangeKind::Moved { from, to } if from == "Shelf A" && to == "Shelf B"));
+        assert!(matches!(notifications[1].kind, ChangeKind::AvailabilityChanged { is_available: false }));
+        assert!(matches!(notifications[2].kind, ChangeKind::Deleted));
+
+        assert_eq!(get_unread_notification_count(), 3);
+        assert!(ok(mark_notification_as_read(notifications[0].id, None)).read);
+        assert_eq!(get_notifications(true, 10, 0).len(), 2);
+        assert_eq!(ok(mark_all_notifications_as_read(None)), 2);
+        assert_eq!(get_unread_notification_count(), 0);
+    }
+
+    #[test]
+    fn unwatched_items_stop_notifying() {
+        call_as(alice());
+        let item = add("Drill", "Shelf A");
+        ok(watch_item(item.id, None));
+        ok(unwatch_item(item.id, None));
+        assert!(get_watchlist().is_empty());
+        assert!(matches!(unwatch_item(item.id, None), Err(Error::NotFound { .. })));
+        ok(update_smart_storage_item(item.id, item.version, payload("Drill", "Shelf B"), None));
+        assert_eq!(get_unread_notification_count(), 0);
+    }
+
+    fn blob_chunks(id: u64) -> usize {
+        ITEM_BLOBS.with(|blobs| blobs.borrow().range((id, 0)..=(id, u32::MAX)).count())
+    }
+
+    #[test]
+    fn long_descriptions_are_stored_across_blob_chunks() {
+        call_as(alice());
+        let long = SmartStorageItemPayload {
+            description: "x".repeat(10_000),
+            ..payload("Drill", "Shelf A")
+        };
+        let item = ok(add_smart_storage_item(long, None));
+        assert_eq!(blob_chunks(item.id), 3);
+        ITEM_CACHE.with(|cache| cache.borrow_mut().clear());
+        assert_eq!(ok(get_smart_storage_item(item.id, None)).description.len(), 10_000);
+
+        ok(update_smart_storage_item(item.id, item.version, payload("Drill", "Shelf A"), None));
+        assert_eq!(blob_chunks(item.id), 1);
+        ITEM_CACHE.with(|cache| cache.borrow_mut().clear());
+        assert_eq!(ok(get_smart_storage_item(item.id, None)).description, "Cordless");
+    }
+
+    #[test]
+    fn field_limits_are_enforced_and_set_by_controllers() {
+        call_as(alice());
+        let long_name = SmartStorageItemPayload {
+            name: "x".repeat(257),
+            ..payload("Drill", "Shelf A")
+        };
+        assert!(matches!(add_smart_storage_item(long_name, None), Err(Error::InvalidInput { .. })));
+        let limits = ItemFieldLimits {
+            max_name_length: 8,
+            ..ItemFieldLimits::default()
+        };
+        assert!(matches!(set_item_field_limits(limits.clone()), Err(Error::Unauthorized { .. })));
+
+        call_as(admin());
+        let zero = ItemFieldLimits {
+            max_location_length: 0,
+            ..ItemFieldLimits::default()
+        };
+        assert!(matches!(set_item_field_limits(zero), Err(Error::InvalidInput { .. })));
+        ok(set_item_field_limits(limits));
+        add("Drill", "Shelf A");
+        let too_long = add_smart_storage_item(payload("Cordless drill", "Shelf A"), None);
+        assert!(matches!(too_long, Err(Error::InvalidInput { msg }) if msg.contains("name")));
+    }
+
+    #[test]
+    fn writes_bump_the_version_and_stale_versions_conflict() {
+        call_as(alice());
+        let item = add("Drill", "Shelf A");
+        assert_eq!(item.version, 1);
+        let updated = ok(update_smart_storage_item(item.id, 1, payload("Drill", "Shelf B"), None));
+        assert_eq!(updated.version, 2);
+        assert_eq!(ok(get_smart_storage_item(item.id, None)).location, "Shelf B");
+
+        // A second writer still holding version 1 loses and learns the current version.
+        let stale = update_smart_storage_item(item.id, 1, payload("Drill", "Shelf C"), None);
+        assert!(matches!(stale, Err(Error::Conflict { current_version: 2, .. })));
+        assert!(matches!(mark_item_as_unavailable(item.id, 1, None), Err(Error::Conflict { current_version: 2, .. })));
+        assert!(matches!(delete_smart_storage_item(item.id, 1, None), Err(Error::Conflict { current_version: 2, .. })));
+        let current = ok(get_smart_storage_item(item.id, None));
+        assert_eq!(current.location, "Shelf B");
+        assert!(current.is_available);
+
+        assert_eq!(ok(mark_item_as_unavailable(item.id, 2, None)).version, 3);
+        assert_eq!(ok(mark_item_as_available(item.id, 3, None)).version, 4);
+    }
+
+    #[test]
+    fn item_history_pages_real_entries_newest_first() {
+        call_as(alice());
+        set_time(10);
+        let item = add("Drill", "Shelf A");
+        call_as(bob());
+        set_time(20);
+        ok(update_smart_storage_item(item.id, 1, payload("Drill", "Shelf B"), None));
+        set_time(30);
+        ok(mark_item_as_unavailable(item.id, 2, None));
+
+        let page = get_item_history(item.id, 10, 0);
+        let kinds: Vec<_> = page.entries.iter().map(|entry| entry.change_type.as_str()).collect();
+        assert_eq!(kinds, ["MarkedUnavailable", "Update", "Creation"]);
+        let created = &page.entries[2];
+        assert_eq!((created.caller, created.timestamp), (alice(), 10));
+        let updated = &page.entries[1];
+        assert_eq!((updated.caller, updated.timestamp), (bob(), 20));
+        assert_eq!(updated.changes.len(), 1);
+        assert_eq!(updated.changes[0].field, "location");
+        assert_eq!(updated.changes[0].before.as_deref(), Some("Shelf A"));
+        assert_eq!(updated.changes[0].after.as_deref(), Some("Shelf B"));
+
+        let second = get_item_history(item.id, 1, 1);
+        assert_eq!(second.entries.len(), 1);
+        assert_eq!(second.entries[0].seq, updated.seq);
+        let transactions = get_item_transaction_history(item.id, 10, 2);
+        assert_eq!(transactions.entries.len(), 1);
+        assert_eq!(transactions.entries[0].transaction_type, "Creation");
+        assert_eq!(get_audit_log(0, 10).entries.len(), 3);
+    }
+
+    #[test]
+    fn audit_seqs_stay_with_their_item_and_snapshots_follow_the_interval() {
+        call_as(alice());
+        let drill = add("Drill", "Shelf A");
+        let saw = add("Saw", "Shelf B");
+        let mut version = drill.version;
+        for shelf in 0..ITEM_SNAPSHOT_INTERVAL {
+            let location = format!("Shelf {}", shelf);
+            version = ok(update_smart_storage_item(drill.id, version, payload("Drill", &location), None)).version;
+            if shelf == 0 {
+                ok(mark_item_as_unavailable(saw.id, saw.version, None));
+            }
+        }
+
+        let drill_seqs: Vec<_> = item_audit_seqs(drill.id, 0).collect();
+        assert_eq!(drill_seqs.len(), ITEM_SNAPSHOT_INTERVAL + 1);
+        assert!(drill_seqs.windows(2).all(|pair| pair[0] < pair[1]));
+        let saw_seqs: Vec<_> = item_audit_seqs(saw.id, 0).collect();
+        assert_eq!(saw_seqs, [1, 3]);
+        assert_eq!(item_audit_seqs(saw.id, 2).collect::<Vec<_>>(), [3]);
+        assert_eq!(item_audit_seqs_rev(saw.id).collect::<Vec<_>>(), [3, 1]);
+
+        // Only the drill has reached the snapshot interval.
+        let snapshots = |id| ITEM_SNAPSHOT_INDEX.with(|index| index.borrow().range((id, 0)..=(id, u64::MAX)).count());
+        assert_eq!(snapshots(drill.id), 1);
+        assert_eq!(snapshots(saw.id), 0);
+    }
+
+    fn append_audit_entry(entry: &AuditEntry) {
+        AUDIT_LOG.with(|log| log.borrow().append(entry).expect("cannot append to the audit log"));
+    }
+
+    #[test]
+    fn audit_chain_verification_reports_the_first_break() {
+        call_as(alice());
+        let item = add("Drill", "Shelf A");
+        ok(update_smart_storage_item(item.id, 1, payload("Drill", "Shelf B"), None));
+        ok(mark_item_as_unavailable(item.id, 2, None));
+        let intact = verify_audit_chain(0, 10);
+        assert_eq!(intact.checked, 3);
+        assert!(intact.first_break.is_none());
+
+        // An entry rewritten after it was hashed.
+        let mut forged = AuditEntry {
+            seq: 3,
+            item_id: item.id,
+            operation: AuditOperation::Update,
+            caller: alice(),
+            timestamp: time(),
+            changes: Vec::new(),
+            prev_hash: audit_chain_head_hash(),
+            hash: Vec::new(),
+        };
+        forged.hash = audit_entry_hash(&forged);
+        forged.caller = bob();
+        append_audit_entry(&forged);
+        // Entries recorded afterwards link to it and verify on their own.
+        ok(mark_item_as_available(item.id, 3, None));
+
+        let report = verify_audit_chain(0, 10);
+        assert_eq!(report.checked, 3);
+        let first_break = report.first_break.expect("the forged entry is reported");
+        assert_eq!(first_break.seq, 3);
+        assert!(first_break.reason.contains("contents"));
+        let later = verify_audit_chain(4, 10);
+        assert_eq!((later.start, later.checked), (4, 1));
+        assert!(later.first_break.is_none());
+
+        // An entry spliced in without linking to its predecessor.
+        let mut spliced = AuditEntry {
+            seq: 5,
+            prev_hash: GENESIS_AUDIT_HASH.to_vec(),
+            ..forged
+        };
+        spliced.hash = audit_entry_hash(&spliced);
+        append_audit_entry(&spliced);
+        let first_break = verify_audit_chain(4, 10).first_break.expect("the spliced entry is reported");
+        assert_eq!(first_break.seq, 5);
+        assert!(first_break.reason.contains("prev_hash"));
+    }
+
+    #[test]
+    fn deleted_items_wait_in_the_trash_until_restored() {
+        call_as(alice());
+        let item = add("Drill", "Shelf A");
+        ok(add_item_comment(item.id, "handle is cracked".to_string(), None));
+        set_time(40);
+        ok(delete_smart_storage_item(item.id, 1, None));
+        assert!(matches!(get_smart_storage_item(item.id, None), Err(Error::NotFound { .. })));
+        let trash = list_trash(10, 0);
+        assert_eq!(trash.len(), 1);
+        assert_eq!((trash[0].item.id, trash[0].deleted_by, trash[0].deleted_at), (item.id, alice(), 40));
+
+        assert!(matches!(restore_smart_storage_item(item.id, 2, None), Err(Error::Conflict { current_version: 1, .. })));
+        let restored = ok(restore_smart_storage_item(item.id, 1, None));
+        assert_eq!(restored.version, 2);
+        assert_eq!(ok(get_smart_storage_item(item.id, None)).location, "Shelf A");
+        assert_eq!(ok(get_items_by_location("Shelf".to_string(), 10, 0)).len(), 1);
+        assert_eq!(get_item_comments(item.id, 10, 0).len(), 1);
+        assert!(list_trash(10, 0).is_empty());
+        assert!(matches!(restore_smart_storage_item(item.id, 2, None), Err(Error::NotFound { .. })));
+    }
+
+    #[test]
+    fn only_the_deleter_or_a_controller_purges_a_trashed_item() {
+        call_as(alice());
+        let drill = add("Drill", "Shelf A");
+        let saw = add("Saw", "Shelf B");
+        ok(add_item_comment(drill.id, "handle is cracked".to_string(), None));
+        ok(delete_smart_storage_item(drill.id, 1, None));
+        ok(delete_smart_storage_item(saw.id, 1, None));
+
+        call_as(bob());
+        assert!(matches!(purge_smart_storage_item(drill.id, 1, None), Err(Error::Unauthorized { .. })));
+        call_as(alice());
+        ok(purge_smart_storage_item(drill.id, 1, None));
+        assert!(get_item_comments(drill.id, 10, 0).is_empty());
+        assert_eq!(blob_chunks(drill.id), 0);
+        assert!(matches!(restore_smart_storage_item(drill.id, 1, None), Err(Error::NotFound { .. })));
+        let history = get_item_history(drill.id, 1, 0);
+        assert_eq!(history.entries[0].change_type, "Purge");
+
+        call_as(admin());
+        ok(purge_smart_storage_item(saw.id, 1, None));
+        assert!(list_trash(10, 0).is_empty());
+    }
+
+    fn statuses(report: &BulkReport) -> Vec<&'static str> {
+        report
+            .entries
+            .iter()
+            .map(|entry| match entry.status {
+                BulkEntryStatus::Applied(_) => "applied",
+                BulkEntryStatus::Failed(_) => "failed",
+                BulkEntryStatus::NotApplied => "not applied",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn an_atomic_bulk_update_applies_nothing_when_one_entry_fails() {
+        call_as(alice());
+        let drill = add("Drill", "Shelf A");
+        let saw = add("Saw", "Shelf A");
+        let updates = || {
+            vec![
+                (drill.id, 1, payload("Drill", "Shelf B")),
+                (99, 1, payload("Hammer", "Shelf B")),
+                (saw.id, 1, payload("Saw", "Shelf B")),
+            ]
+        };
+        let report = ok(bulk_update_smart_storage_items(updates(), BulkMode::Atomic, None, None));
+        assert_eq!((report.applied, report.failed), (0, 1));
+        assert_eq!(statuses(&report), ["not applied", "failed", "not applied"]);
+        assert_eq!(ok(get_smart_storage_item(drill.id, None)).location, "Shelf A");
+        assert_eq!(ok(get_smart_storage_item(saw.id, None)).version, 1);
+
+        let repeated = vec![(drill.id, 1, payload("Drill", "Shelf B")), (drill.id, 1, payload("Drill", "Shelf C"))];
+        let report = ok(bulk_update_smart_storage_items(repeated, BulkMode::Atomic, None, None));
+        assert_eq!(statuses(&report), ["not applied", "failed"]);
+
+        let report = ok(bulk_update_smart_storage_items(updates(), BulkMode::BestEffort, None, None));
+        assert_eq!((report.applied, report.failed), (2, 1));
+        assert_eq!(statuses(&report), ["applied", "failed", "applied"]);
+        assert_eq!(ok(get_smart_storage_item(saw.id, None)).location, "Shelf B");
+    }
+
+    #[test]
+    fn an_atomic_bulk_add_or_delete_leaves_the_store_untouched_on_failure() {
+        call_as(alice());
+        let drill = add("Drill", "Shelf A");
+        let items = vec![payload("Saw", "Shelf B"), payload(&"x".repeat(257), "Shelf B")];
+        let report = ok(bulk_add_smart_storage_items(items, BulkMode::Atomic, None, None));
+        assert_eq!(statuses(&report), ["not applied", "failed"]);
+        assert_eq!(ID_COUNTER.with(|counter| *counter.borrow().get()), drill.id + 1);
+        assert_eq!(get_paginated_smart_storage_items(10, 0, None).len(), 1);
+
+        let deletions = vec![(drill.id, 1), (drill.id + 1, 1)];
+        let report = ok(bulk_delete_smart_storage_items(deletions, BulkMode::Atomic, None, None));
+        assert_eq!(statuses(&report), ["not applied", "failed"]);
+        assert!(list_trash(10, 0).is_empty());
+        assert_eq!(ok(get_smart_storage_item(drill.id, None)).version, 1);
+    }
+
+    // Starts a new message in which every read of the instruction counter
+    // reports `step` more instructions than the last.
+    fn count_instructions(step: u64) {
+        system::INSTRUCTIONS.with(|instructions| instructions.set(0));
+        system::INSTRUCTION_STEP.with(|instruction_step| instruction_step.set(step));
+    }
+
+    #[test]
+    fn bulk_add_hands_out_consecutive_ids_and_continues_past_the_instruction_budget() {
+        call_as(alice());
+        let names = ["Drill", "Saw", "Hammer", "Level", "Clamp"];
+        let items = || names.iter().map(|name| payload(name, "Shelf A")).collect::<Vec<_>>();
+        count_instructions(BULK_BATCH_INSTRUCTIONS / 2);
+        let report = ok(bulk_add_smart_storage_items(items(), BulkMode::BestEffort, None, None));
+        assert_eq!(report.applied, 3);
+        assert_eq!(report.continuation, Some(3));
+        let ids: Vec<_> = report
+            .entries
+            .iter()
+            .map(|entry| match &entry.status {
+                BulkEntryStatus::Applied(item) => item.id,
+                _ => panic!("entry {} was not applied", entry.index),
+            })
+            .collect();
+        assert_eq!(ids, [0, 1, 2]);
+        assert_eq!(ID_COUNTER.with(|counter| *counter.borrow().get()), 3);
+
+        count_instructions(0);
+        let report = ok(bulk_add_smart_storage_items(items(), BulkMode::BestEffort, Some(3), None));
+        assert_eq!((report.applied, report.continuation), (2, None));
+        assert_eq!(report.entries.iter().map(|entry| entry.index).collect::<Vec<_>>(), [3, 4]);
+        let names_added: Vec<_> = get_paginated_smart_storage_items(10, 0, None)
+            .into_iter()
+            .map(|item| item.name)
+            .collect();
+        assert_eq!(names_added, names);
+    }
+
+    #[test]
+    fn bulk_delete_reports_each_entry_and_moves_items_to_the_trash() {
+        call_as(alice());
+        let drill = add("Drill", "Shelf A");
+        let saw = add("Saw", "Shelf A");
+        let deletions = vec![(drill.id, 1), (saw.id, 2), (99, 1)];
+        let report = ok(bulk_delete_smart_storage_items(deletions, BulkMode::BestEffort, None, None));
+        assert_eq!((report.applied, report.failed), (1, 2));
+        assert_eq!(statuses(&report), ["applied", "failed", "failed"]);
+        assert!(matches!(
+            report.entries[1].status,
+            BulkEntryStatus::Failed(Error::Conflict { current_version: 1, .. })
+        ));
+        assert_eq!(list_trash(10, 0).len(), 1);
+        assert!(get_smart_storage_item(saw.id, None).is_ok());
+    }
+
+    fn integrity_problems(report: &IntegrityReport) -> Vec<(&str, bool)> {
+        report
+            .issues
+            .iter()
+            .map(|issue| (issue.store.as_str(), issue.repaired))
+            .collect()
+    }
+
+    #[test]
+    fn integrity_repair_fixes_the_counter_and_indexes_but_not_broken_records() {
+        call_as(alice());
+        let drill = add("Drill", "Shelf A");
+        let saw = add("Saw", "Shelf B");
+        assert!(matches!(check_integrity(None, false), Err(Error::Unauthorized { .. })));
+        call_as(admin());
+        assert!(ok(check_integrity(None, false)).issues.is_empty());
+
+        ID_COUNTER.with(|counter| counter.borrow_mut().set(saw.id)).expect("cannot set id counter");
+        ITEMS_BY_LOCATION.with(|index| index.borrow_mut().remove(&(LocationKey("Shelf A".to_string()), drill.id)));
+        ITEM_BLOBS.with(|blobs| blobs.borrow_mut().insert((42, 0), ItemChunk(b"orphan".to_vec())));
+        STORAGE_ITEM_STORAGE.with(|items| items.borrow_mut().insert(saw.id + 1, StoredItem(vec![ITEM_SCHEMA_VERSION + 1])));
+
+        let report = ok(check_integrity(None, false));
+        assert_eq!(
+            integrity_problems(&report),
+            [("id counter", false), ("items", false), ("items", false), ("item blobs", false)]
+        );
+        assert_eq!(report.repaired, 0);
+        assert_eq!(ID_COUNTER.with(|counter| *counter.borrow().get()), saw.id);
+
+        let report = ok(check_integrity(None, true));
+        assert_eq!(
+            integrity_problems(&report),
+            [("id counter", true), ("items", true), ("items", false), ("item blobs", true)]
+        );
+        assert_eq!(report.repaired, 3);
+        assert_eq!(ID_COUNTER.with(|counter| *counter.borrow().get()), saw.id + 2);
+        assert_eq!(ok(get_items_by_location("Shelf A".to_string(), 10, 0)).len(), 1);
+        assert_eq!(blob_chunks(42), 0);
+        // Only the record that does not decode is left.
+        let report = ok(check_integrity(None, true));
+        assert_eq!(integrity_problems(&report), [("items", false)]);
+    }
+
+    #[test]
+    fn integrity_checks_resume_from_the_returned_cursor() {
+        call_as(admin());
+        for name in ["Drill", "Saw", "Hammer", "Level"] {
+            add(name, "Shelf A");
+        }
+        count_instructions(0);
+        let full = ok(check_integrity(None, false));
+        assert!(full.next.is_none());
+
+        count_instructions(INTEGRITY_BATCH_INSTRUCTIONS / 2);
+        let first = ok(check_integrity(None, false));
+        let cursor = first.next.expect("the walk stops at the instruction budget");
+        assert!(matches!(cursor.phase, IntegrityPhase::Items));
+        count_instructions(0);
+        let rest = ok(check_integrity(Some(cursor), false));
+        assert!(rest.next.is_none());
+        assert_eq!(first.checked + rest.checked, full.checked);
+    }
+
+    #[test]
+    fn memory_usage_reports_every_registered_region() {
+        assert_memory_regions();
+        call_as(alice());
+        add("Drill", "Shelf A");
+        let usage = get_memory_usage();
+        assert_eq!(usage.regions.len(), MEMORY_REGIONS.len());
+        let items = usage
+            .regions
+            .iter()
+            .find(|region| region.id == ITEMS_MEMORY)
+            .expect("the items region is reported");
+        assert_eq!(items.name, "items");
+        assert!(items.pages > 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "not registered")]
+    fn unregistered_memory_ids_are_refused() {
+        let unused = (0..=u8::MAX)
+            .find(|id| MEMORY_REGIONS.iter().all(|(region, _)| region != id))
+            .expect("a free memory id");
+        stable_memory(unused);
+    }
+
+    fn archive() -> Principal {
+        user(7)
+    }
+
+    // Polls a future once; enough for paths that return before their first call.
+    fn poll_once<F: std::future::Future>(future: F) -> Option<F::Output> {
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        match std::pin::pin!(future).poll(&mut context) {
+            std::task::Poll::Ready(output) => Some(output),
+            std::task::Poll::Pending => None,
+        }
+    }
+
+    #[test]
+    fn archived_history_ranges_point_at_the_archive() {
+        call_as(admin());
+        ok(set_archive_config(ArchiveConfig { archive: Some(archive()) }));
+        // The first two entries of the log were already moved to the archive.
+        AUDIT_ARCHIVE_STATE
+            .with(|state| {
+                state.borrow_mut().set(AuditArchiveState {
+                    base: 2,
+                    ..AuditArchiveState::default()
+                })
+            })
+            .expect("cannot set the archive state");
+        let item = add("Drill", "Shelf A");
+        AUDIT_LOG_BY_ITEM.with(|index| {
+            index.borrow_mut().insert((item.id, 0), ());
+            index.borrow_mut().insert((item.id, 1), ());
+        });
+
+        let log = get_audit_log(0, 10);
+        assert_eq!(log.entries.iter().map(|entry| entry.seq).collect::<Vec<_>>(), [2]);
+        let range = log.archived.expect("the archived part is pointed at");
+        assert_eq!((range.archive, range.start, range.length), (archive(), 0, 2));
+        assert!(get_audit_log(2, 10).archived.is_none());
+
+        let history = get_item_history(item.id, 10, 0);
+        assert_eq!(history.entries.len(), 1);
+        let archived = history.archived.expect("the archived part is pointed at");
+        assert_eq!((archived.archive, archived.offset, archived.limit), (archive(), 0, 2));
+
+        let other = ArchiveConfig { archive: Some(user(8)) };
+        assert!(matches!(set_archive_config(other), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn retired_items_leave_the_store_and_are_found_in_the_archive_once_moved() {
+        call_as(admin());
+        ok(set_archive_config(ArchiveConfig { archive: Some(archive()) }));
+        let item = add("Drill", "Shelf A");
+        ok(retire_smart_storage_item(item.id, 1, None));
+        let retired = get_smart_storage_item(item.id, None);
+        assert!(matches!(retired, Err(Error::NotFound { msg }) if msg.contains("retired")));
+        assert!(ok(get_items_by_location("Shelf".to_string(), 10, 0)).is_empty());
+        assert_eq!(get_archive_info().pending_retired_items, 1);
+
+        // What an archive run leaves behind once the archive has taken the item.
+        RETIRED_ITEMS.with(|items| items.borrow_mut().remove(&item.id));
+        ARCHIVED_ITEMS.with(|items| items.borrow_mut().insert(item.id, ()));
+        let archived = get_smart_storage_item(item.id, None);
+        assert!(matches!(archived, Err(Error::Archived { archive: at, .. }) if at == archive()));
+        let other = ArchiveConfig { archive: Some(user(8)) };
+        assert!(matches!(set_archive_config(other), Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn only_one_archive_run_holds_the_flag_at_a_time() {
+        let no_archive = poll_once(archive_pass()).expect("returns before any call");
+        assert!(matches!(no_archive, Err(Error::InvalidInput { msg }) if msg.contains("configured")));
+
+        call_as(admin());
+        ok(set_archive_config(ArchiveConfig { archive: Some(archive()) }));
+        let running = ArchiveRunGuard::acquire().expect("no run is in progress");
+        assert!(ArchiveRunGuard::acquire().is_none());
+        let second = poll_once(archive_pass()).expect("returns before any call");
+        assert!(matches!(second, Err(Error::InvalidInput { msg }) if msg.contains("in progress")));
+        drop(running);
+        assert!(ArchiveRunGuard::acquire().is_some());
+    }
+
+    fn router() -> Principal {
+        user(5)
+    }
+
+    fn shard_config(first_id: u64, end_id: u64) -> ShardConfig {
+        ShardConfig {
+            first_id,
+            end_id,
+            max_stable_memory_bytes: None,
+            router: Some(router()),
+        }
+    }
+
+    #[test]
+    fn a_shard_hands_out_only_its_id_block() {
+        call_as(admin());
+        ok(set_shard_config(shard_config(10, 12)));
+        assert_eq!(add("Drill", "Shelf A").id, 10);
+        assert_eq!(add("Saw", "Shelf A").id, 11);
+        assert!(matches!(add_smart_storage_item(payload("Hammer", "Shelf A"), None), Err(Error::ShardFull { .. })));
+        let report = ok(bulk_add_smart_storage_items(vec![payload("Hammer", "Shelf A")], BulkMode::BestEffort, None, None));
+        assert!(matches!(report.entries[0].status, BulkEntryStatus::Failed(Error::ShardFull { .. })));
+
+        // Ids already handed out pin the start of the block and its smallest end.
+        assert!(matches!(set_shard_config(shard_config(0, 20)), Err(Error::InvalidInput { .. })));
+        assert!(matches!(set_shard_config(shard_config(10, 11)), Err(Error::InvalidInput { .. })));
+        ok(set_shard_config(shard_config(10, 20)));
+        assert_eq!(add("Hammer", "Shelf A").id, 12);
+    }
+
+    #[test]
+    fn routed_updates_act_as_the_end_user_and_only_come_from_the_router() {
+        call_as(admin());
+        ok(set_shard_config(shard_config(0, 100)));
+        let arg = Encode!(&payload("Drill", "Shelf A"), &None::<String>).unwrap();
+
+        call_as(bob());
+        let direct = routed_update(alice(), "add_smart_storage_item".to_string(), arg.clone());
+        assert!(matches!(direct, Err(Error::Unauthorized { .. })));
+
+        call_as(router());
+        let reply = ok(routed_update(alice(), "add_smart_storage_item".to_string(), arg));
+        let item = ok(Decode!(&reply, Result<SmartStorageItem, Error>).unwrap());
+        assert_eq!(get_item_history(item.id, 1, 0).entries[0].caller, alice());
+        let unknown = routed_update(alice(), "purge_smart_storage_item".to_string(), Vec::new());
+        assert!(matches!(unknown, Err(Error::InvalidInput { .. })));
+        let garbled = routed_update(alice(), "watch_item".to_string(), vec![1, 2, 3]);
+        assert!(matches!(garbled, Err(Error::DecodeFailed { .. })));
+        // The routed caller does not outlive the call.
+        assert_eq!(caller(), router());
+    }
+
+    #[test]
+    fn undo_takes_back_the_callers_operations_newest_first() {
+        call_as(alice());
+        let item = add("Drill", "Shelf A");
+        ok(update_smart_storage_item(item.id, 1, payload("Drill", "Shelf B"), None));
+        ok(delete_smart_storage_item(item.id, 2, None));
+        assert_eq!(get_undoable_operations(10).len(), 3);
+
+        let report = ok(undo_last_operations(2, None));
+        assert!(report.refused.is_none());
+        let undone: Vec<_> = report.undone.iter().map(|undone| undone.operation.label()).collect();
+        assert_eq!(undone, ["Deletion", "Update"]);
+        let restored = ok(get_smart_storage_item(item.id, None));
+        assert_eq!(restored.location, "Shelf A");
+        // The undos are new operations of their own, and are not undone in turn.
+        assert_eq!(get_item_history(item.id, 10, 0).entries.len(), 5);
+        assert_eq!(get_undoable_operations(10).len(), 1);
+
+        let report = ok(undo_last_operations(5, None));
+        assert_eq!(report.undone.len(), 1);
+        assert!(matches!(get_smart_storage_item(item.id, None), Err(Error::NotFound { .. })));
+        assert_eq!(list_trash(10, 0).len(), 1);
+        assert!(ok(undo_last_operations(1, None)).undone.is_empty());
+    }
+
+    #[test]
+    fn undo_is_refused_once_someone_else_changed_the_item() {
+        call_as(alice());
+        let item = add("Drill", "Shelf A");
+        ok(update_smart_storage_item(item.id, 1, payload("Drill", "Shelf B"), None));
+        call_as(bob());
+        ok(mark_item_as_unavailable(item.id, 2, None));
+
+        call_as(alice());
+        let report = ok(undo_last_operations(1, None));
+        assert!(report.undone.is_empty());
+        let refused = report.refused.expect("the update is refused");
+        assert!(matches!(refused.error, Error::Conflict { current_version: 3, .. }));
+        assert_eq!(ok(get_smart_storage_item(item.id, None)).location, "Shelf B");
+
+        // Once bob undoes his change, alice's update is hers to undo again.
+        call_as(bob());
+        ok(undo_last_operations(1, None));
+        call_as(alice());
+        assert_eq!(ok(undo_last_operations(1, None)).undone.len(), 1);
+        assert_eq!(ok(get_smart_storage_item(item.id, None)).location, "Shelf A");
+
+        assert!(matches!(undo_last_operations(0, None), Err(Error::InvalidInput { .. })));
+        call_as(Principal::anonymous());
+        assert!(matches!(undo_last_operations(1, None), Err(Error::Unauthorized { .. })));
+    }
+
+    #[test]
+    fn reverting_writes_an_old_version_as_a_new_one() {
+        call_as(alice());
+        let item = add("Drill", "Shelf A");
+        let hammer = add("Hammer", "Shelf A");
+        ok(update_smart_storage_item(item.id, 1, payload("Cordless drill", "Shelf B"), None));
+        ok(mark_item_as_unavailable(item.id, 2, None));
+        let versions = ok(get_item_versions(item.id, 10, 0));
+        let numbers: Vec<_> = versions.iter().map(|version| version.item.version).collect();
+        assert_eq!(numbers, [3, 2, 1]);
+        assert_eq!(versions[2].item.name, "Drill");
+
+        assert!(matches!(revert_smart_storage_item(item.id, 2, 1, None), Err(Error::Conflict { current_version: 3, .. })));
+        assert!(matches!(revert_smart_storage_item(item.id, 3, 3, None), Err(Error::InvalidInput { .. })));
+        assert!(matches!(revert_smart_storage_item(hammer.id, 1, 0, None), Err(Error::NotFound { .. })));
+
+        let reverted = ok(revert_smart_storage_item(item.id, 3, 1, None));
+        assert_eq!(reverted.version, 4);
+        assert_eq!((reverted.name.as_str(), reverted.location.as_str()), ("Drill", "Shelf A"));
+        assert!(reverted.is_available);
+        assert_eq!(ok(get_item_versions(item.id, 10, 0)).len(), 4);
+    }
+
+    #[test]
+    fn reverting_to_an_emptied_location_is_refused() {
+        call_as(alice());
+        let item = add("Drill", "Shelf A");
+        ok(update_smart_storage_item(item.id, 1, payload("Drill", "Shelf B"), None));
+        let refused = revert_smart_storage_item(item.id, 2, 1, None);
+        assert!(matches!(refused, Err(Error::InvalidInput { msg }) if msg.contains("Shelf A")));
+        assert_eq!(ok(get_smart_storage_item(item.id, None)).version, 2);
+
+        add("Saw", "Shelf A");
+        assert_eq!(ok(revert_smart_storage_item(item.id, 2, 1, None)).location, "Shelf A");
+    }
+
+    #[test]
+    fn reverting_runs_the_update_validation() {
+        call_as(alice());
+        let item = add("Cordless drill", "Shelf A");
+        ok(update_smart_storage_item(item.id, 1, payload("Drill", "Shelf A"), None));
+        call_as(admin());
+        let limits = ItemFieldLimits {
+            max_name_length: 8,
+            ..ItemFieldLimits::default()
+        };
+        ok(set_item_field_limits(limits));
+        call_as(alice());
+        assert!(matches!(revert_smart_storage_item(item.id, 2, 1, None), Err(Error::InvalidInput { msg }) if msg.contains("name")));
+    }
+
+    // The raw argument of the current message, which keys are fingerprinted by.
+    fn set_arg_data(arg: Vec<u8>) {
+        system::ARG_DATA.with(|data| *data.borrow_mut() = arg);
+    }
+
+    fn add_with_key(name: &str, key: &str) -> Result<SmartStorageItem, Error> {
+        let item = payload(name, "Shelf A");
+        set_arg_data(Encode!(&item, &Some(key)).unwrap());
+        add_smart_storage_item(item, Some(key.to_string()))
+    }
+
+    fn item_count() -> usize {
+        get_paginated_smart_storage_items(100, 0, None).len()
+    }
+
+    #[test]
+    fn a_retried_call_gets_the_stored_reply_back() {
+        call_as(alice());
+        set_time(100);
+        let first = ok(add_with_key("Drill", "k1"));
+        let retry = ok(add_with_key("Drill", "k1"));
+        assert_eq!((retry.id, retry.created_at), (first.id, first.created_at));
+        assert_eq!(item_count(), 1);
+
+        // Errors are replayed as well, even once the call would succeed.
+        set_arg_data(Encode!(&first.id, &1u64, &Some("k2")).unwrap());
+        let failed = delete_smart_storage_item(first.id, 2, Some("k2".to_string()));
+        assert!(matches!(failed, Err(Error::Conflict { .. })));
+        ok(mark_item_as_unavailable(first.id, 1, None));
+        let replayed = delete_smart_storage_item(first.id, 2, Some("k2".to_string()));
+        assert!(matches!(replayed, Err(Error::Conflict { current_version: 1, .. })));
+
+        // Keys belong to their caller, and expire with the window.
+        call_as(bob());
+        assert_ne!(ok(add_with_key("Drill", "k1")).id, first.id);
+        call_as(alice());
+        set_time(100 + get_idempotency_window());
+        assert_ne!(ok(add_with_key("Drill", "k1")).id, first.id);
+        assert_eq!(item_count(), 3);
+    }
+
+    #[test]
+    fn a_key_reused_for_a_different_call_is_rejected() {
+        call_as(alice());
+        ok(add_with_key("Drill", "k1"));
+        let other_payload = add_with_key("Saw", "k1");
+        assert!(matches!(other_payload, Err(Error::InvalidInput { msg }) if msg.contains("different call")));
+
+        // Same bytes, different method.
+        let arg = Encode!(&payload("Drill", "Shelf A"), &Some("k1")).unwrap();
+        set_arg_data(arg);
+        let other_method = bulk_add_smart_storage_items(Vec::new(), BulkMode::Atomic, None, Some("k1".to_string()));
+        assert!(matches!(other_method, Err(Error::InvalidInput { .. })));
+
+        let too_long = "k".repeat(MAX_IDEMPOTENCY_KEY_LENGTH + 1);
+        assert!(matches!(add_with_key("Saw", &too_long), Err(Error::InvalidInput { .. })));
+        assert!(matches!(add_with_key("Saw", ""), Err(Error::InvalidInput { .. })));
+        assert_eq!(item_count(), 1);
+        assert_eq!(IDEMPOTENCY_KEYS.with(|keys| keys.borrow().len()), 1);
+    }
+
+    fn corrupt_item(id: u64) {
+        STORAGE_ITEM_STORAGE.with(|items| items.borrow_mut().insert(id, StoredItem(vec![ITEM_SCHEMA_VERSION + 1])));
+    }
+
+    #[test]
+    fn upgrade_validation_refuses_unreadable_or_inconsistent_state() {
+        call_as(alice());
+        let drill = add("Drill", "Shelf A");
+        add("Saw", "Shelf A");
+        let hammer = add("Hammer", "Shelf A");
+        assert!(validate_stable_state().is_ok());
+
+        corrupt_item(drill.id);
+        let refused = validate_stable_state().expect_err("an unreadable record is found");
+        assert!(refused.contains(&format!("item id={}", drill.id)));
+        // Past the instruction budget only the last record is still decoded.
+        system::INSTRUCTIONS.with(|used| used.set(UPGRADE_VALIDATION_INSTRUCTIONS + 1));
+        assert!(validate_stable_state().is_ok());
+        corrupt_item(hammer.id);
+        assert!(validate_stable_state().is_err());
+        count_instructions(0);
+
+        STORAGE_ITEM_STORAGE.with(|items| {
+            items.borrow_mut().remove(&drill.id);
+            items.borrow_mut().remove(&hammer.id);
+        });
+        ID_COUNTER.with(|counter| counter.borrow_mut().set(1)).expect("cannot set id counter");
+        let refused = validate_stable_state().expect_err("a lagging counter is found");
+        assert!(refused.contains("id counter"));
+        ID_COUNTER.with(|counter| counter.borrow_mut().set(3)).expect("cannot set id counter");
+
+        let mut forged = audit_entry(audit_log_len() - 1).expect("the last entry is local");
+        forged.seq = audit_log_len();
+        forged.caller = bob();
+        append_audit_entry(&forged);
+        let refused = validate_stable_state().expect_err("a tampered last entry is found");
+        assert!(refused.contains("hash"));
+    }
+
+    #[test]
+    fn stale_derived_indexes_are_rebuilt_from_their_source() {
+        call_as(alice());
+        let drill = add("Drill", "Shelf A");
+        ok(delete_smart_storage_item(add("Saw", "Shelf B").id, 1, None));
+        ITEMS_BY_LOCATION.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(ITEMS_BY_LOCATION_MEMORY)));
+        TRASH_BY_TIME.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(TRASH_BY_TIME_MEMORY)));
+        INDEX_VERSIONS.with(|versions| {
+            let mut versions = versions.borrow_mut();
+            for (index, version) in DERIVED_INDEXES {
+                versions.insert(index, version);
+            }
+            versions.insert(ITEMS_BY_LOCATION_MEMORY, 0);
+        });
+
+        rebuild_stale_indexes();
+        let at_shelf = ok(get_items_by_location("Shelf A".to_string(), 10, 0));
+        assert_eq!(at_shelf.iter().map(|item| item.id).collect::<Vec<_>>(), [drill.id]);
+        // Indexes whose version is current are left alone.
+        assert!(TRASH_BY_TIME.with(|map| map.borrow().is_empty()));
+        assert_eq!(INDEX_VERSIONS.with(|versions| versions.borrow().get(&ITEMS_BY_LOCATION_MEMORY)), Some(1));
+    }
+
+    #[test]
+    fn init_arguments_go_through_the_setters() {
+        call_as(admin());
+        let args = InitArgs {
+            field_limits: Some(ItemFieldLimits {
+                max_name_length: 8,
+                ..ItemFieldLimits::default()
+            }),
+            shard: Some(ShardConfig {
+                router: None,
+                ..shard_config(100, 200)
+            }),
+            ..InitArgs::default()
+        };
+        apply_init_args(Some(args));
+        assert_eq!(get_item_field_limits().max_name_length, 8);
+        assert_eq!(add("Drill", "Shelf A").id, 100);
+    }
+
+    fn max_age(age_ns: u64) -> RetentionPolicy {
+        RetentionPolicy {
+            max_age_ns: Some(age_ns),
+            max_count: None,
+        }
+    }
+
+    #[test]
+    fn retention_purges_what_is_past_its_cutoff() {
+        call_as(admin());
+        ok(set_retention_policy(RetainedData::Trash, max_age(1_000)));
+        ok(set_retention_policy(RetainedData::IdempotencyRecords, max_age(1_000)));
+        ok(set_retention_policy(
+            RetainedData::Notifications,
+            RetentionPolicy {
+                max_age_ns: None,
+                max_count: Some(1),
+            },
+        ));
+        set_time(100);
+        let drill = add("Drill", "Shelf A");
+        let saw = add("Saw", "Shelf A");
+        ok(watch_item(saw.id, None));
+        ok(delete_smart_storage_item(drill.id, 1, None));
+        ok(add_with_key("Hammer", "k1"));
+        call_as(alice());
+        ok(update_smart_storage_item(saw.id, 1, payload("Saw", "Shelf B"), None));
+        set_time(200);
+        ok(mark_item_as_unavailable(saw.id, 2, None));
+        ok(delete_smart_storage_item(saw.id, 3, None));
+
+        assert!(matches!(run_retention(), Err(Error::Unauthorized { .. })));
+        call_as(admin());
+        // Records exactly max_age_ns old are past the cutoff; younger ones stay.
+        set_time(1_100);
+        let run = ok(run_retention());
+        assert_eq!((run.trash, run.idempotency_records, run.notifications), (1, 1, 2));
+        let trash = list_trash(10, 0);
+        assert_eq!(trash.iter().map(|trashed| trashed.item.id).collect::<Vec<_>>(), [saw.id]);
+        assert!(IDEMPOTENCY_KEYS.with(|keys| keys.borrow().is_empty()));
+        let notifications = get_notifications(false, 10, 0);
+        assert_eq!(notifications.len(), 1);
+        assert!(matches!(notifications[0].kind, ChangeKind::Deleted));
+
+        let runs = get_retention_runs(10, 0);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].timestamp, 1_100);
+        // A run that removes nothing is not recorded.
+        assert_eq!(ok(run_retention()).trash, 0);
+        assert_eq!(get_retention_runs(10, 0).len(), 1);
+        set_time(1_200);
+        assert_eq!(ok(run_retention()).trash, 1);
+        assert!(list_trash(10, 0).is_empty());
+    }
+
+    #[test]
+    fn history_is_only_bounded_once_an_archive_is_set() {
+        call_as(admin());
+        let refused = set_retention_policy(RetainedData::History, max_age(1_000));
+        assert!(matches!(refused, Err(Error::InvalidInput { msg }) if msg.contains("archive")));
+        ok(set_archive_config(ArchiveConfig { archive: Some(archive()) }));
+        ok(set_retention_policy(RetainedData::History, max_age(1_000)));
+        assert_eq!(get_retention_policies().history.max_age_ns, Some(1_000));
+
+        // The older settings read and write the same policies; u64::MAX is no limit.
+        ok(set_trash_retention(u64::MAX));
+        assert_eq!(get_retention_policies().trash.max_age_ns, None);
+        assert_eq!(get_trash_retention(), u64::MAX);
+        ok(set_retention_policy(RetainedData::IdempotencyRecords, max_age(5)));
+        assert_eq!(get_idempotency_window(), 5);
+    }
+
+    #[test]
+    fn legacy_retention_limits_are_moved_into_the_policies_once() {
+        ARCHIVE_CONFIG
+            .with(|config| {
+                config.borrow_mut().set(StoredArchiveConfig {
+                    archive: None,
+                    history_retention_ns: Some(u64::MAX),
+                })
+            })
+            .expect("cannot set the archive config");
+        LEGACY_TRASH_RETENTION_NS.with(|cell| cell.borrow_mut().set(5)).expect("cannot set the trash retention");
+        LEGACY_IDEMPOTENCY_WINDOW_NS.with(|cell| cell.borrow_mut().set(7)).expect("cannot set the window");
+
+        move_legacy_retention_limits();
+        let policies = get_retention_policies();
+        assert_eq!(policies.history.max_age_ns, None);
+        assert_eq!(policies.trash.max_age_ns, Some(5));
+        assert_eq!(policies.idempotency_records.max_age_ns, Some(7));
+        assert!(ARCHIVE_CONFIG.with(|config| config.borrow().get().history_retention_ns.is_none()));
+
+        // Later runs leave the policies to their own setters.
+        LEGACY_TRASH_RETENTION_NS.with(|cell| cell.borrow_mut().set(9)).expect("cannot set the trash retention");
+        move_legacy_retention_limits();
+        assert_eq!(get_retention_policies().trash.max_age_ns, Some(5));
+    }
+
+    #[test]
+    fn restricted_fields_are_blanked_unless_a_role_grants_them() {
+        call_as(admin());
+        let item = add("Drill", "Shelf A");
+        ok(set_field_restricted(ItemField::Location, true));
+        ok(set_field_restricted(ItemField::Description, true));
+        ok(set_role_field_access("auditor".to_string(), vec![ItemField::Location]));
+        ok(assign_role(bob(), "auditor".to_string()));
+        call_as(alice());
+        assert!(matches!(assign_role(alice(), "auditor".to_string()), Err(Error::Unauthorized { .. })));
+
+        let seen = ok(get_smart_storage_item(item.id, None));
+        assert_eq!((seen.name.as_str(), seen.description.as_str(), seen.location.as_str()), ("Drill", "", ""));
+        assert!(matches!(get_items_by_location("Shelf".to_string(), 10, 0), Err(Error::Unauthorized { .. })));
+        let created = &get_item_history(item.id, 1, 0).entries[0];
+        let location = created.changes.iter().find(|change| change.field == "location").expect("location is listed");
+        assert!(location.after.is_none());
+
+        call_as(bob());
+        let seen = ok(get_smart_storage_item(item.id, None));
+        assert_eq!((seen.description.as_str(), seen.location.as_str()), ("", "Shelf A"));
+        assert_eq!(ok(get_items_by_location("Shelf".to_string(), 10, 0)).len(), 1);
+
+        call_as(admin());
+        assert_eq!(ok(get_smart_storage_item(item.id, None)).description, "Cordless");
+        ok(unassign_role(bob(), "auditor".to_string()));
+        call_as(bob());
+        assert_eq!(ok(get_smart_storage_item(item.id, None)).location, "");
+    }
+
+    #[test]
+    fn writes_by_restricted_callers_store_the_full_item_and_reply_redacted() {
+        call_as(admin());
+        let item = add("Drill", "Shelf A");
+        ok(watch_item(item.id, None));
+        ok(set_field_restricted(ItemField::Location, true));
+
+        call_as(alice());
+        let updated = ok(update_smart_storage_item(item.id, 1, payload("Drill", "Shelf B"), None));
+        assert_eq!(updated.location, "");
+        let updates = vec![(item.id, 2, payload("Cordless drill", "Shelf C"))];
+        let report = ok(bulk_update_smart_storage_items(updates, BulkMode::Atomic, None, None));
+        assert!(matches!(&report.entries[0].status, BulkEntryStatus::Applied(item) if item.location.is_empty()));
+
+        call_as(admin());
+        let stored = ok(get_smart_storage_item(item.id, None));
+        assert_eq!((stored.name.as_str(), stored.location.as_str()), ("Cordless drill", "Shelf C"));
+        let notifications = get_notifications(false, 10, 0);
+        assert!(matches!(&notifications[0].kind, ChangeKind::Moved { to, .. } if to == "Shelf B"));
+    }
+
+    fn names_at(prefix: &str, limit: usize, offset: usize) -> Vec<String> {
+        ok(get_items_by_location(prefix.to_string(), limit, offset))
+            .into_iter()
+            .map(|item| item.name)
+            .collect()
+    }
+
+    #[test]
+    fn location_queries_match_by_prefix_in_location_order() {
+        call_as(alice());
+        add("Saw", "Shelf B");
+        add("Drill", "Shelf A2");
+        add("Hammer", "Shelf A");
+        add("Level", "Bin 1");
+        add("Clamp", "Shelf A");
+
+        assert_eq!(names_at("Shelf A", 10, 0), ["Hammer", "Clamp", "Drill"]);
+        assert_eq!(names_at("Shelf A", 1, 1), ["Clamp"]);
+        assert_eq!(names_at("Shelf", 10, 0), ["Hammer", "Clamp", "Drill", "Saw"]);
+        assert_eq!(names_at("", 10, 0).len(), 5);
+        assert!(names_at("shelf", 10, 0).is_empty());
+        assert!(names_at("Shelf C", 10, 0).is_empty());
+    }
+
+    #[test]
+    fn the_location_index_follows_moves_deletes_and_restores() {
+        call_as(alice());
+        let drill = add("Drill", "Shelf A");
+        add("Saw", "Shelf A");
+        ok(update_smart_storage_item(drill.id, 1, payload("Drill", "Shelf B"), None));
+        assert_eq!(names_at("Shelf A", 10, 0), ["Saw"]);
+        assert_eq!(names_at("Shelf B", 10, 0), ["Drill"]);
+
+        ok(delete_smart_storage_item(drill.id, 2, None));
+        assert!(names_at("Shelf B", 10, 0).is_empty());
+        ok(restore_smart_storage_item(drill.id, 2, None));
+        assert_eq!(names_at("Shelf B", 10, 0), ["Drill"]);
+        assert_eq!(ITEMS_BY_LOCATION.with(|index| index.borrow().len()), 2);
+    }
+
+    fn available_names() -> Vec<String> {
+        get_available_smart_storage_items(None).into_iter().map(|item| item.name).collect()
+    }
+
+    #[test]
+    fn availability_counts_follow_every_change() {
+        call_as(alice());
+        let drill = add("Drill", "Shelf A");
+        let saw = add("Saw", "Shelf A");
+        let hammer = add("Hammer", "Shelf A");
+        ok(add_smart_storage_item(
+            SmartStorageItemPayload {
+                is_available: false,
+                ..payload("Level", "Shelf A")
+            },
+            None,
+        ));
+        assert_eq!(available_names(), ["Drill", "Saw", "Hammer"]);
+        let stats = get_item_statistics();
+        assert_eq!((stats.total_items, stats.average_availability_rate), (4, 75.0));
+
+        ok(mark_item_as_unavailable(saw.id, 1, None));
+        ok(delete_smart_storage_item(hammer.id, 1, None));
+        assert_eq!(available_names(), ["Drill"]);
+        let stats = get_item_statistics();
+        assert_eq!((stats.total_items, stats.average_availability_rate), (3, 1.0 / 3.0 * 100.0));
+
+        ok(restore_smart_storage_item(hammer.id, 1, None));
+        ok(mark_item_as_available(saw.id, 2, None));
+        let unavailable = SmartStorageItemPayload {
+            is_available: false,
+            ..payload("Drill", "Shelf B")
+        };
+        ok(update_smart_storage_item(drill.id, 1, unavailable, None));
+        assert_eq!(available_names(), ["Saw", "Hammer"]);
+        assert_eq!(get_item_statistics().average_availability_rate, 50.0);
+    }
+
+    #[test]
+    fn a_stale_availability_index_is_rebuilt() {
+        call_as(alice());
+        add("Drill", "Shelf A");
+        add("Saw", "Shelf A");
+        INDEX_VERSIONS.with(|versions| {
+            let mut versions = versions.borrow_mut();
+            for (index, version) in DERIVED_INDEXES {
+                versions.insert(index, version);
+            }
+            versions.insert(AVAILABLE_ITEMS_MEMORY, 0);
+        });
+        AVAILABLE_ITEMS.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_memory(AVAILABLE_ITEMS_MEMORY)));
+        assert_eq!(get_item_statistics().average_availability_rate, 0.0);
+
+        rebuild_stale_indexes();
+        assert_eq!(available_names(), ["Drill", "Saw"]);
+        assert_eq!(get_item_statistics().average_availability_rate, 100.0);
+    }
+}
@@ -0,0 +1,644 @@
+#[macro_use]
+extern crate serde;
+
+// Router in front of a set of storage canisters ("shards"), each running the
+// storage canister code. Every shard owns a contiguous block of item ids, so an
+// id alone says which shard holds an item. New items go to the newest shard;
+// once it reports that it is full the router creates the next one. Item calls
+// reach the shards through their routed_update and routed_query endpoints, which
+// act for the router's caller instead of the router.
+
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::caller;
+use ic_cdk::api::management_canister::main::{
+    canister_info, create_canister, install_code, CanisterInfoRequest, CanisterInstallMode, CanisterSettings,
+    CreateCanisterArgument, InstallCodeArgument,
+};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{Cell, DefaultMemoryImpl, Storable};
+use serde::de::DeserializeOwned;
+use std::{borrow::Cow, cell::RefCell};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Mirrors SmartStorageItem in the storage canister.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SmartStorageItem {
+    id: u64,
+    name: String,
+    description: String,
+    location: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+    is_available: bool,
+    version: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SmartStorageItemPayload {
+    name: String,
+    description: String,
+    location: String,
+    is_available: bool,
+}
+
+// Mirrors ChangeKind and Notification in the storage canister.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ChangeKind {
+    Updated,
+    AvailabilityChanged { is_available: bool },
+    Moved { from: String, to: String },
+    Deleted,
+    Restored,
+    Retired,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Notification {
+    id: u64,
+    item_id: u64,
+    kind: ChangeKind,
+    changed_by: Principal,
+    timestamp: u64,
+    read: bool,
+}
+
+// Mirrors Error in the storage canister, so shard errors pass through unchanged.
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum Error {
+    NotFound { msg: String },
+    InvalidInput { msg: String },
+    Unauthorized { msg: String },
+    DecodeFailed { msg: String },
+    Conflict { msg: String, current_version: u64 },
+    Archived { msg: String, archive: Principal },
+    CallFailed { msg: String },
+    ShardFull { msg: String },
+}
+
+// Mirrors ShardConfig in the storage canister.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ShardConfig {
+    first_id: u64,
+    end_id: u64,
+    max_stable_memory_bytes: Option<u64>,
+    router: Option<Principal>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Shard {
+    canister: Principal,
+    first_id: u64,
+    // Exclusive.
+    end_id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ShardList(Vec<Shard>);
+
+impl Storable for ShardList {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ShardPolicy {
+    // Size of the id block handed to each new shard.
+    ids_per_shard: u64,
+    // Passed on to every shard, which refuses new items past it.
+    max_stable_memory_bytes: Option<u64>,
+    // Cycles a newly created shard starts with.
+    cycles_per_shard: u128,
+    // Controllers a newly created shard gets besides the router, so admins can
+    // call its controller-only endpoints. None means the router's own
+    // controllers.
+    admin_controllers: Option<Vec<Principal>>,
+}
+
+impl Default for ShardPolicy {
+    fn default() -> Self {
+        ShardPolicy {
+            ids_per_shard: 10_000_000,
+            max_stable_memory_bytes: Some(DEFAULT_MAX_SHARD_STABLE_MEMORY_BYTES),
+            cycles_per_shard: 2_000_000_000_000,
+            admin_controllers: None,
+        }
+    }
+}
+
+impl Storable for ShardPolicy {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// A canister the router created for a shard but could not install or configure.
+// The next shard creation retries it instead of paying for another canister.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PendingShard(Option<Principal>);
+
+impl Storable for PendingShard {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Leaves headroom below the per-canister stable memory limit for the audit
+// log and the other stores that keep growing after a shard stops taking items.
+const DEFAULT_MAX_SHARD_STABLE_MEMORY_BYTES: u64 = 64 * 1024 * 1024 * 1024;
+
+// Upper bound on the items returned by one list_items call.
+const MAX_LIST_LIMIT: u64 = 100;
+
+// Position of a paginated listing: the shard to continue in and how many of
+// its items were already returned.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ListCursor {
+    shard: u32,
+    offset: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ItemPage {
+    items: Vec<SmartStorageItem>,
+    next: Option<ListCursor>,
+}
+
+const SHARDS_MEMORY: u8 = 0;
+const SHARD_POLICY_MEMORY: u8 = 1;
+const SHARD_WASM_MEMORY: u8 = 2;
+const PENDING_SHARD_MEMORY: u8 = 3;
+
+fn stable_memory(id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    // Shards in id order; the last one takes new items.
+    static SHARDS: RefCell<Cell<ShardList, Memory>> = RefCell::new(
+        Cell::init(stable_memory(SHARDS_MEMORY), ShardList::default())
+            .expect("Cannot create the shard list")
+    );
+
+    static SHARD_POLICY: RefCell<Cell<ShardPolicy, Memory>> = RefCell::new(
+        Cell::init(stable_memory(SHARD_POLICY_MEMORY), ShardPolicy::default())
+            .expect("Cannot create the shard policy")
+    );
+
+    // Module installed on the shards the router creates.
+    static SHARD_WASM: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(stable_memory(SHARD_WASM_MEMORY), Vec::new())
+            .expect("Cannot create the shard module")
+    );
+
+    static PENDING_SHARD: RefCell<Cell<PendingShard, Memory>> = RefCell::new(
+        Cell::init(stable_memory(PENDING_SHARD_MEMORY), PendingShard::default())
+            .expect("Cannot create the pending shard")
+    );
+
+    // Set while a shard is being created or registered, so two calls cannot
+    // hand out the same id block.
+    static SHARD_CHANGE_RUNNING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+#[ic_cdk::init]
+fn init(policy: Option<ShardPolicy>) {
+    if let Some(policy) = policy {
+        SHARD_POLICY
+            .with(|cell| cell.borrow_mut().set(policy))
+            .expect("cannot persist the shard policy");
+    }
+}
+
+fn ensure_controller() -> Result<(), Error> {
+    if ic_cdk::api::is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "only a canister controller may do this".to_string(),
+        })
+    }
+}
+
+#[ic_cdk::query]
+fn get_shards() -> Vec<Shard> {
+    SHARDS.with(|shards| shards.borrow().get().0.clone())
+}
+
+#[ic_cdk::query]
+fn get_shard_policy() -> ShardPolicy {
+    SHARD_POLICY.with(|policy| policy.borrow().get().clone())
+}
+
+// Applies to shards created or registered from now on.
+#[ic_cdk::update]
+fn set_shard_policy(policy: ShardPolicy) -> Result<ShardPolicy, Error> {
+    ensure_controller()?;
+    if policy.ids_per_shard == 0 {
+        return Err(Error::InvalidInput {
+            msg: "ids_per_shard must be positive".to_string(),
+        });
+    }
+    SHARD_POLICY
+        .with(|cell| cell.borrow_mut().set(policy.clone()))
+        .expect("cannot persist the shard policy");
+    Ok(policy)
+}
+
+// Stores the storage canister module used for new shards.
+#[ic_cdk::update]
+fn set_shard_wasm(wasm: Vec<u8>) -> Result<(), Error> {
+    ensure_controller()?;
+    SHARD_WASM
+        .with(|cell| cell.borrow_mut().set(wasm))
+        .expect("cannot persist the shard module");
+    Ok(())
+}
+
+// Takes on an already deployed storage canister as the next shard. The router
+// has to be one of its controllers.
+#[ic_cdk::update]
+async fn register_shard(canister: Principal) -> Result<Shard, Error> {
+    ensure_controller()?;
+    if shards().iter().any(|shard| shard.canister == canister) {
+        return Err(Error::InvalidInput {
+            msg: format!("{} is already a shard", canister),
+        });
+    }
+    change_shards(add_shard(canister)).await
+}
+
+// New items go to the newest shard. With an idempotency key the shards are
+// tried from the oldest instead: each keeps the reply under the key, so full
+// shards answer ShardFull and the first with room takes the item. A retry walks
+// the same shards and gets the stored replies back, so it ends at the shard that
+// took the item even when newer shards were created since.
+#[ic_cdk::update]
+async fn add_item(payload: SmartStorageItemPayload, idempotency_key: Option<String>) -> Result<SmartStorageItem, Error> {
+    let user = caller();
+    let mut next = match idempotency_key {
+        Some(_) => 0,
+        None => shards().len().saturating_sub(1),
+    };
+    loop {
+        // Another call may have created the next shard while this one waited.
+        let (shard, created) = match shards().get(next) {
+            Some(shard) => (shard.clone(), false),
+            None => (change_shards(create_shard()).await?, true),
+        };
+        let result: Result<SmartStorageItem, Error> = call_shard_as(
+            user,
+            shard.canister,
+            ROUTED_UPDATE,
+            "add_smart_storage_item",
+            (payload.clone(), idempotency_key.clone()),
+        )
+        .await?;
+        match result {
+            Err(Error::ShardFull { .. }) if !created => next += 1,
+            result => return result,
+        }
+    }
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_item(id: u64) -> Result<SmartStorageItem, Error> {
+    let user = caller();
+    let shard = shard_for(id)?;
+    call_shard_as(user, shard.canister, ROUTED_QUERY, "get_smart_storage_item", (id, None::<u64>)).await?
+}
+
+#[ic_cdk::update]
+async fn update_item(
+    id: u64,
+    expected_version: u64,
+    payload: SmartStorageItemPayload,
+    idempotency_key: Option<String>,
+) -> Result<SmartStorageItem, Error> {
+    let user = caller();
+    let shard = shard_for(id)?;
+    call_shard_as(
+        user,
+        shard.canister,
+        ROUTED_UPDATE,
+        "update_smart_storage_item",
+        (id, expected_version, payload, idempotency_key),
+    )
+    .await?
+}
+
+#[ic_cdk::update]
+async fn delete_item(id: u64, expected_version: u64, idempotency_key: Option<String>) -> Result<SmartStorageItem, Error> {
+    let user = caller();
+    let shard = shard_for(id)?;
+    call_shard_as(
+        user,
+        shard.canister,
+        ROUTED_UPDATE,
+        "delete_smart_storage_item",
+        (id, expected_version, idempotency_key),
+    )
+    .await?
+}
+
+#[ic_cdk::update]
+async fn watch_item(item_id: u64, idempotency_key: Option<String>) -> Result<(), Error> {
+    let user = caller();
+    let shard = shard_for(item_id)?;
+    call_shard_as(user, shard.canister, ROUTED_UPDATE, "watch_item", (item_id, idempotency_key)).await?
+}
+
+#[ic_cdk::update]
+async fn unwatch_item(item_id: u64, idempotency_key: Option<String>) -> Result<(), Error> {
+    let user = caller();
+    let shard = shard_for(item_id)?;
+    call_shard_as(user, shard.canister, ROUTED_UPDATE, "unwatch_item", (item_id, idempotency_key)).await?
+}
+
+// Notification ids are only unique within a shard, so the notification is
+// found through the item it is about.
+#[ic_cdk::update]
+async fn mark_notification_as_read(
+    item_id: u64,
+    id: u64,
+    idempotency_key: Option<String>,
+) -> Result<Notification, Error> {
+    let user = caller();
+    let shard = shard_for(item_id)?;
+    call_shard_as(user, shard.canister, ROUTED_UPDATE, "mark_notification_as_read", (id, idempotency_key)).await?
+}
+
+// Marks the caller's notifications read on every shard and returns how many
+// were unread. Each shard keeps its own reply under the key, so a retry after
+// a partial failure only redoes the shards that did not answer.
+#[ic_cdk::update]
+async fn mark_all_notifications_as_read(idempotency_key: Option<String>) -> Result<u64, Error> {
+    let user = caller();
+    let mut count = 0;
+    for shard in shards() {
+        let result: Result<u64, Error> = call_shard_as(
+            user,
+            shard.canister,
+            ROUTED_UPDATE,
+            "mark_all_notifications_as_read",
+            (idempotency_key.clone(),),
+        )
+        .await?;
+        count += result?;
+    }
+    Ok(count)
+}
+
+// Walks the shards in id order, so pages come back sorted by id.
+#[ic_cdk::query(composite = true)]
+async fn list_items(cursor: Option<ListCursor>, limit: u64) -> Result<ItemPage, Error> {
+    let user = caller();
+    let shards = shards();
+    let limit = limit.min(MAX_LIST_LIMIT);
+    let mut cursor = cursor.unwrap_or(ListCursor { shard: 0, offset: 0 });
+    let mut items = Vec::new();
+    while (cursor.shard as usize) < shards.len() && (items.len() as u64) < limit {
+        let wanted = limit - items.len() as u64;
+        let page: Vec<SmartStorageItem> = call_shard_as(
+            user,
+            shards[cursor.shard as usize].canister,
+            ROUTED_QUERY,
+            "get_paginated_smart_storage_items",
+            (wanted, cursor.offset, None::<u64>),
+        )
+        .await?;
+        let returned = page.len() as u64;
+        items.extend(page);
+        if returned < wanted {
+            cursor = ListCursor {
+                shard: cursor.shard + 1,
+                offset: 0,
+            };
+        } else {
+            cursor.offset += returned;
+        }
+    }
+    let next = ((cursor.shard as usize) < shards.len()).then_some(cursor);
+    Ok(ItemPage { items, next })
+}
+
+#[ic_cdk::query(composite = true)]
+async fn search_items(query: String) -> Result<Vec<SmartStorageItem>, Error> {
+    let user = caller();
+    let mut items = Vec::new();
+    for shard in shards() {
+        let found: Vec<SmartStorageItem> = call_shard_as(
+            user,
+            shard.canister,
+            ROUTED_QUERY,
+            "search_smart_storage_items",
+            (query.clone(), None::<u64>),
+        )
+        .await?;
+        items.extend(found);
+    }
+    Ok(items)
+}
+
+fn shards() -> Vec<Shard> {
+    SHARDS.with(|shards| shards.borrow().get().0.clone())
+}
+
+fn shard_for(id: u64) -> Result<Shard, Error> {
+    shards()
+        .into_iter()
+        .find(|shard| shard.first_id <= id && id < shard.end_id)
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("no shard holds item id={}", id),
+        })
+}
+
+// Runs one shard creation or registration at a time.
+async fn change_shards(change: impl std::future::Future<Output = Result<Shard, Error>>) -> Result<Shard, Error> {
+    let Some(_running) = ShardChangeGuard::acquire() else {
+        return Err(Error::InvalidInput {
+            msg: "a shard is being added; retry shortly".to_string(),
+        });
+    };
+    change.await
+}
+
+// Holds SHARD_CHANGE_RUNNING for one change. When a callback traps, the system
+// runs the call's cleanup, which drops the change and with it the guard, so a
+// failed change never leaves the flag set.
+struct ShardChangeGuard;
+
+impl ShardChangeGuard {
+    fn acquire() -> Option<Self> {
+        let running = SHARD_CHANGE_RUNNING.with(|running| running.replace(true));
+        if running {
+            // Not `then_some`, which would build a guard here too and clear
+            // the flag of the run in progress when dropping it.
+            return None;
+        }
+        Some(ShardChangeGuard)
+    }
+}
+
+impl Drop for ShardChangeGuard {
+    fn drop(&mut self) {
+        SHARD_CHANGE_RUNNING.with(|running| *running.borrow_mut() = false);
+    }
+}
+
+// Creates, installs and adds the next shard. A canister left over from an
+// earlier attempt that failed after creating it is reinstalled and used instead.
+async fn create_shard() -> Result<Shard, Error> {
+    let wasm = SHARD_WASM.with(|cell| cell.borrow().get().clone());
+    if wasm.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "no shard module was uploaded with set_shard_wasm".to_string(),
+        });
+    }
+    let (canister, mode) = match PENDING_SHARD.with(|cell| cell.borrow().get().0) {
+        // It holds nothing yet: it was never added, so no item went to it.
+        Some(canister) => (canister, CanisterInstallMode::Reinstall),
+        None => {
+            let canister = create_shard_canister().await?;
+            set_pending_shard(Some(canister));
+            (canister, CanisterInstallMode::Install)
+        }
+    };
+    install_code(InstallCodeArgument {
+        mode,
+        canister_id: canister,
+        wasm_module: wasm,
+        arg: Encode!().unwrap(),
+    })
+    .await
+    .map_err(|(code, msg)| Error::CallFailed {
+        msg: format!("installing shard {} failed ({:?}): {}", canister, code, msg),
+    })?;
+    let shard = add_shard(canister).await?;
+    set_pending_shard(None);
+    Ok(shard)
+}
+
+async fn create_shard_canister() -> Result<Principal, Error> {
+    let policy = SHARD_POLICY.with(|policy| policy.borrow().get().clone());
+    let admins = match policy.admin_controllers {
+        Some(admins) => admins,
+        None => {
+            let (info,) = canister_info(CanisterInfoRequest {
+                canister_id: ic_cdk::id(),
+                num_requested_changes: None,
+            })
+            .await
+            .map_err(|(code, msg)| Error::CallFailed {
+                msg: format!("reading the router's controllers failed ({:?}): {}", code, msg),
+            })?;
+            info.controllers
+        }
+    };
+    let mut controllers = vec![ic_cdk::id()];
+    controllers.extend(admins.into_iter().filter(|admin| *admin != ic_cdk::id()));
+    let settings = CanisterSettings {
+        controllers: Some(controllers),
+        ..Default::default()
+    };
+    let (record,) = create_canister(
+        CreateCanisterArgument {
+            settings: Some(settings),
+        },
+        policy.cycles_per_shard,
+    )
+    .await
+    .map_err(|(code, msg)| Error::CallFailed {
+        msg: format!("creating a shard failed ({:?}): {}", code, msg),
+    })?;
+    Ok(record.canister_id)
+}
+
+fn set_pending_shard(canister: Option<Principal>) {
+    PENDING_SHARD
+        .with(|cell| cell.borrow_mut().set(PendingShard(canister)))
+        .expect("cannot persist the pending shard");
+}
+
+// Hands the canister the id block after the newest shard and appends it.
+async fn add_shard(canister: Principal) -> Result<Shard, Error> {
+    let policy = SHARD_POLICY.with(|policy| policy.borrow().get().clone());
+    let first_id = shards().last().map_or(0, |shard| shard.end_id);
+    let config = ShardConfig {
+        first_id,
+        end_id: first_id.saturating_add(policy.ids_per_shard),
+        max_stable_memory_bytes: policy.max_stable_memory_bytes,
+        router: Some(ic_cdk::id()),
+    };
+    let result: Result<ShardConfig, Error> = call_shard(canister, "set_shard_config", (config,)).await?;
+    let config = result?;
+    let shard = Shard {
+        canister,
+        first_id: config.first_id,
+        end_id: config.end_id,
+    };
+    SHARDS
+        .with(|cell| {
+            let mut list = cell.borrow().get().clone();
+            list.0.push(shard.clone());
+            cell.borrow_mut().set(list)
+        })
+        .expect("cannot persist the shard list");
+    Ok(shard)
+}
+
+async fn call_shard<A: ArgumentEncoder, R: CandidType + DeserializeOwned>(
+    shard: Principal,
+    method: &str,
+    args: A,
+) -> Result<R, Error> {
+    let (result,): (R,) = ic_cdk::call(shard, method, args)
+        .await
+        .map_err(|(code, msg)| Error::CallFailed {
+            msg: format!("{} on shard {} failed ({:?}): {}", method, shard, code, msg),
+        })?;
+    Ok(result)
+}
+
+const ROUTED_UPDATE: &str = "routed_update";
+const ROUTED_QUERY: &str = "routed_query";
+
+// Calls `method` on the shard through `entry` (ROUTED_UPDATE or ROUTED_QUERY)
+// on behalf of `user`, so the shard applies that principal's permissions,
+// idempotency keys and watchlist and records them in its history. Endpoints
+// read `user` before their first await.
+async fn call_shard_as<A: ArgumentEncoder, R: CandidType + DeserializeOwned>(
+    user: Principal,
+    shard: Principal,
+    entry: &str,
+    method: &str,
+    args: A,
+) -> Result<R, Error> {
+    let arg = candid::encode_args(args).map_err(|err| Error::InvalidInput {
+        msg: format!("cannot encode the arguments of {}: {}", method, err),
+    })?;
+    let result: Result<Vec<u8>, Error> = call_shard(shard, entry, (user, method, arg)).await?;
+    Decode!(&result?, R).map_err(|err| Error::DecodeFailed {
+        msg: format!("cannot decode the reply of {} on shard {}: {}", method, shard, err),
+    })
+}
+
+ic_cdk::export_candid!();